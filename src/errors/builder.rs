@@ -6,10 +6,13 @@ use std::ops::Range;
 
 use super::{DiagnosticsContext, Level};
 
+/// How many calls in a backtrace are shown before the rest are elided.
+const BACKTRACE_FRAMES_SHOWN: usize = 5;
+
 impl From<Level> for AnnotationType {
     fn from(level: Level) -> Self {
         match level {
-            Level::ICE | Level::Error => AnnotationType::Error,
+            Level::Ice | Level::Error => AnnotationType::Error,
             Level::Warning => AnnotationType::Warning,
             Level::Help => AnnotationType::Help,
             Level::Info => AnnotationType::Info,
@@ -50,6 +53,8 @@ pub struct DiagnosticBuilder<'ctx, 'src> {
     level: Level,
     labels: Vec<Label>,
     footers: Vec<Label>,
+    /// The calls that led to this diagnostic, innermost first
+    backtrace: Vec<(Range<usize>, String)>,
     context: &'ctx DiagnosticsContext<'src>
 }
 
@@ -65,6 +70,7 @@ impl<'ctx, 'src> DiagnosticBuilder<'ctx, 'src> {
             level,
             labels: Vec::new(),
             footers: Vec::new(),
+            backtrace: Vec::new(),
             context
         }
     }
@@ -105,7 +111,59 @@ impl<'ctx, 'src> DiagnosticBuilder<'ctx, 'src> {
         self
     }
 
-    pub fn emit(self) {
+    /// Add a backtrace of the calls that led to this diagnostic,
+    /// given as the span and a description of each call, innermost first.
+    /// The innermost calls are labelled, and a note lists the chain of calls.
+    pub fn backtrace(mut self, frames: Vec<(Range<usize>, String)>) -> Self {
+        self.backtrace = frames;
+        self
+    }
+
+    /// Add labels and a note for the backtrace. Calls with an empty span
+    /// (ex. from generated code) or which already have a label aren't labelled.
+    fn render_backtrace(&mut self) {
+        let frames = std::mem::take(&mut self.backtrace);
+
+        if frames.is_empty() {
+            return;
+        }
+
+        let shown = &frames[..frames.len().min(BACKTRACE_FRAMES_SHOWN)];
+
+        for (span, description) in shown {
+            // Recursive calls tend to come from the same place
+            let seen = self.labels.iter().any(|label| label.span == *span);
+
+            if !span.is_empty() && !seen {
+                self.labels.push(Label {
+                    contents: Some(format!("in this call to {}", description)),
+                    level: Level::Note,
+                    span: span.clone()
+                });
+            }
+        }
+
+        let mut chain: Vec<&str> = shown
+            .iter()
+            .map(|(_, description)| description.as_str())
+            .collect();
+        let hidden = frames.len() - shown.len();
+        let elided = format!("{} more calls", hidden);
+
+        if hidden > 0 {
+            chain.push(&elided);
+        }
+
+        self.footers.push(Label {
+            contents: Some(format!("in {}", chain.join(", called from "))),
+            level: Level::Note,
+            span: 0..0
+        });
+    }
+
+    pub fn emit(mut self) {
+        self.render_backtrace();
+
         let snippet = Snippet {
             title: Some(Annotation {
                 label: Some(&self.title),
@@ -118,7 +176,7 @@ impl<'ctx, 'src> DiagnosticBuilder<'ctx, 'src> {
             slices: vec![Slice {
                 source: self.context.source,
                 line_start: 1,
-                origin: self.context.origin.as_ref().map(|x| x.as_str()),
+                origin: self.context.origin.as_deref(),
                 fold: true,
                 annotations: self.labels.iter().map(SourceAnnotation::from).collect()
             }],
@@ -2,11 +2,11 @@ mod builder;
 
 use std::ops::Range;
 
-use self::builder::DiagnosticBuilder;
+pub use self::builder::DiagnosticBuilder;
 
 #[derive(Clone, Copy)]
 enum Level {
-    ICE,
+    Ice,
     Error,
     Warning,
     Help,
@@ -29,56 +29,60 @@ impl<'src> DiagnosticsContext<'src> {
         self.source = source;
     }
 
-    pub fn build_ice(&self, message: &str) -> DiagnosticBuilder {
-        DiagnosticBuilder::new(message.to_string(), Level::ICE, self)
+    pub fn build_ice(&self, message: &str) -> DiagnosticBuilder<'_, '_> {
+        DiagnosticBuilder::new(message.to_string(), Level::Ice, self)
             .note("this is an internal error")
             .note("a bug report would be highly appreciated:\nhttps://github.com/PatchMixolydic/nightbug/issues/new")
     }
 
-    pub fn build_ice_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder {
+    pub fn build_ice_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder<'_, '_> {
         self.build_ice(message).with_span(span)
     }
 
     // The below is quite repetitive, but using a macro causes rust-analyzer
     // to be unable to find these functions :(
 
-    pub fn build_error(&self, message: &str) -> DiagnosticBuilder {
+    pub fn build_error(&self, message: &str) -> DiagnosticBuilder<'_, '_> {
         DiagnosticBuilder::new(message.to_string(), Level::Error, self)
     }
 
-    pub fn build_error_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder {
+    pub fn build_error_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder<'_, '_> {
         self.build_error(message).with_span(span)
     }
 
-    pub fn build_warning(&self, message: &str) -> DiagnosticBuilder {
+    pub fn build_warning(&self, message: &str) -> DiagnosticBuilder<'_, '_> {
         DiagnosticBuilder::new(message.to_string(), Level::Warning, self)
     }
 
-    pub fn build_warning_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder {
+    pub fn build_warning_span(
+        &self,
+        span: Range<usize>,
+        message: &str
+    ) -> DiagnosticBuilder<'_, '_> {
         self.build_warning(message).with_span(span)
     }
 
-    pub fn build_help(&self, message: &str) -> DiagnosticBuilder {
+    pub fn build_help(&self, message: &str) -> DiagnosticBuilder<'_, '_> {
         DiagnosticBuilder::new(message.to_string(), Level::Help, self)
     }
 
-    pub fn build_help_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder {
+    pub fn build_help_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder<'_, '_> {
         self.build_help(message).with_span(span)
     }
 
-    pub fn build_info(&self, message: &str) -> DiagnosticBuilder {
+    pub fn build_info(&self, message: &str) -> DiagnosticBuilder<'_, '_> {
         DiagnosticBuilder::new(message.to_string(), Level::Info, self)
     }
 
-    pub fn build_info_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder {
+    pub fn build_info_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder<'_, '_> {
         self.build_info(message).with_span(span)
     }
 
-    pub fn build_note(&self, message: &str) -> DiagnosticBuilder {
+    pub fn build_note(&self, message: &str) -> DiagnosticBuilder<'_, '_> {
         DiagnosticBuilder::new(message.to_string(), Level::Note, self)
     }
 
-    pub fn build_note_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder {
+    pub fn build_note_span(&self, span: Range<usize>, message: &str) -> DiagnosticBuilder<'_, '_> {
         self.build_note(message).with_span(span)
     }
 }
@@ -0,0 +1,645 @@
+//! Compilation from expressions to bytecode for the `vm` module.
+//!
+//! Every chunk of bytecode has a table with the span of the expression
+//! each instruction came from, so that runtime errors can point at the code
+//! which caused them. Calls also keep the spans of their name and arguments
+//! in a separate table of call sites.
+//!
+//! Expressions which the compiler doesn't handle, including every malformed
+//! expression, are compiled to `Op::Interpret` and left to the tree-walking
+//! interpreter. Since malformed expressions are only reported once they are
+//! evaluated, this keeps errors the same on both backends.
+
+use std::{ops::Range, rc::Rc};
+
+use super::{quote::quote_expr, Value};
+use crate::{
+    parser::{Expr, ExprKind, Keyword},
+    symbol::Symbol
+};
+
+/// A single bytecode instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// Push a constant from the constant pool
+    Constant(usize),
+    /// Push the argument at an index in the innermost function call
+    Argument(usize),
+    /// Push the value of an identifier, searching the current scope
+    Lookup(Symbol),
+    /// Bind the value on top of the stack in the current scope,
+    /// replacing it with unit
+    Define(Symbol),
+    /// Pop a value and bind it in the current scope
+    Bind(Symbol),
+    /// Assign the value on top of the stack to an existing binding,
+    /// replacing it with unit
+    Set(Symbol),
+    /// Assign the value on top of the stack to the argument at an index
+    /// in the innermost function call, replacing it with unit
+    SetArgument(usize),
+    /// Discard the value on top of the stack
+    Pop,
+    /// Pop a condition for the given keyword,
+    /// jumping to `target` if it is equal to `jump_if`
+    Branch {
+        keyword: Keyword,
+        jump_if: bool,
+        target: usize
+    },
+    Jump(usize),
+    /// Create a function from a template, capturing the current scope
+    Closure(usize),
+    /// Enter a new scope for the body of a `let`-like expression
+    PushScope(Keyword),
+    /// Leave this many scopes
+    PopScopes(usize),
+    /// Check the function on top of the stack before its arguments are
+    /// evaluated, given the index of the call site
+    PrepareCall(usize),
+    /// Call a function with the arguments on top of the stack,
+    /// given the index of the call site
+    Call(usize),
+    /// Like `Call`, but replacing the current call, since the result
+    /// of the current function is the result of this call
+    TailCall(usize),
+    /// Pop this many values, which alternate between keys and values,
+    /// and push a map of them
+    MakeMap(usize),
+    /// Pop this many values and push a set of them
+    MakeSet(usize),
+    /// Return the value on top of the stack from the current call
+    Return,
+    /// Evaluate an expression with the tree-walking interpreter
+    Interpret(usize)
+}
+
+/// A call written in the code.
+#[derive(Debug)]
+pub struct CallSite {
+    /// The name of the function being called, for diagnostics
+    pub ident: &'static str,
+    pub span: Range<usize>,
+    pub name_span: Range<usize>,
+    pub arg_spans: Vec<Range<usize>>
+}
+
+/// A function expression, compiled once and turned into
+/// a new function each time it is evaluated.
+#[derive(Debug)]
+pub struct FunctionTemplate {
+    pub name: Option<Symbol>,
+    pub params: Rc<[Symbol]>,
+    pub body: Rc<[Expr]>,
+    pub chunk: Rc<Chunk>
+}
+
+/// A compiled program or function body.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    /// The span of the expression each instruction came from
+    pub spans: Vec<Range<usize>>,
+    pub constants: Vec<Value>,
+    pub functions: Vec<Rc<FunctionTemplate>>,
+    pub calls: Vec<CallSite>,
+    /// Expressions left to the tree-walking interpreter
+    pub exprs: Vec<Expr>
+}
+
+/// Compile a program, which has already been expanded and resolved.
+pub fn compile_program(program: &[Expr]) -> Chunk {
+    let mut chunk = Chunk::default();
+    // The top level isn't a function, so nothing is in tail position
+    chunk.body(program, false, 0..0);
+    chunk.emit(Op::Return, 0..0);
+    chunk
+}
+
+/// Compile the body of a function.
+pub fn compile_function(body: &[Expr]) -> Chunk {
+    let mut chunk = Chunk::default();
+    chunk.body(body, true, 0..0);
+    chunk.emit(Op::Return, 0..0);
+    chunk
+}
+
+/// The names in a parameter list, if it is well-formed.
+fn parameter_names(params: &[Expr]) -> Option<Vec<Symbol>> {
+    params
+        .iter()
+        .map(|param| match param.kind {
+            ExprKind::Identifier(param) => Some(param),
+            _ => None
+        })
+        .collect()
+}
+
+/// The names and values in a `let` binding list, if it is well-formed.
+fn let_bindings(bindings_expr: &Expr) -> Option<Vec<(Symbol, &Expr)>> {
+    let bindings = match &bindings_expr.kind {
+        ExprKind::Unit => return Some(Vec::new()),
+        ExprKind::List(bindings) => bindings,
+        _ => return None
+    };
+
+    bindings
+        .iter()
+        .map(|binding| match &binding.kind {
+            ExprKind::List(pair) => match &pair[..] {
+                [Expr {
+                    kind: ExprKind::Identifier(name),
+                    ..
+                }, value] => Some((*name, value)),
+                _ => None
+            },
+            _ => None
+        })
+        .collect()
+}
+
+impl Chunk {
+    fn emit(&mut self, op: Op, span: Range<usize>) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    fn constant(&mut self, value: Value, span: Range<usize>) {
+        self.constants.push(value);
+        self.emit(Op::Constant(self.constants.len() - 1), span);
+    }
+
+    fn unit(&mut self, span: Range<usize>) {
+        self.constant(Value::Unit, span);
+    }
+
+    /// Point the jump at `idx` to the next instruction.
+    fn patch(&mut self, idx: usize) {
+        let next = self.code.len();
+
+        match &mut self.code[idx] {
+            Op::Jump(target) | Op::Branch { target, .. } => *target = next,
+            op => unreachable!("tried to patch {:?}", op)
+        }
+    }
+
+    /// Leave an expression to the tree-walking interpreter.
+    fn interpret(&mut self, expr: &Expr) {
+        self.exprs.push(expr.clone());
+        self.emit(Op::Interpret(self.exprs.len() - 1), expr.span.clone());
+    }
+
+    /// Compile a body made of several expressions,
+    /// whose value is the value of the last one.
+    fn body(&mut self, exprs: &[Expr], tail: bool, span: Range<usize>) {
+        let (last, rest) = match exprs.split_last() {
+            Some(split) => split,
+            None => return self.unit(span)
+        };
+
+        for expr in rest {
+            self.expr(expr, false);
+            self.emit(Op::Pop, expr.span.clone());
+        }
+
+        self.expr(last, tail);
+    }
+
+    /// Compile an expression. Expressions in tail position
+    /// are the last thing evaluated in a function body.
+    fn expr(&mut self, expr: &Expr, tail: bool) {
+        let span = expr.span.clone();
+
+        match &expr.kind {
+            ExprKind::Integer(_)
+            | ExprKind::BigInteger(_)
+            | ExprKind::Rational(_)
+            | ExprKind::Float(_)
+            | ExprKind::String(_)
+            | ExprKind::Boolean(_)
+            | ExprKind::Unit => self.constant(quote_expr(expr.clone()), span),
+
+            ExprKind::Identifier(ident) => {
+                self.emit(Op::Lookup(*ident), span);
+            },
+
+            ExprKind::Argument(idx) => {
+                self.emit(Op::Argument(*idx), span);
+            },
+
+            ExprKind::List(contents) => self.list(expr, contents, tail),
+
+            ExprKind::Map(contents) => {
+                for item in contents.iter() {
+                    self.expr(item, false);
+                }
+
+                self.emit(Op::MakeMap(contents.len()), span);
+            },
+
+            ExprKind::Set(contents) => {
+                for item in contents.iter() {
+                    self.expr(item, false);
+                }
+
+                self.emit(Op::MakeSet(contents.len()), span);
+            },
+
+            ExprKind::Keyword(_) => self.interpret(expr)
+        }
+    }
+
+    fn list(&mut self, expr: &Expr, contents: &[Expr], tail: bool) {
+        // The parser turns `()` into `ExprKind::Unit`, so lists are never empty
+        let (head, args) = contents.split_first().unwrap();
+        let span = expr.span.clone();
+
+        let compiled = match head.kind {
+            ExprKind::Keyword(Keyword::Define) => self.define(span, args),
+            ExprKind::Keyword(Keyword::Set) => self.set(args),
+            ExprKind::Keyword(Keyword::Fn) => self.function(span, args),
+
+            ExprKind::Keyword(keyword @ (Keyword::Let | Keyword::LetStar | Keyword::LetRec)) => {
+                self.let_expr(keyword, span, args, tail)
+            },
+
+            ExprKind::Keyword(Keyword::If) => self.if_expr(span, args, tail),
+            ExprKind::Keyword(Keyword::Cond) => self.cond(args, tail),
+
+            ExprKind::Keyword(keyword @ (Keyword::When | Keyword::Unless)) => {
+                self.when(keyword, span, args, tail)
+            },
+
+            ExprKind::Keyword(keyword @ (Keyword::And | Keyword::Or)) => {
+                self.and_or(keyword, span, args);
+                true
+            },
+
+            ExprKind::Keyword(Keyword::Quote) if args.len() == 1 => {
+                self.constant(quote_expr(args[0].clone()), span);
+                true
+            },
+
+            // Everything else is either rarely evaluated or an error
+            ExprKind::Keyword(_) => false,
+
+            _ => {
+                self.call(span, head, args, tail);
+                true
+            }
+        };
+
+        if !compiled {
+            self.interpret(expr);
+        }
+    }
+
+    /// Compile a `define` expression, returning false if it is malformed.
+    fn define(&mut self, span: Range<usize>, args: &[Expr]) -> bool {
+        match args {
+            [Expr {
+                kind: ExprKind::Identifier(name),
+                ..
+            }, value] => {
+                self.expr(value, false);
+                self.emit(Op::Define(*name), span);
+                true
+            },
+
+            [Expr {
+                kind: ExprKind::List(signature),
+                ..
+            }, body @ ..]
+                if !body.is_empty() =>
+            {
+                let (name, params) = match signature.split_first() {
+                    Some((
+                        Expr {
+                            kind: ExprKind::Identifier(name),
+                            ..
+                        },
+                        params
+                    )) => (*name, params),
+                    _ => return false
+                };
+
+                match parameter_names(params) {
+                    Some(params) => {
+                        self.closure(Some(name), params, body, span.clone());
+                        self.emit(Op::Define(name), span);
+                        true
+                    },
+                    None => false
+                }
+            },
+
+            _ => false
+        }
+    }
+
+    /// Compile a `set!` expression, returning false if it is malformed.
+    fn set(&mut self, args: &[Expr]) -> bool {
+        let (target, value) = match args {
+            [target, value] => (target, value),
+            _ => return false
+        };
+
+        // Errors point at the name being set
+        let op = match target.kind {
+            ExprKind::Identifier(name) => Op::Set(name),
+            ExprKind::Argument(idx) => Op::SetArgument(idx),
+            _ => return false
+        };
+
+        self.expr(value, false);
+        self.emit(op, target.span.clone());
+        true
+    }
+
+    /// Compile a `fn` expression, returning false if it is malformed.
+    fn function(&mut self, span: Range<usize>, args: &[Expr]) -> bool {
+        let (params_expr, body) = match args.split_first() {
+            Some(split) if !split.1.is_empty() => split,
+            _ => return false
+        };
+
+        let params = match &params_expr.kind {
+            ExprKind::Unit => Some(Vec::new()),
+            ExprKind::List(params) => parameter_names(params),
+            _ => None
+        };
+
+        match params {
+            Some(params) => {
+                self.closure(None, params, body, span);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn closure(
+        &mut self,
+        name: Option<Symbol>,
+        params: Vec<Symbol>,
+        body: &[Expr],
+        span: Range<usize>
+    ) {
+        self.functions.push(Rc::new(FunctionTemplate {
+            name,
+            params: params.into(),
+            body: body.into(),
+            chunk: Rc::new(compile_function(body))
+        }));
+        self.emit(Op::Closure(self.functions.len() - 1), span);
+    }
+
+    /// Compile a `let`, `let*`, or `letrec` expression,
+    /// returning false if it is malformed.
+    fn let_expr(
+        &mut self,
+        keyword: Keyword,
+        span: Range<usize>,
+        args: &[Expr],
+        tail: bool
+    ) -> bool {
+        let (bindings, body) = match args.split_first() {
+            Some((bindings_expr, body)) if !body.is_empty() => match let_bindings(bindings_expr) {
+                Some(bindings) => (bindings, body),
+                None => return false
+            },
+            _ => return false
+        };
+
+        let scopes = match keyword {
+            Keyword::Let => {
+                for (_, value) in &bindings {
+                    self.expr(value, false);
+                }
+
+                self.emit(Op::PushScope(keyword), span.clone());
+
+                // The values are popped in reverse, so a name bound twice
+                // must keep its last value
+                for (idx, (name, _)) in bindings.iter().enumerate().rev() {
+                    if bindings[idx + 1..].iter().any(|(later, _)| later == name) {
+                        self.emit(Op::Pop, span.clone());
+                    } else {
+                        self.emit(Op::Bind(*name), span.clone());
+                    }
+                }
+
+                1
+            },
+
+            Keyword::LetStar => {
+                for (name, value) in &bindings {
+                    self.expr(value, false);
+                    self.emit(Op::PushScope(keyword), span.clone());
+                    self.emit(Op::Bind(*name), span.clone());
+                }
+
+                // Give the body its own scope, even if there were no bindings
+                self.emit(Op::PushScope(keyword), span.clone());
+                bindings.len() + 1
+            },
+
+            _ => {
+                self.emit(Op::PushScope(keyword), span.clone());
+
+                for (name, value) in &bindings {
+                    self.expr(value, false);
+                    self.emit(Op::Bind(*name), span.clone());
+                }
+
+                1
+            }
+        };
+
+        self.body(body, tail, 0..0);
+
+        // Returning from a function leaves its scopes anyway
+        if !tail {
+            self.emit(Op::PopScopes(scopes), span);
+        }
+
+        true
+    }
+
+    /// Compile an `if` expression, returning false if it is malformed.
+    fn if_expr(&mut self, span: Range<usize>, args: &[Expr], tail: bool) -> bool {
+        let (condition, consequent, alternative) = match args {
+            [condition, consequent] => (condition, consequent, None),
+            [condition, consequent, alternative] => (condition, consequent, Some(alternative)),
+            _ => return false
+        };
+
+        let branch = self.condition(Keyword::If, condition, false);
+        self.expr(consequent, tail);
+        let jump = self.emit(Op::Jump(0), span.clone());
+        self.patch(branch);
+
+        match alternative {
+            Some(alternative) => self.expr(alternative, tail),
+            None => self.unit(span)
+        }
+
+        self.patch(jump);
+        true
+    }
+
+    /// Compile a condition followed by a branch which is taken
+    /// if it is equal to `jump_if`, returning the branch to patch.
+    fn condition(&mut self, keyword: Keyword, condition: &Expr, jump_if: bool) -> usize {
+        self.expr(condition, false);
+        self.emit(
+            Op::Branch {
+                keyword,
+                jump_if,
+                target: 0
+            },
+            condition.span.clone()
+        )
+    }
+
+    /// Compile a `cond` expression, returning false if it is malformed.
+    fn cond(&mut self, clauses: &[Expr], tail: bool) -> bool {
+        let mut parsed = Vec::with_capacity(clauses.len());
+
+        for (idx, clause) in clauses.iter().enumerate() {
+            let (condition, body) = match &clause.kind {
+                // nb. lists are never empty
+                ExprKind::List(clause) => clause.split_first().unwrap(),
+                _ => return false
+            };
+
+            let is_else = condition.kind == ExprKind::Keyword(Keyword::Else);
+
+            if is_else && idx != clauses.len() - 1 {
+                return false;
+            }
+
+            parsed.push((is_else, condition, body));
+        }
+
+        let mut jumps = Vec::with_capacity(parsed.len());
+
+        for (is_else, condition, body) in parsed {
+            if is_else {
+                self.body(body, tail, 0..0);
+                jumps.iter().for_each(|&jump| self.patch(jump));
+                return true;
+            }
+
+            let branch = self.condition(Keyword::Cond, condition, false);
+            self.body(body, tail, 0..0);
+            jumps.push(self.emit(Op::Jump(0), condition.span.clone()));
+            self.patch(branch);
+        }
+
+        self.unit(0..0);
+        jumps.iter().for_each(|&jump| self.patch(jump));
+        true
+    }
+
+    /// Compile a `when` or `unless` expression, returning false if it is
+    /// malformed.
+    fn when(&mut self, keyword: Keyword, span: Range<usize>, args: &[Expr], tail: bool) -> bool {
+        let (condition, body) = match args.split_first() {
+            Some(split) => split,
+            None => return false
+        };
+
+        let expected = keyword == Keyword::When;
+        let branch = self.condition(keyword, condition, !expected);
+        self.body(body, tail, 0..0);
+        let jump = self.emit(Op::Jump(0), span.clone());
+        self.patch(branch);
+        self.unit(span);
+        self.patch(jump);
+        true
+    }
+
+    fn and_or(&mut self, keyword: Keyword, span: Range<usize>, operands: &[Expr]) {
+        // `and` stops at the first false operand, `or` at the first true one
+        let short_circuit = keyword == Keyword::Or;
+
+        let branches: Vec<usize> = operands
+            .iter()
+            .map(|operand| self.condition(keyword, operand, short_circuit))
+            .collect();
+
+        self.constant(Value::Boolean(!short_circuit), span.clone());
+        let jump = self.emit(Op::Jump(0), span.clone());
+        branches.into_iter().for_each(|branch| self.patch(branch));
+        self.constant(Value::Boolean(short_circuit), span.clone());
+        self.patch(jump);
+    }
+
+    fn call(&mut self, span: Range<usize>, head: &Expr, args: &[Expr], tail: bool) {
+        let ident = match head.kind {
+            ExprKind::Identifier(ident) => ident.as_str(),
+            _ => "<anonymous>"
+        };
+
+        self.calls.push(CallSite {
+            ident,
+            span: span.clone(),
+            name_span: head.span.clone(),
+            arg_spans: args.iter().map(|arg| arg.span.clone()).collect()
+        });
+        let site = self.calls.len() - 1;
+
+        self.expr(head, false);
+        self.emit(Op::PrepareCall(site), span.clone());
+
+        for arg in args {
+            self.expr(arg, false);
+        }
+
+        let op = if tail {
+            Op::TailCall(site)
+        } else {
+            Op::Call(site)
+        };
+        self.emit(op, span);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interpreter::resolve::resolve_program, lexer::lex, parser::parse};
+
+    fn compile_str(code: &str) -> Chunk {
+        compile_program(&resolve_program(parse(lex(code).unwrap(), code).unwrap()))
+    }
+
+    #[test]
+    fn every_instruction_has_a_span() {
+        let chunk = compile_str("(define (f x) (if (< x 1) x (f (- x 1)))) (f 3)");
+        assert_eq!(chunk.code.len(), chunk.spans.len());
+
+        let function = &chunk.functions[0].chunk;
+        assert_eq!(function.code.len(), function.spans.len());
+    }
+
+    #[test]
+    fn tail_calls() {
+        let chunk = compile_str("(define (f x) (g (h x))) (f 1)");
+        let function = &chunk.functions[0].chunk;
+
+        assert!(function.code.contains(&Op::TailCall(0)));
+        assert!(function.code.contains(&Op::Call(1)));
+        // The top level is never in tail position
+        assert!(!chunk.code.iter().any(|op| matches!(op, Op::TailCall(_))));
+    }
+
+    #[test]
+    fn malformed_expressions_are_interpreted() {
+        let chunk = compile_str("(if true) (quasiquote (1 2))");
+        assert_eq!(chunk.exprs.len(), 2);
+        assert!(matches!(chunk.code[0], Op::Interpret(0)));
+    }
+}
@@ -0,0 +1,167 @@
+//! Conditional expressions: `if`, `cond`, `when`, `unless`, `and`, and `or`.
+//!
+//! Conditions must evaluate to a boolean; there are no "truthy" or "falsy"
+//! values. Using anything else as a condition is an error which points at
+//! the offending condition.
+
+use std::ops::Range;
+
+use super::{Expressions, InterpResult, Interpreter, InterpreterError, Step, StepResult, Value};
+use crate::parser::{Expr, ExprKind, Keyword};
+
+impl<'src> Interpreter<'src> {
+    /// Evaluate a condition, checking that it produces a boolean.
+    fn condition(&mut self, keyword: &str, expr: Expr) -> Result<bool, InterpreterError> {
+        let span = expr.span.clone();
+        let value = self.interpret_expr(expr)?;
+        self.check_condition(keyword, value, span)
+    }
+
+    /// Check that the value of a condition written at `span` is a boolean.
+    pub(super) fn check_condition(
+        &self,
+        keyword: &str,
+        value: Value,
+        span: Range<usize>
+    ) -> Result<bool, InterpreterError> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+
+            value => {
+                self.build_error(&format!("`{}` expects a boolean condition", keyword))
+                    .span_label(
+                        span,
+                        &format!("expected a boolean, found {}", value.type_name())
+                    )
+                    .note("only `true` and `false` can be used as conditions")
+                    .emit();
+
+                Err(InterpreterError::NotABoolean(keyword.to_string(), value))
+            }
+        }
+    }
+
+    /// Handle an `if` expression.
+    /// `(if condition then else)` evaluates `then` if `condition` is true,
+    /// and `else` otherwise. `else` can be omitted, in which case
+    /// the result is unit.
+    pub(super) fn handle_if(
+        &mut self,
+        span: Range<usize>,
+        mut expressions: Expressions
+    ) -> StepResult {
+        if !matches!(expressions.len(), 2 | 3) {
+            return Err(self.malformed_expression(
+                "if",
+                span,
+                "expected a condition, a consequent, and an optional alternative"
+            ));
+        }
+
+        // nb. the unwraps are safe because of the length check above
+        let condition = expressions.next().unwrap();
+        let consequent = expressions.next().unwrap();
+        let alternative = expressions.next();
+
+        if self.condition("if", condition)? {
+            Ok(Step::Eval(consequent))
+        } else {
+            match alternative {
+                Some(alternative) => Ok(Step::Eval(alternative)),
+                None => Ok(Step::Value(Value::Unit))
+            }
+        }
+    }
+
+    /// Handle a `cond` expression.
+    /// `(cond (condition body...)... (else body...))` evaluates the body
+    /// of the first clause whose condition is true.
+    /// The result is unit if no clause matches.
+    pub(super) fn handle_cond(&mut self, expressions: Expressions) -> StepResult {
+        let num_clauses = expressions.len();
+
+        for (idx, clause) in expressions.enumerate() {
+            let clause_span = clause.span.clone();
+            let mut clause = match clause.kind {
+                ExprKind::List(clause) => Expressions::new(clause),
+                _ => {
+                    return Err(self.malformed_expression(
+                        "cond",
+                        clause_span,
+                        "expected a clause of the form `(condition body...)`"
+                    ))
+                },
+            };
+
+            // nb. lists are never empty
+            let condition = clause.next().unwrap();
+
+            let matched = match condition.kind {
+                ExprKind::Keyword(Keyword::Else) if idx == num_clauses - 1 => true,
+
+                ExprKind::Keyword(Keyword::Else) => {
+                    return Err(self.malformed_expression(
+                        "cond",
+                        clause_span,
+                        "`else` must be the last clause"
+                    ))
+                },
+
+                _ => self.condition("cond", condition)?
+            };
+
+            if matched {
+                return self.tail_body(clause);
+            }
+        }
+
+        Ok(Step::Value(Value::Unit))
+    }
+
+    /// Handle a `when` or `unless` expression.
+    /// `(when condition body...)` evaluates `body` if `condition` is true,
+    /// while `unless` evaluates it if `condition` is false.
+    /// The result is unit if the body is not evaluated.
+    pub(super) fn handle_when(
+        &mut self,
+        keyword: Keyword,
+        span: Range<usize>,
+        mut expressions: Expressions
+    ) -> StepResult {
+        let keyword_str = keyword.as_str();
+        let condition = match expressions.next() {
+            Some(condition) => condition,
+            None => {
+                return Err(self.malformed_expression(keyword_str, span, "expected a condition"))
+            },
+        };
+
+        let expected = keyword == Keyword::When;
+
+        if self.condition(keyword_str, condition)? == expected {
+            self.tail_body(expressions)
+        } else {
+            Ok(Step::Value(Value::Unit))
+        }
+    }
+
+    /// Handle an `and` or `or` expression.
+    /// Each operand is evaluated in turn until the result is known,
+    /// so `(and false x)` and `(or true x)` never evaluate `x`.
+    pub(super) fn handle_and_or(
+        &mut self,
+        keyword: Keyword,
+        expressions: Expressions
+    ) -> InterpResult {
+        // `and` stops at the first false operand, `or` at the first true one
+        let short_circuit = keyword == Keyword::Or;
+
+        for expr in expressions {
+            if self.condition(keyword.as_str(), expr)? == short_circuit {
+                return Ok(Value::Boolean(short_circuit));
+            }
+        }
+
+        Ok(Value::Boolean(!short_circuit))
+    }
+}
@@ -0,0 +1,321 @@
+use std::{cell::RefCell, collections::HashMap, fmt, mem, ops::Range, rc::Rc};
+
+use super::{
+    heap::{self, Children, Owned},
+    Value
+};
+use crate::symbol::Symbol;
+
+/// Describes what introduced a scope, for use in diagnostics.
+#[derive(Clone, Debug)]
+pub enum ScopeKind {
+    /// The top-level scope
+    Global,
+    /// The body of a function, which may or may not have a name
+    Function(Option<Symbol>),
+    /// The body of a `let`, `let*`, or `letrec` expression
+    Let(&'static str)
+}
+
+impl fmt::Display for ScopeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScopeKind::Global => write!(f, "the global scope"),
+            ScopeKind::Function(Some(name)) => write!(f, "function `{}`", name),
+            ScopeKind::Function(None) => write!(f, "an anonymous function"),
+            ScopeKind::Let(keyword) => write!(f, "a `{}` expression", keyword)
+        }
+    }
+}
+
+/// The arguments passed to a function call.
+struct Frame {
+    params: Rc<[Symbol]>,
+    /// Arguments can be changed with `set!`, just like other bindings
+    args: RefCell<Vec<Value>>,
+    /// Where each argument was written, if this is a call to a macro.
+    /// Values don't have spans, so this lets code built by the macro
+    /// point back at its arguments.
+    arg_spans: Vec<Range<usize>>
+}
+
+/// A lexical scope, holding bindings and a link to the scope that encloses it.
+pub struct Environment {
+    kind: ScopeKind,
+    bindings: RefCell<HashMap<Symbol, Value>>,
+    /// Present if this scope is the body of a function call
+    frame: Option<Frame>,
+    parent: Option<Rc<Environment>>
+}
+
+impl Children for Environment {
+    fn take_children(&mut self, owned: &mut Vec<Owned>) {
+        for (_, mut value) in self.bindings.get_mut().drain() {
+            value.take_children(owned);
+        }
+
+        if let Some(frame) = &mut self.frame {
+            frame.args.get_mut().take_children(owned);
+        }
+
+        // Long chains of scopes are built by recursive functions
+        // which capture the scope they're called in
+        if let Some(parent) = self.parent.take() {
+            owned.push(Owned::Environment(parent));
+        }
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        let mut owned = Vec::new();
+        self.take_children(&mut owned);
+        heap::drop_all(owned);
+    }
+}
+
+impl Environment {
+    /// Create a new global scope.
+    pub fn global() -> Rc<Self> {
+        Self::alloc(Self {
+            kind: ScopeKind::Global,
+            bindings: RefCell::new(HashMap::new()),
+            frame: None,
+            parent: None
+        })
+    }
+
+    /// Create a new scope enclosed by `parent`.
+    pub fn child(parent: &Rc<Self>, kind: ScopeKind) -> Rc<Self> {
+        Self::alloc(Self {
+            kind,
+            bindings: RefCell::new(HashMap::new()),
+            frame: None,
+            parent: Some(Rc::clone(parent))
+        })
+    }
+
+    /// Create a new scope for a call to a function,
+    /// enclosed by the scope the function was created in.
+    pub fn call(
+        parent: &Rc<Self>,
+        name: Option<Symbol>,
+        params: Rc<[Symbol]>,
+        args: Vec<Value>
+    ) -> Rc<Self> {
+        Self::macro_call(parent, name, params, args, Vec::new())
+    }
+
+    /// Create a new scope for a call to a macro, given where each of its
+    /// arguments was written.
+    pub fn macro_call(
+        parent: &Rc<Self>,
+        name: Option<Symbol>,
+        params: Rc<[Symbol]>,
+        args: Vec<Value>,
+        arg_spans: Vec<Range<usize>>
+    ) -> Rc<Self> {
+        Self::alloc(Self {
+            kind: ScopeKind::Function(name),
+            bindings: RefCell::new(HashMap::new()),
+            frame: Some(Frame {
+                params,
+                args: RefCell::new(args),
+                arg_spans
+            }),
+            parent: Some(Rc::clone(parent))
+        })
+    }
+
+    fn alloc(self) -> Rc<Self> {
+        let res = Rc::new(self);
+        heap::register_environment(&res);
+        res
+    }
+
+    /// Create or replace a binding in this scope.
+    pub fn define(&self, ident: Symbol, value: Value) {
+        self.bindings.borrow_mut().insert(ident, value);
+    }
+
+    /// Find the binding for an identifier, searching this scope
+    /// and then every enclosing scope.
+    pub fn get(&self, ident: Symbol) -> Option<Value> {
+        let mut scope = self;
+
+        loop {
+            if let Some(res) = scope.bindings.borrow().get(&ident) {
+                return Some(res.clone());
+            }
+
+            if let Some(Frame { params, args, .. }) = &scope.frame {
+                if let Some(idx) = params.iter().position(|&param| param == ident) {
+                    return Some(args.borrow()[idx].clone());
+                }
+            }
+
+            scope = scope.parent.as_deref()?;
+        }
+    }
+
+    /// Change the value of an existing binding, searching this scope
+    /// and then every enclosing scope.
+    /// Returns false if the identifier isn't bound.
+    pub fn set(&self, ident: Symbol, value: Value) -> bool {
+        let mut scope = self;
+
+        loop {
+            if let Some(binding) = scope.bindings.borrow_mut().get_mut(&ident) {
+                *binding = value;
+                return true;
+            }
+
+            if let Some(Frame { params, args, .. }) = &scope.frame {
+                if let Some(idx) = params.iter().position(|&param| param == ident) {
+                    args.borrow_mut()[idx] = value;
+                    return true;
+                }
+            }
+
+            match scope.parent.as_deref() {
+                Some(parent) => scope = parent,
+                None => return false
+            }
+        }
+    }
+
+    /// The scope which encloses this one, unless this is the global scope.
+    pub fn parent(&self) -> Option<&Rc<Self>> {
+        self.parent.as_ref()
+    }
+
+    /// The global scope, which encloses every other scope.
+    pub fn root(&self) -> &Self {
+        let mut scope = self;
+
+        while let Some(parent) = &scope.parent {
+            scope = parent;
+        }
+
+        scope
+    }
+
+    /// Find the argument at `idx` in the innermost function call.
+    pub fn argument(&self, idx: usize) -> Option<Value> {
+        let mut scope = self;
+
+        loop {
+            if let Some(frame) = &scope.frame {
+                return frame.args.borrow().get(idx).cloned();
+            }
+
+            scope = scope.parent.as_deref()?;
+        }
+    }
+
+    /// Change the argument at `idx` in the innermost function call.
+    /// Returns false if there is no such argument.
+    pub fn set_argument(&self, idx: usize, value: Value) -> bool {
+        let mut scope = self;
+
+        loop {
+            if let Some(frame) = &scope.frame {
+                return match frame.args.borrow_mut().get_mut(idx) {
+                    Some(arg) => {
+                        *arg = value;
+                        true
+                    },
+                    None => false
+                };
+            }
+
+            match scope.parent.as_deref() {
+                Some(parent) => scope = parent,
+                None => return false
+            }
+        }
+    }
+
+    /// Call `f` with every binding and argument in this scope, or return
+    /// `None` if the bindings are being changed.
+    pub fn for_each_binding(&self, mut f: impl FnMut(&Value)) -> Option<()> {
+        self.bindings.try_borrow().ok()?.values().for_each(&mut f);
+
+        if let Some(frame) = &self.frame {
+            frame.args.try_borrow().ok()?.iter().for_each(f);
+        }
+
+        Some(())
+    }
+
+    /// Remove every binding from this scope and replace its arguments with
+    /// unit, for the garbage collector to break a cycle. The values are
+    /// returned so they can be dropped later.
+    pub fn clear(&self) -> Vec<Value> {
+        let mut res: Vec<Value> = self.bindings.take().into_values().collect();
+
+        if let Some(frame) = &self.frame {
+            for arg in frame.args.borrow_mut().iter_mut() {
+                res.push(mem::replace(arg, Value::Unit));
+            }
+        }
+
+        res
+    }
+
+    /// Where the argument at `idx` in the innermost function call was
+    /// written, if it was a call to a macro.
+    pub fn argument_span(&self, idx: usize) -> Option<Range<usize>> {
+        let mut scope = self;
+
+        loop {
+            if let Some(frame) = &scope.frame {
+                return frame.arg_spans.get(idx).cloned();
+            }
+
+            scope = scope.parent.as_deref()?;
+        }
+    }
+
+    /// Describe this scope and every enclosing scope, innermost first.
+    pub fn describe_chain(&self) -> Vec<String> {
+        let mut res = Vec::new();
+        let mut scope = Some(self);
+
+        while let Some(inner) = scope {
+            res.push(inner.kind.to_string());
+            scope = inner.parent.as_deref();
+        }
+
+        res
+    }
+}
+
+// Scopes can contain closures which refer back to them,
+// so only the kind is shown to avoid printing forever
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Environment")
+            .field("kind", &self.kind)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_walks_parents() {
+        let global = Environment::global();
+        global.define(Symbol::intern("x"), Value::Integer(1));
+        let child = Environment::child(&global, ScopeKind::Let("let"));
+
+        assert!(child.get(Symbol::intern("x")).is_some());
+        assert!(child.get(Symbol::intern("y")).is_none());
+        assert_eq!(
+            child.describe_chain(),
+            vec!["a `let` expression", "the global scope"]
+        );
+    }
+}
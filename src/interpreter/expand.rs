@@ -0,0 +1,834 @@
+//! Macro expansion.
+//!
+//! Each top-level expression of a program is expanded right before it is
+//! resolved and run: a `defmacro` registers a macro, and every call to a
+//! macro is replaced with the code it produces. Macros are Nightbug
+//! functions which receive their arguments as unevaluated data and return
+//! new code as data, so they can use anything defined above them.
+//!
+//! Hygienic macros can also be defined with `define-syntax`, using the
+//! pattern-matching rules from the `syntax_rules` module.
+//!
+//! Expanded code keeps the spans of any arguments it contains. Everything
+//! else (ex. code from a quasiquoted template in the macro's body) is given
+//! the span of the macro call, so diagnostics point at the call site.
+
+use std::{ops::Range, rc::Rc};
+
+use super::{
+    environment::Environment,
+    quote::{head_keyword, quote_expr},
+    resolve,
+    syntax_rules::{self, ExpandError, Rule, SyntaxRules},
+    Expressions, Function, InterpResult, Interpreter, InterpreterError, Value, STACK_GROWTH,
+    STACK_RED_ZONE
+};
+use crate::{
+    parser::{Expr, ExprKind, Keyword},
+    symbol::Symbol
+};
+
+#[derive(Clone)]
+pub enum Macro {
+    /// A macro defined with `defmacro`
+    Procedural {
+        function: Rc<Function>,
+        /// If true, the last parameter collects any remaining arguments as a
+        /// list
+        variadic: bool
+    },
+    /// A hygienic macro defined with `define-syntax`
+    SyntaxRules(Rc<SyntaxRules>)
+}
+
+/// Keep `span` if it lies within the macro call at `call_span`,
+/// otherwise replace it with `call_span`.
+fn call_site_span(span: Range<usize>, call_span: &Range<usize>) -> Range<usize> {
+    if span.start < span.end && span.start >= call_span.start && span.end <= call_span.end {
+        span
+    } else {
+        call_span.clone()
+    }
+}
+
+/// Turn data produced by a macro back into code, where `span` is where
+/// the data was written (if it was quoted). Symbols with the name of a
+/// keyword become that keyword. On failure, returns the part of the data
+/// which isn't code.
+fn value_to_code(
+    value: Value,
+    span: Range<usize>,
+    call_span: &Range<usize>
+) -> Result<Expr, Value> {
+    let span = call_site_span(span, call_span);
+
+    let kind = match value {
+        Value::Symbol(sym) => return Ok(Expr::ident_to_expr(span, sym)),
+        Value::Integer(i) => ExprKind::Integer(i),
+        Value::BigInteger(i) => ExprKind::BigInteger(i),
+        Value::Rational(r) => ExprKind::Rational(r),
+        Value::Float(x) => ExprKind::Float(x),
+        Value::String(s) => ExprKind::String(s.to_string()),
+        Value::Boolean(b) => ExprKind::Boolean(b),
+        Value::Unit => ExprKind::Unit,
+
+        Value::Pair(ref pair) => {
+            let span = call_site_span(pair.span.clone(), call_span);
+            let mut contents = Vec::new();
+            let mut rest = &value;
+
+            loop {
+                match rest {
+                    Value::Pair(pair) => {
+                        let item = pair.car.clone();
+                        contents.push(value_to_code(item, pair.car_span.clone(), call_span)?);
+                        rest = &pair.cdr;
+                    },
+
+                    Value::Unit => return Ok(Expr::list(span, contents)),
+                    _ => return Err(value)
+                }
+            }
+        },
+
+        Value::Map(ref map) => {
+            let mut contents = Vec::with_capacity(map.len() * 2);
+
+            for (key, value) in map.iter() {
+                contents.push(value_to_code(key.clone(), 0..0, call_span)?);
+                contents.push(value_to_code(value.clone(), 0..0, call_span)?);
+            }
+
+            ExprKind::Map(contents.into())
+        },
+
+        Value::Set(ref set) => ExprKind::Set(
+            set.iter()
+                .map(|element| value_to_code(element.clone(), 0..0, call_span))
+                .collect::<Result<_, _>>()?
+        ),
+
+        Value::Box(_)
+        | Value::Vector(_)
+        | Value::Function(_)
+        | Value::NativeFunction(_)
+        | Value::Opaque(_) => return Err(value)
+    };
+
+    Ok(Expr::new(span, kind))
+}
+
+fn respan(expr: Expr, call_span: &Range<usize>) -> Expr {
+    let span = call_site_span(expr.span, call_span);
+    let respan_all = |contents: Vec<Expr>| {
+        contents
+            .into_iter()
+            .map(|expr| respan(expr, call_span))
+            .collect()
+    };
+
+    match expr.kind {
+        ExprKind::List(contents) => Expr::list(span, respan_all(contents.to_vec())),
+        ExprKind::Map(contents) => Expr::map(span, respan_all(contents.to_vec())),
+        ExprKind::Set(contents) => Expr::set(span, respan_all(contents.to_vec())),
+        kind => Expr::new(span, kind)
+    }
+}
+
+/// The keyword of a `defmacro` or `define-syntax` expression.
+fn macro_definition(expr: &Expr) -> Option<Keyword> {
+    match &expr.kind {
+        ExprKind::List(contents) => match head_keyword(contents) {
+            Some(keyword @ (Keyword::DefMacro | Keyword::DefineSyntax)) => Some(keyword),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+impl<'src> Interpreter<'src> {
+    /// Expand a top-level expression. Macro definitions are registered
+    /// and replaced with unit.
+    pub(super) fn expand_top_level(&mut self, expr: Expr) -> Result<Expr, InterpreterError> {
+        let span = expr.span.clone();
+
+        match macro_definition(&expr) {
+            Some(Keyword::DefMacro) => self.define_macro(expr)?,
+            Some(_) => self.define_syntax(expr)?,
+            None => return self.expand(expr)
+        }
+
+        Ok(Expr::unit(span))
+    }
+
+    /// Handle a `defmacro` expression, registering a macro.
+    /// `(defmacro name (params...) body...)` is like defining a function,
+    /// except that the last parameter can be preceded by `.`
+    /// to collect any remaining arguments as a list.
+    fn define_macro(&mut self, expr: Expr) -> Result<(), InterpreterError> {
+        let span = expr.span;
+        let mut expressions = match expr.kind {
+            ExprKind::List(contents) => Expressions::new(contents),
+            _ => unreachable!()
+        };
+        // Skip the keyword
+        expressions.next();
+
+        let name = match expressions.next() {
+            Some(Expr {
+                kind: ExprKind::Identifier(name),
+                ..
+            }) => name,
+
+            _ => return Err(self.malformed_expression("defmacro", span, "expected a macro name"))
+        };
+
+        let params_expr = match expressions.next() {
+            Some(params_expr) => params_expr,
+            None => {
+                return Err(self.malformed_expression(
+                    "defmacro",
+                    span,
+                    "expected a parameter list"
+                ))
+            },
+        };
+
+        let mut params = match params_expr.kind {
+            ExprKind::Unit => Vec::new(),
+            ExprKind::List(params) => self.parameter_names("defmacro", params.to_vec())?,
+            _ => {
+                return Err(self.malformed_expression(
+                    "defmacro",
+                    params_expr.span,
+                    "expected a parameter list"
+                ))
+            },
+        };
+
+        let dot = Symbol::intern(".");
+        let variadic = match params.iter().position(|&param| param == dot) {
+            Some(idx) if idx + 2 == params.len() => {
+                params.remove(idx);
+                true
+            },
+
+            Some(_) => {
+                return Err(self.malformed_expression(
+                    "defmacro",
+                    params_expr.span,
+                    "`.` must come right before the last parameter"
+                ))
+            },
+
+            None => false
+        };
+
+        if expressions.len() == 0 {
+            return Err(self.malformed_expression("defmacro", span, "expected a macro body"));
+        }
+
+        // Macros can use other macros in their bodies
+        let body = self.expand_body(params.clone(), expressions)?;
+        let body = resolve::resolve_function_body(body, &params);
+
+        let function = Function::new(Some(name), params.into(), body.into(), Rc::clone(&self.env));
+        self.macros
+            .insert(name, Macro::Procedural { function, variadic });
+        Ok(())
+    }
+
+    /// Handle a `define-syntax` expression, registering a hygienic macro.
+    /// `(define-syntax name (syntax-rules (literals...) (pattern
+    /// template)...))` defines a macro which expands to the template of the
+    /// first rule whose pattern matches.
+    fn define_syntax(&mut self, expr: Expr) -> Result<(), InterpreterError> {
+        let span = expr.span;
+        let mut expressions = match expr.kind {
+            ExprKind::List(contents) => Expressions::new(contents),
+            _ => unreachable!()
+        };
+        // Skip the keyword
+        expressions.next();
+
+        let name = match expressions.next() {
+            Some(Expr {
+                kind: ExprKind::Identifier(name),
+                ..
+            }) => name,
+
+            _ => {
+                return Err(self.malformed_expression(
+                    "define-syntax",
+                    span,
+                    "expected a macro name"
+                ))
+            },
+        };
+
+        let rules_expr = match (expressions.next(), expressions.next()) {
+            (Some(rules_expr), None) => rules_expr,
+            _ => {
+                return Err(self.malformed_expression(
+                    "define-syntax",
+                    span,
+                    "expected a name followed by a `syntax-rules` expression"
+                ))
+            },
+        };
+
+        let rules = self.syntax_rules(rules_expr)?;
+        self.macros.insert(name, Macro::SyntaxRules(Rc::new(rules)));
+        Ok(())
+    }
+
+    /// Check a `syntax-rules` expression, returning its rules.
+    fn syntax_rules(&self, expr: Expr) -> Result<SyntaxRules, InterpreterError> {
+        let span = expr.span;
+        let mut expressions = match expr.kind {
+            ExprKind::List(contents) if head_keyword(&contents) == Some(Keyword::SyntaxRules) => {
+                Expressions::new(contents)
+            },
+
+            _ => {
+                return Err(self.malformed_expression(
+                    "define-syntax",
+                    span,
+                    "expected a `syntax-rules` expression"
+                ))
+            },
+        };
+        expressions.next();
+
+        let literals = match expressions.next().map(|expr| expr.kind) {
+            Some(ExprKind::Unit) => Vec::new(),
+            Some(ExprKind::List(literals)) => {
+                // Keywords always match themselves, so they don't need to be listed
+                let literals = literals
+                    .iter()
+                    .filter(|literal| !matches!(literal.kind, ExprKind::Keyword(_)))
+                    .cloned()
+                    .collect();
+                self.parameter_names("syntax-rules", literals)?
+            },
+            _ => {
+                return Err(self.malformed_expression(
+                    "syntax-rules",
+                    span,
+                    "expected a list of literals"
+                ))
+            },
+        };
+
+        let mut rules = Vec::with_capacity(expressions.len());
+
+        for rule in expressions {
+            let mut parts = match rule.kind {
+                ExprKind::List(parts) if parts.len() == 2 => Expressions::new(parts),
+                _ => {
+                    return Err(self.malformed_expression(
+                        "syntax-rules",
+                        rule.span,
+                        "expected a rule made of a pattern and a template"
+                    ))
+                },
+            };
+
+            // nb. the unwraps are safe because of the length check above
+            let pattern = parts.next().unwrap();
+            let template = parts.next().unwrap();
+
+            // The first item of the pattern is the macro's name, which is ignored
+            let pattern = match pattern.kind {
+                ExprKind::List(pattern) => pattern[1..].to_vec(),
+                _ => {
+                    return Err(self.malformed_expression(
+                        "syntax-rules",
+                        pattern.span,
+                        "expected a pattern starting with the macro name"
+                    ))
+                },
+            };
+
+            syntax_rules::check_ellipses(&pattern, true)
+                .and_then(|()| syntax_rules::check_ellipses(std::slice::from_ref(&template), false))
+                .map_err(|(span, reason)| {
+                    self.malformed_expression("syntax-rules", span, &reason)
+                })?;
+
+            rules.push(Rule::new(pattern, template));
+        }
+
+        Ok(SyntaxRules::new(literals, rules))
+    }
+
+    fn expand_all(&mut self, expressions: Expressions) -> Result<Vec<Expr>, InterpreterError> {
+        expressions.map(|expr| self.expand(expr)).collect()
+    }
+
+    /// Expand a body which is evaluated in a new scope, where `names` are
+    /// bound. Names `define`d in the body are bound throughout it as well.
+    fn expand_body(
+        &mut self,
+        mut names: Vec<Symbol>,
+        body: Expressions
+    ) -> Result<Vec<Expr>, InterpreterError> {
+        let body: Vec<Expr> = body.collect();
+
+        for expr in &body {
+            resolve::defined_names(expr, &mut names);
+        }
+
+        self.with_local_names(names, |this| this.expand_all(body.into()))
+    }
+
+    /// Run `f` with `names` bound as local variables, hiding any macros
+    /// with the same names.
+    fn with_local_names<T>(&mut self, names: Vec<Symbol>, f: impl FnOnce(&mut Self) -> T) -> T {
+        let prev_len = self.local_names.len();
+        self.local_names.extend(names);
+        let res = f(self);
+        self.local_names.truncate(prev_len);
+        res
+    }
+
+    /// The macro called by a list, if any.
+    /// Like other identifiers, a name renamed by a hygienic macro
+    /// refers to the macro with its original name.
+    /// A local variable with the same name as a macro hides it.
+    fn called_macro(&self, contents: &[Expr]) -> Option<Macro> {
+        match contents.first() {
+            Some(Expr {
+                kind: ExprKind::Identifier(name),
+                ..
+            }) if !self.local_names.contains(name) => self
+                .macros
+                .get(name)
+                .or_else(|| self.macros.get(&name.original()?))
+                .cloned(),
+            _ => None
+        }
+    }
+
+    /// Expand every macro call in an expression.
+    fn expand(&mut self, expr: Expr) -> Result<Expr, InterpreterError> {
+        // Expanding nested expressions and the results of macro calls
+        // recurses, so the stack is grown as needed like in `interpret_expr`
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || self.expand_expr(expr))
+    }
+
+    fn expand_expr(&mut self, expr: Expr) -> Result<Expr, InterpreterError> {
+        let Expr { span, kind } = expr;
+
+        let contents = match kind {
+            ExprKind::List(contents) => contents,
+
+            ExprKind::Map(contents) => {
+                let contents = self.expand_all(Expressions::new(contents))?;
+                return Ok(Expr::map(span, contents));
+            },
+
+            ExprKind::Set(contents) => {
+                let contents = self.expand_all(Expressions::new(contents))?;
+                return Ok(Expr::set(span, contents));
+            },
+
+            kind => return Ok(Expr::new(span, kind))
+        };
+
+        if let Some(mac) = self.called_macro(&contents) {
+            // A macro which always expands to another call to itself
+            // would otherwise never finish
+            if self.expansion_depth >= self.recursion_limit {
+                return Err(self.stack_overflow(span));
+            }
+
+            self.expansion_depth += 1;
+            let res = self
+                .expand_call(&mac, span, contents)
+                .and_then(|expanded| self.expand(expanded));
+            self.expansion_depth -= 1;
+            return res;
+        }
+
+        let keyword = head_keyword(&contents);
+
+        let mut expressions = Expressions::new(contents);
+
+        let contents = match keyword {
+            Some(keyword @ (Keyword::DefMacro | Keyword::DefineSyntax)) => {
+                return Err(self.malformed_expression(
+                    keyword.as_str(),
+                    span,
+                    "macros can only be defined at the top level"
+                ))
+            },
+
+            Some(Keyword::Quote) => expressions.collect(),
+
+            Some(Keyword::Quasiquote) => {
+                let mut res: Vec<Expr> = expressions.next().into_iter().collect();
+
+                for expr in expressions {
+                    res.push(self.expand_quasiquoted(expr, 0)?);
+                }
+
+                res
+            },
+
+            // Parameter lists and function signatures aren't code
+            Some(Keyword::Fn) => {
+                let mut res: Vec<Expr> = expressions.by_ref().take(2).collect();
+                let params = match res.get(1) {
+                    Some(params_expr) => resolve::parameter_names(params_expr),
+                    None => Vec::new()
+                };
+
+                let params = params.into_iter().flatten().collect();
+                res.extend(self.expand_body(params, expressions)?);
+                res
+            },
+
+            Some(Keyword::Define) => {
+                let mut res: Vec<Expr> = expressions.by_ref().take(2).collect();
+
+                match res.get(1) {
+                    // (define (name params...) body...)
+                    Some(
+                        signature @ Expr {
+                            kind: ExprKind::List(_),
+                            ..
+                        }
+                    ) => {
+                        let params = resolve::parameter_names(signature)
+                            .into_iter()
+                            .skip(1)
+                            .flatten()
+                            .collect();
+                        res.extend(self.expand_body(params, expressions)?);
+                    },
+
+                    // (define name value)
+                    _ => res.extend(self.expand_all(expressions)?)
+                }
+
+                res
+            },
+
+            // Only the values in binding lists are code
+            Some(keyword @ (Keyword::Let | Keyword::LetStar | Keyword::LetRec)) => {
+                let mut res: Vec<Expr> = expressions.next().into_iter().collect();
+                let mut names = Vec::new();
+
+                if let Some(bindings_expr) = expressions.next() {
+                    if let ExprKind::List(bindings) = &bindings_expr.kind {
+                        names.extend(bindings.iter().filter_map(resolve::let_binding_name));
+                    }
+
+                    res.push(self.expand_let_bindings(keyword, &names, bindings_expr)?);
+                }
+
+                res.extend(self.expand_body(names, expressions)?);
+                res
+            },
+
+            _ => self.expand_all(expressions)?
+        };
+
+        Ok(Expr::list(span, contents))
+    }
+
+    /// Expand the values in a `let` binding list of the form `((name
+    /// value)...)`, which binds `names`.
+    fn expand_let_bindings(
+        &mut self,
+        keyword: Keyword,
+        names: &[Symbol],
+        bindings_expr: Expr
+    ) -> Result<Expr, InterpreterError> {
+        let bindings = match bindings_expr.kind {
+            ExprKind::List(bindings) => bindings.to_vec(),
+            _ => return Ok(bindings_expr)
+        };
+
+        let mut res = Vec::with_capacity(bindings.len());
+        // The names bound before the current binding
+        let mut bound = 0;
+
+        for binding in bindings {
+            let visible = match keyword {
+                Keyword::Let => &[],
+                // Each value can see the bindings before it
+                Keyword::LetStar => &names[..bound],
+                _ => names
+            };
+
+            if resolve::let_binding_name(&binding).is_some() {
+                bound += 1;
+            }
+
+            match binding.kind {
+                ExprKind::List(pair) => {
+                    let mut pair = Expressions::new(pair);
+                    let mut expanded: Vec<Expr> = pair.next().into_iter().collect();
+                    expanded.extend(
+                        self.with_local_names(visible.to_vec(), |this| this.expand_all(pair))?
+                    );
+                    res.push(Expr::list(binding.span, expanded));
+                },
+
+                kind => res.push(Expr::new(binding.span, kind))
+            }
+        }
+
+        Ok(Expr::list(bindings_expr.span, res))
+    }
+
+    /// Expand the unquoted parts of an expression which is `depth`
+    /// quasiquotes deeper than the outermost one.
+    fn expand_quasiquoted(&mut self, expr: Expr, depth: usize) -> Result<Expr, InterpreterError> {
+        let Expr { span, kind } = expr;
+
+        let contents = match kind {
+            ExprKind::List(contents) => contents.to_vec(),
+
+            ExprKind::Map(contents) => {
+                let contents = self.expand_all_quasiquoted(contents.to_vec(), depth)?;
+                return Ok(Expr::map(span, contents));
+            },
+
+            ExprKind::Set(contents) => {
+                let contents = self.expand_all_quasiquoted(contents.to_vec(), depth)?;
+                return Ok(Expr::set(span, contents));
+            },
+
+            kind => return Ok(Expr::new(span, kind))
+        };
+
+        let inner_depth = match contents.first().map(|expr| &expr.kind) {
+            Some(ExprKind::Keyword(Keyword::Quasiquote)) => depth + 1,
+
+            Some(ExprKind::Keyword(Keyword::Unquote | Keyword::UnquoteSplicing)) if depth == 0 => {
+                return self.expand(Expr::list(span, contents));
+            },
+
+            Some(ExprKind::Keyword(Keyword::Unquote | Keyword::UnquoteSplicing)) => depth - 1,
+            _ => depth
+        };
+
+        let contents = self.expand_all_quasiquoted(contents, inner_depth)?;
+        Ok(Expr::list(span, contents))
+    }
+
+    fn expand_all_quasiquoted(
+        &mut self,
+        exprs: Vec<Expr>,
+        depth: usize
+    ) -> Result<Vec<Expr>, InterpreterError> {
+        exprs
+            .into_iter()
+            .map(|expr| self.expand_quasiquoted(expr, depth))
+            .collect()
+    }
+
+    /// Expand a single call to a macro, without expanding the result.
+    fn expand_call(
+        &mut self,
+        mac: &Macro,
+        span: Range<usize>,
+        contents: Rc<[Expr]>
+    ) -> Result<Expr, InterpreterError> {
+        let mut contents = Expressions::new(contents);
+        // nb. the list starts with the macro's name
+        let name_expr = contents.next().unwrap();
+        let ident = match name_expr.kind {
+            ExprKind::Identifier(name) => name.to_string(),
+            _ => unreachable!()
+        };
+        let arg_exprs: Vec<Expr> = contents.collect();
+
+        match mac {
+            Macro::Procedural { function, variadic } => {
+                self.call_procedural(function, *variadic, ident, name_expr.span, span, arg_exprs)
+            },
+
+            Macro::SyntaxRules(rules) => self.expand_syntax_rules(rules, ident, span, &arg_exprs)
+        }
+    }
+
+    /// Expand a call to a macro defined with `defmacro` by calling it
+    /// with its arguments as data.
+    fn call_procedural(
+        &mut self,
+        function: &Function,
+        variadic: bool,
+        ident: String,
+        name_span: Range<usize>,
+        span: Range<usize>,
+        arg_exprs: Vec<Expr>
+    ) -> Result<Expr, InterpreterError> {
+        let min_args = function.params.len() - variadic as usize;
+
+        if !variadic && arg_exprs.len() != min_args {
+            let arg_spans: Vec<_> = arg_exprs.iter().map(|expr| expr.span.clone()).collect();
+            return Err(self.wrong_num_args(&ident, name_span, min_args, &arg_spans));
+        }
+
+        if arg_exprs.len() < min_args {
+            self.build_error(&format!(
+                "not enough arguments for macro `{}` (expected at least {}, got {})",
+                ident,
+                min_args,
+                arg_exprs.len()
+            ))
+            .with_span(span)
+            .emit();
+
+            return Err(InterpreterError::NotEnoughArgs {
+                ident,
+                min: min_args,
+                got: arg_exprs.len()
+            });
+        }
+
+        let mut arg_spans: Vec<_> = arg_exprs.iter().map(|expr| expr.span.clone()).collect();
+        let mut args: Vec<Value> = arg_exprs.into_iter().map(quote_expr).collect();
+
+        if variadic {
+            let rest_spans = arg_spans.split_off(min_args);
+            let rest = args.split_off(min_args);
+            let rest_span = match (rest_spans.first(), rest_spans.last()) {
+                (Some(first), Some(last)) => first.start..last.end,
+                _ => 0..0
+            };
+
+            let rest = rest.into_iter().zip(rest_spans).rev().fold(
+                Value::Unit,
+                |res, (item, item_span)| {
+                    let pair_span = item_span.start..rest_span.end;
+                    Value::cons_at(item, item_span, res, pair_span)
+                }
+            );
+            args.push(rest);
+            arg_spans.push(rest_span);
+        }
+
+        let env = Environment::macro_call(
+            &function.env,
+            function.name,
+            Rc::clone(&function.params),
+            args,
+            arg_spans
+        );
+        let res = self.with_env(env, |this| {
+            this.interpret(Expressions::new(Rc::clone(&function.body)))
+        })?;
+
+        value_to_code(res, 0..0, &span).map_err(|value| {
+            self.build_error(&format!(
+                "macro `{}` expanded to {}, which isn't code",
+                ident,
+                value.type_name()
+            ))
+            .span_label(span, "in this macro call")
+            .emit();
+            InterpreterError::InvalidExpansion(ident, value)
+        })
+    }
+
+    /// Expand a call to a macro defined with `define-syntax` using the
+    /// first of its rules that matches.
+    fn expand_syntax_rules(
+        &self,
+        rules: &SyntaxRules,
+        ident: String,
+        span: Range<usize>,
+        arg_exprs: &[Expr]
+    ) -> Result<Expr, InterpreterError> {
+        match rules.expand(span.clone(), arg_exprs) {
+            Ok(expr) => Ok(respan(expr, &span)),
+
+            Err(ExpandError::NoMatch(failures)) => {
+                let mut msg =
+                    self.build_error(&format!("no rule of macro `{}` matches this call", ident));
+
+                if failures.is_empty() {
+                    msg = msg
+                        .with_span(span)
+                        .note(&format!("`{}` has no rules", ident));
+                }
+
+                for (idx, failure) in failures.into_iter().enumerate() {
+                    msg = msg.span_label(
+                        failure.span,
+                        &format!("rule {} failed here: {}", idx + 1, failure.reason)
+                    );
+                }
+
+                msg.emit();
+                Err(InterpreterError::NoMatchingRule(ident))
+            },
+
+            Err(ExpandError::Template(template_span, reason)) => {
+                self.build_error(&format!("couldn't expand macro `{}`", ident))
+                    .span_label(template_span, &reason)
+                    .span_label(span, "in this macro call")
+                    .emit();
+                Err(InterpreterError::MalformedExpression(
+                    Keyword::SyntaxRules.as_str().to_string()
+                ))
+            }
+        }
+    }
+
+    /// Handle a `macroexpand` expression.
+    /// `(macroexpand expr)` evaluates `expr` to get some code as data, then
+    /// expands it for as long as it is a call to a macro. Macro calls inside
+    /// of the result aren't expanded.
+    pub(super) fn handle_macroexpand(
+        &mut self,
+        span: Range<usize>,
+        mut expressions: Expressions
+    ) -> InterpResult {
+        if expressions.len() != 1 {
+            return Err(self.malformed_expression(
+                "macroexpand",
+                span,
+                "expected exactly one expression"
+            ));
+        }
+
+        // nb. the unwrap is safe because of the check above
+        let expr = expressions.next().unwrap();
+        let expr_span = expr.span.clone();
+        let value = self.interpret_expr(expr)?;
+
+        let mut code = value_to_code(value, 0..0, &span).map_err(|value| {
+            self.build_error("`macroexpand` expects code")
+                .span_label(expr_span, &format!("found {}", value.type_name()))
+                .emit();
+            InterpreterError::InvalidArgument("macroexpand".to_string(), value, None)
+        })?;
+
+        let mut expansions = 0;
+
+        while let Some(mac) = match &code.kind {
+            ExprKind::List(contents) => self.called_macro(contents),
+            _ => None
+        } {
+            if expansions >= self.recursion_limit {
+                return Err(self.stack_overflow(code.span));
+            }
+
+            expansions += 1;
+            let Expr { span, kind } = code;
+            let contents = match kind {
+                ExprKind::List(contents) => contents,
+                _ => unreachable!()
+            };
+            code = self.expand_call(&mac, span, contents)?;
+        }
+
+        Ok(quote_expr(code))
+    }
+}
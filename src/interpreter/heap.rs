@@ -0,0 +1,553 @@
+//! The heap of runtime values: pairs, functions, the scopes functions
+//! capture, boxes, vectors, maps, sets and strings.
+//!
+//! Values are reference counted, which frees most of them as soon as they
+//! are no longer used. Reference counting can't free cycles though, such as
+//! a function defined inside another function, which is bound in the scope
+//! it captures, or a box which holds itself. Each `Interpreter` owns a heap,
+//! and every value created while it is running is registered with that heap,
+//! so that a tracing collector can find and break these cycles. Values
+//! created while no interpreter is running (ex. by the program embedding
+//! one) aren't registered anywhere.
+//!
+//! A collection traces from the interpreter's roots: its global scope, its
+//! current scope and its macros. Values can also be referenced from outside
+//! the heap while the interpreter is running: by arguments which are still
+//! being evaluated, the VM's operand stack, values captured by natives and
+//! so on. These are roots too, and the collector finds them by comparing
+//! each value's reference count with the number of references to it from
+//! other values in the heap, like CPython's cycle collector does.
+//! Everything which can't be reached from a root is garbage, and clearing
+//! its scopes, boxes, and vectors breaks the cycles keeping it alive. Maps
+//! and sets are immutable, so they are never cleared, but they are still
+//! traced since they can hold a box or vector which holds them. Strings
+//! can't hold anything, so they're only counted, and creating them never
+//! leads to a collection.
+//!
+//! This is a mark-and-sweep collector layered over reference counting,
+//! rather than a heap which only frees values when it is traced. Values
+//! are shared with Rust code as `Rc`s (by natives, opaque values, the VM
+//! and the tree-walker's own stack frames), and with a purely traced heap
+//! each of those would have to be registered as a root by hand, where
+//! missing one would free a value which is still in use. Counting references
+//! keeps those values alive without any bookkeeping, and lets the collector
+//! treat them as roots alongside the interpreter's own.
+//!
+//! Dropping a value can free everything it holds, which Rust would do
+//! recursively, overflowing the stack for deeply nested values. Instead,
+//! each value moves out whatever it holds the last reference to, and those
+//! are dropped one at a time from a worklist by `drop_all`.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hasher},
+    mem,
+    rc::{Rc, Weak}
+};
+
+use indexmap::{IndexMap, IndexSet};
+
+use super::{
+    environment::Environment,
+    value::{Map, Mutable, Set},
+    Function, Pair, Value
+};
+
+/// How many values can be created before the first automatic collection.
+const INITIAL_THRESHOLD: usize = 10_000;
+
+thread_local! {
+    /// The heap of the interpreter which is running on this thread,
+    /// which new values are registered with
+    static ACTIVE: RefCell<Option<Rc<RefCell<Heap>>>> = const { RefCell::new(None) };
+}
+
+/// Keeps a heap active until it is dropped.
+pub struct ActiveHeap {
+    prev: Option<Rc<RefCell<Heap>>>
+}
+
+impl Drop for ActiveHeap {
+    fn drop(&mut self) {
+        ACTIVE.with(|active| *active.borrow_mut() = self.prev.take());
+    }
+}
+
+/// Register new values with `heap` until the returned guard is dropped.
+/// An interpreter can be run by a native called from another one,
+/// so the heap which was active before is restored afterwards.
+pub fn enter(heap: &Rc<RefCell<Heap>>) -> ActiveHeap {
+    let prev = ACTIVE.with(|active| active.replace(Some(Rc::clone(heap))));
+    ActiveHeap { prev }
+}
+
+/// The values an interpreter refers to directly,
+/// which a collection traces from.
+#[derive(Default)]
+pub struct Roots(Vec<*const ()>);
+
+impl Roots {
+    pub fn environment(&mut self, env: &Environment) {
+        self.0.push((env as *const Environment).cast());
+    }
+
+    pub fn function(&mut self, function: &Function) {
+        self.0.push((function as *const Function).cast());
+    }
+}
+
+/// A snapshot of the heap, for `(heap-stats)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HeapStats {
+    /// How many values of each kind are alive
+    pub pairs: usize,
+    pub functions: usize,
+    pub environments: usize,
+    pub boxes: usize,
+    pub vectors: usize,
+    pub maps: usize,
+    pub sets: usize,
+    pub strings: usize,
+    /// How many collections have run
+    pub collections: usize,
+    /// How many values every collection has freed in total
+    pub freed: usize
+}
+
+pub struct Heap {
+    pairs: Vec<Weak<Pair>>,
+    functions: Vec<Weak<Function>>,
+    environments: Vec<Weak<Environment>>,
+    boxes: Vec<Weak<Mutable<Value>>>,
+    vectors: Vec<Weak<Mutable<Vec<Value>>>>,
+    maps: Vec<Weak<Map>>,
+    sets: Vec<Weak<Set>>,
+    /// Strings can't refer to other values, so they are never traced,
+    /// only forgotten about once they've been freed
+    strings: Vec<Weak<str>>,
+    /// How many values other than strings to register
+    /// before collecting automatically
+    threshold: usize,
+    /// How many strings to register before forgetting the freed ones
+    strings_threshold: usize,
+    collections: usize,
+    freed: usize
+}
+
+/// A value in the heap during a collection.
+enum Node {
+    Pair(Rc<Pair>),
+    Function(Rc<Function>),
+    Environment(Rc<Environment>),
+    Box(Rc<Mutable<Value>>),
+    Vector(Rc<Mutable<Vec<Value>>>),
+    Map(Rc<Map>),
+    Set(Rc<Set>)
+}
+
+impl Node {
+    fn address(&self) -> *const () {
+        match self {
+            Node::Pair(pair) => Rc::as_ptr(pair) as *const (),
+            Node::Function(function) => Rc::as_ptr(function) as *const (),
+            Node::Environment(env) => Rc::as_ptr(env) as *const (),
+            Node::Box(cell) => Rc::as_ptr(cell) as *const (),
+            Node::Vector(vector) => Rc::as_ptr(vector) as *const (),
+            Node::Map(map) => Rc::as_ptr(map) as *const (),
+            Node::Set(set) => Rc::as_ptr(set) as *const ()
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Pair(pair) => Rc::strong_count(pair),
+            Node::Function(function) => Rc::strong_count(function),
+            Node::Environment(env) => Rc::strong_count(env),
+            Node::Box(cell) => Rc::strong_count(cell),
+            Node::Vector(vector) => Rc::strong_count(vector),
+            Node::Map(map) => Rc::strong_count(map),
+            Node::Set(set) => Rc::strong_count(set)
+        }
+    }
+
+    /// Call `f` with the address of every value in the heap this one
+    /// refers to, or return `None` if they can't be found right now.
+    fn children(&self, mut f: impl FnMut(*const ())) -> Option<()> {
+        match self {
+            Node::Pair(pair) => {
+                value_address(&pair.car, &mut f);
+                value_address(&pair.cdr, &mut f);
+            },
+
+            Node::Function(function) => f(Rc::as_ptr(&function.env) as *const ()),
+
+            Node::Environment(env) => {
+                env.for_each_binding(|value| value_address(value, &mut f))?;
+
+                if let Some(parent) = env.parent() {
+                    f(Rc::as_ptr(parent) as *const ());
+                }
+            },
+
+            Node::Box(cell) => value_address(&*cell.try_borrow().ok()?, &mut f),
+
+            Node::Vector(vector) => vector
+                .try_borrow()
+                .ok()?
+                .iter()
+                .for_each(|item| value_address(item, &mut f)),
+
+            Node::Map(map) => {
+                for (key, value) in map.iter() {
+                    value_address(key, &mut f);
+                    value_address(value, &mut f);
+                }
+            },
+
+            Node::Set(set) => set
+                .iter()
+                .for_each(|element| value_address(element, &mut f))
+        }
+
+        Some(())
+    }
+}
+
+fn value_address(value: &Value, f: &mut impl FnMut(*const ())) {
+    match value {
+        Value::Pair(pair) => f(Rc::as_ptr(pair) as *const ()),
+        Value::Function(function) => f(Rc::as_ptr(function) as *const ()),
+        Value::Box(cell) => f(Rc::as_ptr(cell) as *const ()),
+        Value::Vector(vector) => f(Rc::as_ptr(vector) as *const ()),
+        Value::Map(map) => f(Rc::as_ptr(map) as *const ()),
+        Value::Set(set) => f(Rc::as_ptr(set) as *const ()),
+        // Natives and opaque values may hold other values, but those can't
+        // be seen, so they count as references from outside the heap
+        _ => ()
+    }
+}
+
+/// Hashes the addresses of values, which are already well distributed
+/// apart from their alignment.
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, _: &[u8]) {
+        unreachable!("only addresses are hashed")
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.0 = (n as u64 >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// Upgrade every live value, forgetting about the ones which were freed.
+fn live<T: ?Sized>(values: &mut Vec<Weak<T>>) -> Vec<Rc<T>> {
+    let mut res = Vec::with_capacity(values.len());
+    values.retain(|value| match value.upgrade() {
+        Some(value) => {
+            res.push(value);
+            true
+        },
+        None => false
+    });
+    res
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            pairs: Vec::new(),
+            functions: Vec::new(),
+            environments: Vec::new(),
+            boxes: Vec::new(),
+            vectors: Vec::new(),
+            maps: Vec::new(),
+            sets: Vec::new(),
+            strings: Vec::new(),
+            threshold: INITIAL_THRESHOLD,
+            strings_threshold: INITIAL_THRESHOLD,
+            collections: 0,
+            freed: 0
+        }
+    }
+
+    /// How many values which could be part of a cycle have been registered.
+    fn registered(&self) -> usize {
+        self.pairs.len()
+            + self.functions.len()
+            + self.environments.len()
+            + self.boxes.len()
+            + self.vectors.len()
+            + self.maps.len()
+            + self.sets.len()
+    }
+
+    /// Whether enough values have been created since the last collection
+    /// to run another one.
+    pub fn wants_collection(&self) -> bool {
+        self.registered() >= self.threshold
+    }
+
+    pub fn stats(&mut self) -> HeapStats {
+        HeapStats {
+            pairs: live(&mut self.pairs).len(),
+            functions: live(&mut self.functions).len(),
+            environments: live(&mut self.environments).len(),
+            boxes: live(&mut self.boxes).len(),
+            vectors: live(&mut self.vectors).len(),
+            maps: live(&mut self.maps).len(),
+            sets: live(&mut self.sets).len(),
+            strings: live(&mut self.strings).len(),
+            collections: self.collections,
+            freed: self.freed
+        }
+    }
+
+    /// Find every value which can't be reached from `roots`,
+    /// or from a value referenced from outside the heap.
+    fn find_garbage(&mut self, roots: &Roots) -> Vec<Node> {
+        self.strings.retain(|string| string.strong_count() > 0);
+
+        let nodes: Vec<Node> = live(&mut self.pairs)
+            .into_iter()
+            .map(Node::Pair)
+            .chain(live(&mut self.functions).into_iter().map(Node::Function))
+            .chain(
+                live(&mut self.environments)
+                    .into_iter()
+                    .map(Node::Environment)
+            )
+            .chain(live(&mut self.boxes).into_iter().map(Node::Box))
+            .chain(live(&mut self.vectors).into_iter().map(Node::Vector))
+            .chain(live(&mut self.maps).into_iter().map(Node::Map))
+            .chain(live(&mut self.sets).into_iter().map(Node::Set))
+            .collect();
+
+        let ids: HashMap<usize, usize, BuildHasherDefault<AddressHasher>> = nodes
+            .iter()
+            .enumerate()
+            .map(|(id, node)| (node.address() as usize, id))
+            .collect();
+
+        // The children of node `id` are `edges[starts[id]..starts[id + 1]]`
+        let mut edges = Vec::new();
+        let mut starts = Vec::with_capacity(nodes.len() + 1);
+        let mut internal = vec![0; nodes.len()];
+        // Nodes whose children can't be found have to be treated as roots
+        let mut unknown = vec![false; nodes.len()];
+
+        for (id, node) in nodes.iter().enumerate() {
+            starts.push(edges.len());
+
+            let found = node.children(|addr| {
+                if let Some(&child) = ids.get(&(addr as usize)) {
+                    edges.push(child);
+                    internal[child] += 1;
+                }
+            });
+
+            if found.is_none() {
+                unknown[id] = true;
+            }
+        }
+
+        starts.push(edges.len());
+
+        // nb. `nodes` holds one reference to every value itself
+        let mut stack: Vec<usize> = (0..nodes.len())
+            .filter(|&id| unknown[id] || nodes[id].strong_count() - 1 > internal[id])
+            .chain(
+                roots
+                    .0
+                    .iter()
+                    .filter_map(|&addr| ids.get(&(addr as usize)).copied())
+            )
+            .collect();
+        let mut reachable = vec![false; nodes.len()];
+
+        while let Some(id) = stack.pop() {
+            if reachable[id] {
+                continue;
+            }
+
+            reachable[id] = true;
+            stack.extend_from_slice(&edges[starts[id]..starts[id + 1]]);
+        }
+
+        nodes
+            .into_iter()
+            .zip(reachable)
+            .filter(|(_, reachable)| !reachable)
+            .map(|(node, _)| node)
+            .collect()
+    }
+}
+
+/// Register a value with the active heap, if there is one.
+/// Collections aren't run from here, since only the interpreter
+/// knows its roots; it checks `Heap::wants_collection` instead.
+fn register(f: impl FnOnce(&mut Heap)) {
+    ACTIVE.with(|active| {
+        if let Some(heap) = &*active.borrow() {
+            f(&mut heap.borrow_mut());
+        }
+    });
+}
+
+pub fn register_pair(pair: &Rc<Pair>) {
+    register(|heap| heap.pairs.push(Rc::downgrade(pair)));
+}
+
+pub fn register_function(function: &Rc<Function>) {
+    register(|heap| heap.functions.push(Rc::downgrade(function)));
+}
+
+pub fn register_environment(env: &Rc<Environment>) {
+    register(|heap| heap.environments.push(Rc::downgrade(env)));
+}
+
+pub fn register_box(cell: &Rc<Mutable<Value>>) {
+    register(|heap| heap.boxes.push(Rc::downgrade(cell)));
+}
+
+pub fn register_vector(vector: &Rc<Mutable<Vec<Value>>>) {
+    register(|heap| heap.vectors.push(Rc::downgrade(vector)));
+}
+
+pub fn register_map(map: &Rc<Map>) {
+    register(|heap| heap.maps.push(Rc::downgrade(map)));
+}
+
+pub fn register_set(set: &Rc<Set>) {
+    register(|heap| heap.sets.push(Rc::downgrade(set)));
+}
+
+pub fn register_string(string: &Rc<str>) {
+    register(|heap| {
+        heap.strings.push(Rc::downgrade(string));
+
+        // Strings are never collected, so the freed ones are
+        // only forgotten about once enough of them have piled up
+        if heap.strings.len() >= heap.strings_threshold {
+            heap.strings.retain(|string| string.strong_count() > 0);
+            heap.strings_threshold = INITIAL_THRESHOLD.max(heap.strings.len() * 2);
+        }
+    });
+}
+
+/// Free every value in `heap` which is unreachable from `roots`,
+/// returning how many were freed.
+pub fn collect(heap: &RefCell<Heap>, roots: &Roots) -> usize {
+    let garbage = heap.borrow_mut().find_garbage(roots);
+    let freed = garbage.len();
+
+    // The contents are dropped outside of the loop, since dropping them
+    // may free other garbage which is still being cleared
+    let mut cleared = Vec::new();
+
+    for node in &garbage {
+        match node {
+            Node::Environment(env) => cleared.append(&mut env.clear()),
+            Node::Box(cell) => cleared.push(cell.replace(Value::Unit)),
+            Node::Vector(vector) => cleared.append(&mut vector.take()),
+            Node::Pair(_) | Node::Function(_) | Node::Map(_) | Node::Set(_) => ()
+        }
+    }
+
+    drop(cleared);
+    drop(garbage);
+
+    let mut heap = heap.borrow_mut();
+    heap.collections += 1;
+    heap.freed += freed;
+    // Wait until the heap has doubled before collecting again
+    heap.threshold = INITIAL_THRESHOLD.max(heap.registered() * 2);
+
+    freed
+}
+
+/// A value which is being dropped, and may hold the last reference
+/// to other values in the heap.
+pub enum Owned {
+    Value(Value),
+    Environment(Rc<Environment>)
+}
+
+/// Values which can hold other values in the heap.
+pub trait Children {
+    /// Move every value this holds onto `owned`, so they can be dropped
+    /// by `drop_all` rather than recursively.
+    fn take_children(&mut self, owned: &mut Vec<Owned>);
+}
+
+impl Children for Value {
+    fn take_children(&mut self, owned: &mut Vec<Owned>) {
+        // Other values don't hold anything in the heap
+        if let Value::Pair(_)
+        | Value::Function(_)
+        | Value::Box(_)
+        | Value::Vector(_)
+        | Value::Map(_)
+        | Value::Set(_) = self
+        {
+            owned.push(Owned::Value(mem::replace(self, Value::Unit)));
+        }
+    }
+}
+
+impl Children for Vec<Value> {
+    fn take_children(&mut self, owned: &mut Vec<Owned>) {
+        for value in self.iter_mut() {
+            value.take_children(owned);
+        }
+    }
+}
+
+impl Children for IndexMap<Value, Value> {
+    fn take_children(&mut self, owned: &mut Vec<Owned>) {
+        for (mut key, mut value) in self.drain(..) {
+            key.take_children(owned);
+            value.take_children(owned);
+        }
+    }
+}
+
+impl Children for IndexSet<Value> {
+    fn take_children(&mut self, owned: &mut Vec<Owned>) {
+        for mut element in self.drain(..) {
+            element.take_children(owned);
+        }
+    }
+}
+
+/// Move out the children of the value behind `rc`,
+/// if this is the last reference to it.
+fn take_unique<T: Children>(rc: Rc<T>, owned: &mut Vec<Owned>) {
+    if let Ok(mut value) = Rc::try_unwrap(rc) {
+        value.take_children(owned);
+    }
+}
+
+/// Drop values one at a time, along with everything they hold
+/// the last reference to.
+pub fn drop_all(mut owned: Vec<Owned>) {
+    while let Some(next) = owned.pop() {
+        match next {
+            Owned::Value(Value::Pair(pair)) => take_unique(pair, &mut owned),
+            Owned::Value(Value::Function(function)) => take_unique(function, &mut owned),
+            Owned::Value(Value::Box(cell)) => take_unique(cell, &mut owned),
+            Owned::Value(Value::Vector(vector)) => take_unique(vector, &mut owned),
+            Owned::Value(Value::Map(map)) => take_unique(map, &mut owned),
+            Owned::Value(Value::Set(set)) => take_unique(set, &mut owned),
+            Owned::Value(_) => (),
+            Owned::Environment(env) => take_unique(env, &mut owned)
+        }
+    }
+}
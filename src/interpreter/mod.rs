@@ -1,27 +1,198 @@
+mod compile;
+mod control;
+mod environment;
+mod expand;
+mod heap;
+mod natives;
+mod quote;
+mod resolve;
+mod syntax_rules;
 #[cfg(test)]
 mod tests;
+mod value;
+mod vm;
 
-use std::{collections::HashMap, ops::Range};
+use num_bigint::BigInt;
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    fmt,
+    ops::Range,
+    rc::Rc
+};
 use thiserror::Error;
 
+pub use self::{
+    natives::NativeContext,
+    value::{Pair, Value}
+};
+
+use self::{
+    compile::{Chunk, FunctionTemplate},
+    environment::{Environment, ScopeKind},
+    expand::Macro,
+    heap::{Heap, HeapStats}
+};
 use crate::{
-    errors::DiagnosticsContext,
-    parser::{Expr, ExprKind}
+    errors::{DiagnosticBuilder, DiagnosticsContext},
+    parser::{Expr, ExprKind, Keyword},
+    symbol::Symbol
 };
 
-type Expressions = std::vec::IntoIter<Expr>;
-type Bindings = std::vec::IntoIter<Binding>;
-type InterpResult = Result<Binding, InterpreterError>;
-
-/// The value of a binding.
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum Binding {
-    /// An expression (ex. "(add 2 2)")
-    Expression(Expr),
-    /// A function defined in Nightbug
-    Function(usize /* num_arguments */, Expr),
-    /// A function defined in Rust
-    NativeFunction(Option<usize>, fn(Bindings) -> InterpResult)
+/// An iterator over a list of expressions, which clones each one as it's taken.
+/// Lists are shared, so this costs as much as the list's length
+/// rather than the size of everything in it.
+struct Expressions {
+    list: Rc<[Expr]>,
+    remaining: Range<usize>
+}
+
+impl Expressions {
+    fn new(list: Rc<[Expr]>) -> Self {
+        let remaining = 0..list.len();
+        Expressions { list, remaining }
+    }
+}
+
+impl From<Vec<Expr>> for Expressions {
+    fn from(list: Vec<Expr>) -> Self {
+        Expressions::new(list.into())
+    }
+}
+
+impl Iterator for Expressions {
+    type Item = Expr;
+
+    fn next(&mut self) -> Option<Expr> {
+        self.remaining.next().map(|idx| self.list[idx].clone())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.remaining.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for Expressions {
+    fn next_back(&mut self) -> Option<Expr> {
+        self.remaining.next_back().map(|idx| self.list[idx].clone())
+    }
+}
+
+impl ExactSizeIterator for Expressions {}
+
+/// The arguments passed to a native function.
+pub type Arguments = std::vec::IntoIter<Value>;
+type InterpResult = Result<Value, InterpreterError>;
+type NativeClosure = dyn Fn(&mut NativeContext<'_, '_>, Arguments) -> InterpResult;
+type StepResult = Result<Step, InterpreterError>;
+
+/// How much Rust stack space must be left before evaluating an expression
+/// for evaluation to carry on without allocating more.
+const STACK_RED_ZONE: usize = 128 * 1024;
+/// How much Rust stack space to allocate once it runs low.
+const STACK_GROWTH: usize = 1024 * 1024;
+
+/// The result of evaluating part of an expression.
+/// An expression in tail position is handed back instead of being evaluated,
+/// so that `Interpreter::interpret_expr` can evaluate it in a loop rather
+/// than recursing. This keeps tail calls from growing the Rust stack.
+enum Step {
+    /// The expression has been fully evaluated
+    Value(Value),
+    /// The value of the expression is the value of another expression,
+    /// to be evaluated in the current scope
+    Eval(Expr)
+}
+
+/// A function defined in Rust, which may capture state from the program
+/// embedding the interpreter.
+pub struct NativeFunction {
+    name: Symbol,
+    /// `None` if the function is variadic
+    num_arguments: Option<usize>,
+    func: Box<NativeClosure>
+}
+
+// The function itself can't be printed
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("num_arguments", &self.num_arguments)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A function defined in Nightbug,
+/// along with the scope it was created in.
+#[derive(Debug)]
+pub struct Function {
+    name: Option<Symbol>,
+    params: Rc<[Symbol]>,
+    /// The body of the function, with its parameters already resolved
+    /// by the `resolve` module
+    body: Rc<[Expr]>,
+    /// The compiled body, for the bytecode backend.
+    /// Functions created by the tree-walking interpreter compile it
+    /// the first time the VM calls them.
+    chunk: OnceCell<Rc<Chunk>>,
+    env: Rc<Environment>
+}
+
+impl Function {
+    fn new(
+        name: Option<Symbol>,
+        params: Rc<[Symbol]>,
+        body: Rc<[Expr]>,
+        env: Rc<Environment>
+    ) -> Rc<Self> {
+        Self::alloc(Self {
+            name,
+            params,
+            body,
+            chunk: OnceCell::new(),
+            env
+        })
+    }
+
+    /// Create a function which has already been compiled.
+    fn compiled(template: &FunctionTemplate, env: Rc<Environment>) -> Rc<Self> {
+        Self::alloc(Self {
+            name: template.name,
+            params: Rc::clone(&template.params),
+            body: Rc::clone(&template.body),
+            chunk: Rc::clone(&template.chunk).into(),
+            env
+        })
+    }
+
+    fn alloc(self) -> Rc<Self> {
+        let res = Rc::new(self);
+        heap::register_function(&res);
+        res
+    }
+}
+
+// Dropping a function on its own only drops its scope, which drops
+// everything else iteratively, so it doesn't need a `Drop` impl
+impl heap::Children for Function {
+    fn take_children(&mut self, owned: &mut Vec<heap::Owned>) {
+        // The scope can't be moved out, so if this is the last reference
+        // to it, a second one is left to drop after this function is gone
+        if Rc::strong_count(&self.env) == 1 {
+            owned.push(heap::Owned::Environment(Rc::clone(&self.env)));
+        }
+    }
+}
+
+/// Which way an `Interpreter` runs programs.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Backend {
+    /// Evaluate expressions directly
+    #[default]
+    TreeWalker,
+    /// Compile programs to bytecode and run them on a virtual machine
+    Bytecode
 }
 
 #[derive(Debug, Error)]
@@ -34,28 +205,180 @@ pub enum InterpreterError {
         expected: usize,
         got: usize
     },
+    /// Also has the index of the argument, if it was passed directly
     #[error("Invalid argument provided to function {0}: {1:?}")]
-    InvalidArgument(String, Binding)
+    InvalidArgument(String, Value, Option<usize>),
+    #[error("Malformed {0} expression")]
+    MalformedExpression(String),
+    #[error("Unexpected keyword {0:?}")]
+    UnexpectedKeyword(Keyword),
+    #[error("Tried to call a value that is not a function: {0:?}")]
+    NotAFunction(Value),
+    #[error("Condition for {0} is not a boolean: {1:?}")]
+    NotABoolean(String, Value),
+    #[error("Not enough arguments for {ident} (expected at least {min}, got {got})")]
+    NotEnoughArgs {
+        ident: String,
+        min: usize,
+        got: usize
+    },
+    /// A length or count was too large to fit in a machine integer
+    #[error("Integer overflow in {ident} ({quantity} too large)")]
+    IntegerOverflow {
+        ident: String,
+        quantity: &'static str,
+        /// The largest value the quantity could have had
+        max: i64
+    },
+    #[error("Division by zero in {0}")]
+    DivisionByZero(String),
+    #[error("Index {index} out of range for {ident} (length {len})")]
+    IndexOutOfRange {
+        ident: String,
+        index: BigInt,
+        len: usize
+    },
+    #[error("Could not parse {0:?} as an integer")]
+    CouldntParseInt(String),
+    #[error("Macro {0} expanded to something which isn't code: {1:?}")]
+    InvalidExpansion(String, Value),
+    #[error("No rule of macro {0} matches the call")]
+    NoMatchingRule(String),
+    #[error("Stack overflow (more than {0} nested calls)")]
+    StackOverflow(usize)
+}
+
+/// A call to a Nightbug function which hasn't returned yet.
+#[derive(Clone, Debug)]
+pub struct CallFrame {
+    /// The name of the function, if it has one
+    pub function: Option<Symbol>,
+    /// Where the function was called
+    pub span: Range<usize>
+}
+
+/// An error from running a program, along with the calls
+/// that were being made when it happened.
+#[derive(Debug, Error)]
+#[error("{error}")]
+pub struct RuntimeError {
+    pub error: Box<InterpreterError>,
+    /// The calls to Nightbug functions that led to the error, innermost first
+    pub backtrace: Vec<CallFrame>
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.function {
+            Some(name) => write!(f, "`{}`", name),
+            None => write!(f, "an anonymous function")
+        }
+    }
 }
 
 pub struct Interpreter<'src> {
-    bindings: HashMap<String, Binding>,
+    /// The scope expressions are currently being evaluated in.
+    env: Rc<Environment>,
+    /// Every value created while this interpreter is running,
+    /// for freeing cycles which reference counting can't.
+    heap: Rc<RefCell<Heap>>,
+    /// Macros defined with `defmacro` or `define-syntax`,
+    /// which are expanded before each top-level expression is resolved.
+    macros: HashMap<Symbol, Macro>,
+    /// Calls to Nightbug functions which haven't returned yet, innermost last.
+    /// A tail call replaces the frame of the call it was made from.
+    call_stack: Vec<CallFrame>,
+    /// How many calls `call_stack` can hold before evaluation
+    /// stops with `InterpreterError::StackOverflow`.
+    /// This also limits how deeply macro calls can expand to other ones.
+    recursion_limit: usize,
+    /// How many macro calls are currently being expanded inside each other
+    expansion_depth: usize,
+    /// The local variables in scope where code is being expanded,
+    /// which hide any macros with the same names
+    local_names: Vec<Symbol>,
+    backend: Backend,
+    /// The call stack when the current error happened, innermost first.
+    /// Only the innermost place an error passes through sets this.
+    error_backtrace: Option<Vec<CallFrame>>,
     error_ctx: DiagnosticsContext<'src>
 }
 
+// Once the interpreter is gone, none of its values are roots any more,
+// so any cycles left in its heap are freed
+impl Drop for Interpreter<'_> {
+    fn drop(&mut self) {
+        let _active = heap::enter(&self.heap);
+        self.macros.clear();
+        self.env = Environment::global();
+        heap::collect(&self.heap, &heap::Roots::default());
+    }
+}
+
 impl<'src> Interpreter<'src> {
+    /// The default value for `Interpreter::set_recursion_limit`.
+    pub const DEFAULT_RECURSION_LIMIT: usize = 10_000;
+
     pub fn new() -> Self {
-        let mut bindings = HashMap::new();
-        bindings.insert("add".to_string(), Binding::NativeFunction(None, add_native));
-        bindings.insert(
-            "second".to_string(),
-            Binding::Function(2, Expr::argument(0..0, 1))
-        );
+        let heap = Rc::new(RefCell::new(Heap::new()));
+        let _active = heap::enter(&heap);
 
-        Self {
-            bindings,
+        let mut res = Self {
+            env: Environment::global(),
+            heap: Rc::clone(&heap),
+            macros: HashMap::new(),
+            call_stack: Vec::new(),
+            recursion_limit: Self::DEFAULT_RECURSION_LIMIT,
+            expansion_depth: 0,
+            local_names: Vec::new(),
+            backend: Backend::default(),
+            error_backtrace: None,
             error_ctx: DiagnosticsContext::new("", None)
+        };
+
+        for &(name, num_arguments, func) in natives::NATIVES {
+            res.register_native(name, num_arguments, func);
         }
+
+        res
+    }
+
+    /// Define a global function implemented in Rust, replacing any
+    /// existing global with the same name. Functions with `None` as their
+    /// number of arguments are variadic.
+    ///
+    /// The function is called with the evaluated arguments, and can
+    /// capture any state it needs from the program embedding the interpreter.
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        num_arguments: Option<usize>,
+        func: impl Fn(&mut NativeContext<'_, '_>, Arguments) -> InterpResult + 'static
+    ) {
+        let name = Symbol::intern(name);
+        let native = NativeFunction {
+            name,
+            num_arguments,
+            func: Box::new(func)
+        };
+
+        self.env
+            .root()
+            .define(name, Value::NativeFunction(Rc::new(native)));
+    }
+
+    /// Set how many nested (non-tail) calls to Nightbug functions
+    /// are allowed before evaluation stops with
+    /// `InterpreterError::StackOverflow`.
+    /// Macro calls can expand to other macro calls as deeply as this too.
+    pub fn set_recursion_limit(&mut self, limit: usize) {
+        self.recursion_limit = limit;
+    }
+
+    /// Choose how programs are run. Definitions made with one backend
+    /// can still be used after switching to the other.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
     }
 
     /// Interpret a given list of expressions.
@@ -64,151 +387,856 @@ impl<'src> Interpreter<'src> {
         &mut self,
         expressions: Vec<Expr>,
         source: &'src str
-    ) -> InterpResult {
+    ) -> Result<Value, RuntimeError> {
         self.error_ctx.set_src(source);
-        self.interpret(expressions.into_iter())
+        self.error_backtrace = None;
+        let _active = heap::enter(&self.heap);
+
+        let res = expressions
+            .into_iter()
+            .try_fold(Value::Unit, |_, expr| self.interpret_top_level(expr));
+
+        res.map_err(|error| RuntimeError {
+            error: Box::new(error),
+            backtrace: self.error_backtrace.take().unwrap_or_default()
+        })
     }
 
-    /// Interpret a given iterator over expressions.
-    /// Returns a binding as the result of the evaluation.
-    fn interpret(&mut self, mut expressions: Expressions) -> InterpResult {
-        let Expr { span, kind } = match expressions.next() {
-            Some(expr) => expr,
-            None => return Ok(Binding::Expression(Expr::unit(0..0)))
+    /// Expand, resolve and run a single top-level expression.
+    /// Each one is run before the next is expanded, so macros can use
+    /// anything defined above them.
+    fn interpret_top_level(&mut self, expr: Expr) -> InterpResult {
+        self.collect_if_needed();
+        let expr = self.expand_top_level(expr)?;
+        let program = resolve::resolve_program(vec![expr]);
+
+        match self.backend {
+            Backend::TreeWalker => self.interpret(program.into()),
+            Backend::Bytecode => self.run_program(&program)
+        }
+    }
+
+    /// Free every value in the heap which the program can no longer use,
+    /// returning how many were freed.
+    fn collect_garbage(&mut self) -> usize {
+        let mut roots = heap::Roots::default();
+        roots.environment(&self.env);
+        roots.environment(self.env.root());
+
+        for mac in self.macros.values() {
+            if let Macro::Procedural { function, .. } = mac {
+                roots.function(function);
+            }
+        }
+
+        heap::collect(&self.heap, &roots)
+    }
+
+    /// Collect garbage if enough values have been created since the
+    /// last collection. Called before each function call.
+    fn collect_if_needed(&mut self) {
+        if self.heap.borrow().wants_collection() {
+            self.collect_garbage();
+        }
+    }
+
+    fn heap_stats(&self) -> HeapStats {
+        self.heap.borrow_mut().stats()
+    }
+
+    /// Interpret a given iterator over expressions in order.
+    /// Returns the value produced by the last expression,
+    /// or unit if there were no expressions.
+    fn interpret(&mut self, expressions: Expressions) -> InterpResult {
+        let mut res = Value::Unit;
+
+        for expr in expressions {
+            res = self.interpret_expr(expr)?;
+        }
+
+        Ok(res)
+    }
+
+    /// Interpret every expression, keeping each of their values.
+    fn interpret_all(&mut self, exprs: Vec<Expr>) -> Result<Vec<Value>, InterpreterError> {
+        exprs
+            .into_iter()
+            .map(|expr| self.interpret_expr(expr))
+            .collect()
+    }
+
+    /// Interpret a single expression.
+    fn interpret_expr(&mut self, expr: Expr) -> InterpResult {
+        // Nested calls recurse on the Rust stack, so more of it is allocated
+        // as needed. This way only the recursion limit decides how deep
+        // calls can go, rather than the size of the current thread's stack.
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || {
+            self.evaluate(|this| this.step(expr))
+        })
+    }
+
+    /// Finish evaluating the result of `first`, evaluating tail calls in a
+    /// loop.
+    fn evaluate(&mut self, first: impl FnOnce(&mut Self) -> StepResult) -> InterpResult {
+        let prev_env = Rc::clone(&self.env);
+        let prev_depth = self.call_stack.len();
+        let mut step = first(self);
+
+        let res = loop {
+            match step {
+                Ok(Step::Value(res)) => break Ok(res),
+
+                Ok(Step::Eval(next)) => {
+                    // Only keep the frame of the latest tail call
+                    let depth = self.call_stack.len();
+                    if depth > prev_depth + 1 {
+                        self.call_stack.drain(prev_depth..depth - 1);
+                    }
+
+                    step = self.step(next);
+                },
+
+                Err(err) => {
+                    if self.error_backtrace.is_none() {
+                        self.error_backtrace =
+                            Some(self.call_stack.iter().rev().cloned().collect());
+                    }
+
+                    break Err(err);
+                }
+            }
         };
 
+        // Tail calls switch to the scope of the function being called
+        self.env = prev_env;
+        self.call_stack.truncate(prev_depth);
+        res
+    }
+
+    /// Evaluate an expression, stopping at the expression in tail position
+    /// if there is one.
+    fn step(&mut self, expr: Expr) -> StepResult {
+        let Expr { span, kind } = expr;
+
         match kind {
-            ExprKind::Integer(_) | ExprKind::Boolean(_) | ExprKind::Unit => {
-                Ok(Binding::Expression(Expr::new(span, kind)))
+            ExprKind::Integer(_)
+            | ExprKind::BigInteger(_)
+            | ExprKind::Rational(_)
+            | ExprKind::Float(_)
+            | ExprKind::String(_)
+            | ExprKind::Boolean(_)
+            | ExprKind::Unit => Ok(Step::Value(quote::quote_expr(Expr::new(span, kind)))),
+
+            ExprKind::List(inner_expressions) => self.interpret_list(span, inner_expressions),
+
+            ExprKind::Map(contents) => {
+                let items = self.interpret_all(contents.to_vec())?;
+                Ok(Step::Value(Value::map_from_items(items)))
+            },
+
+            ExprKind::Set(contents) => {
+                let items = self.interpret_all(contents.to_vec())?;
+                Ok(Step::Value(Value::set(items.into_iter().collect())))
             },
 
-            ExprKind::List(inner_expressions) => self.interpret(inner_expressions.into_iter()),
-            ExprKind::Identifier(ident) => self.handle_identifier(&ident, span, expressions),
-            ExprKind::Keyword(_) => todo!(),
-            ExprKind::Argument(_) => unreachable!()
+            ExprKind::Identifier(ident) => self.handle_identifier(ident, span).map(Step::Value),
+
+            ExprKind::Keyword(keyword) => {
+                self.build_error("expected an expression, found a keyword")
+                    .span_label(span, "keywords can only appear at the start of a list")
+                    .emit();
+                Err(InterpreterError::UnexpectedKeyword(keyword))
+            },
+
+            ExprKind::Argument(idx) => self.handle_argument(idx, span).map(Step::Value)
         }
     }
 
-    /// Try and resolve a binding, making a function call if necessary.
-    fn handle_identifier(
-        &mut self,
-        ident: &str,
-        span: Range<usize>,
-        expressions: Expressions
-    ) -> InterpResult {
-        let binding = match self.bindings.get(ident) {
-            Some(res) => res.clone(),
-            None => {
-                self.error_ctx
-                    .build_error(&format!("unknown identifier `{}`", ident))
-                    .span_label(span.clone(), "not found in this scope")
+    /// Interpret an S-expression, either by handling a keyword
+    /// or by calling a function.
+    fn interpret_list(&mut self, span: Range<usize>, contents: Rc<[Expr]>) -> StepResult {
+        let mut expressions = Expressions::new(contents);
+        // The parser turns `()` into `ExprKind::Unit`, so lists are never empty
+        let head = expressions.next().unwrap();
+
+        let res = match head.kind {
+            ExprKind::Keyword(Keyword::Define) => self.handle_define(span, expressions),
+            ExprKind::Keyword(Keyword::Set) => self.handle_set(span, expressions),
+            ExprKind::Keyword(Keyword::Fn) => self.handle_fn(span, expressions),
+
+            ExprKind::Keyword(keyword @ (Keyword::Let | Keyword::LetStar | Keyword::LetRec)) => {
+                return self.handle_let(keyword, span, expressions);
+            },
+
+            ExprKind::Keyword(Keyword::If) => return self.handle_if(span, expressions),
+            ExprKind::Keyword(Keyword::Cond) => return self.handle_cond(expressions),
+
+            ExprKind::Keyword(keyword @ (Keyword::When | Keyword::Unless)) => {
+                return self.handle_when(keyword, span, expressions);
+            },
+
+            ExprKind::Keyword(keyword @ (Keyword::And | Keyword::Or)) => {
+                self.handle_and_or(keyword, expressions)
+            },
+
+            ExprKind::Keyword(Keyword::Quote) => self.handle_quote(span, expressions),
+            ExprKind::Keyword(Keyword::Quasiquote) => self.handle_quasiquote(span, expressions),
+            ExprKind::Keyword(Keyword::MacroExpand) => self.handle_macroexpand(span, expressions),
+
+            // Expansion removes every valid macro definition
+            ExprKind::Keyword(keyword @ (Keyword::DefMacro | Keyword::DefineSyntax)) => Err(self
+                .malformed_expression(
+                    keyword.as_str(),
+                    span,
+                    "macros can only be defined at the top level"
+                )),
+
+            ExprKind::Keyword(keyword @ (Keyword::Unquote | Keyword::UnquoteSplicing)) => {
+                self.build_error(&format!(
+                    "`{}` can only be used inside `quasiquote`",
+                    keyword.as_str()
+                ))
+                .with_span(span)
+                .emit();
+                Err(InterpreterError::UnexpectedKeyword(keyword))
+            },
+
+            ExprKind::Keyword(Keyword::Else) => {
+                self.build_error("`else` can only be used in `cond` expressions")
+                    .with_span(head.span)
                     .emit();
+                Err(InterpreterError::UnexpectedKeyword(Keyword::Else))
+            },
 
-                return Err(InterpreterError::UnknownIdentifier(ident.to_string()));
+            _ => {
+                let head_span = head.span.clone();
+                let ident = match head.kind {
+                    ExprKind::Identifier(ident) => ident.as_str(),
+                    _ => "<anonymous>"
+                };
+                let func = self.interpret_expr(head)?;
+
+                match func {
+                    Value::Function(_) | Value::NativeFunction(..) => {
+                        return self.handle_function(&func, ident, span, head_span, expressions);
+                    },
+
+                    // `(x)` is the same as `x`
+                    _ if expressions.len() == 0 => Ok(func),
+
+                    _ => {
+                        self.build_error("tried to call a value that is not a function")
+                            .span_label(head_span, "this is not a function")
+                            .emit();
+                        Err(InterpreterError::NotAFunction(func))
+                    }
+                }
             }
         };
 
+        res.map(Step::Value)
+    }
+
+    /// Evaluate a body made of several expressions, handing back
+    /// the last one since it is in tail position.
+    fn tail_body(&mut self, mut expressions: Expressions) -> StepResult {
+        let last = match expressions.next_back() {
+            Some(last) => last,
+            None => return Ok(Step::Value(Value::Unit))
+        };
+
+        for expr in expressions {
+            self.interpret_expr(expr)?;
+        }
+
+        Ok(Step::Eval(last))
+    }
+
+    /// Evaluate `f` with `env` as the current scope,
+    /// restoring the previous scope afterwards.
+    fn with_env<T>(&mut self, env: Rc<Environment>, f: impl FnOnce(&mut Self) -> T) -> T {
+        let prev_env = std::mem::replace(&mut self.env, env);
+        let res = f(self);
+        self.env = prev_env;
+        res
+    }
+
+    /// Try and resolve a binding.
+    /// Identifiers renamed by a hygienic macro which aren't bound by the
+    /// expansion they came from refer to the global binding of their
+    /// original name, since macros are always defined at the top level.
+    fn handle_identifier(&mut self, ident: Symbol, span: Range<usize>) -> InterpResult {
+        let binding = self
+            .env
+            .get(ident)
+            .or_else(|| self.env.root().get(ident.original()?));
+
         match binding {
-            Binding::Expression(expr) => return Ok(Binding::Expression(expr.clone())),
+            Some(res) => Ok(res),
+            None => {
+                self.build_error(&format!("unknown identifier `{}`", ident))
+                    .span_label(span, "not found in this scope")
+                    .note(&format!(
+                        "searched {}",
+                        self.env.describe_chain().join(", then ")
+                    ))
+                    .emit();
+
+                Err(InterpreterError::UnknownIdentifier(ident.to_string()))
+            }
+        }
+    }
+
+    /// Find an argument of the innermost function call,
+    /// which the `resolve` module replaced a parameter with.
+    fn handle_argument(&mut self, idx: usize, span: Range<usize>) -> InterpResult {
+        match self.env.argument(idx) {
+            Some(res) => Ok(res),
+            None => {
+                self.error_ctx
+                    .build_ice_span(span, "found an argument placeholder outside of a function")
+                    .emit();
+                Err(InterpreterError::UnknownIdentifier(
+                    "<argument>".to_string()
+                ))
+            }
+        }
+    }
+
+    /// Change the value of an existing binding, like `handle_identifier`
+    /// finds it.
+    fn set_identifier(
+        &mut self,
+        ident: Symbol,
+        value: Value,
+        span: Range<usize>
+    ) -> Result<(), InterpreterError> {
+        let found = self.env.set(ident, value.clone())
+            || ident
+                .original()
+                .is_some_and(|original| self.env.root().set(original, value));
+
+        if found {
+            return Ok(());
+        }
 
-            Binding::Function(..) | Binding::NativeFunction(..) => {
-                if expressions.len() != 0 {
-                    return self.handle_function(&binding, &ident, span, expressions);
-                } else {
-                    return Ok(binding.clone());
+        self.build_error(&format!("cannot set undefined identifier `{}`", ident))
+            .span_label(span, "not found in this scope")
+            .note(&format!(
+                "searched {}",
+                self.env.describe_chain().join(", then ")
+            ))
+            .note("use `define` to create a new binding")
+            .emit();
+
+        Err(InterpreterError::UnknownIdentifier(ident.to_string()))
+    }
+
+    /// Change an argument of the innermost function call.
+    fn set_argument(
+        &mut self,
+        idx: usize,
+        value: Value,
+        span: Range<usize>
+    ) -> Result<(), InterpreterError> {
+        if self.env.set_argument(idx, value) {
+            return Ok(());
+        }
+
+        self.error_ctx
+            .build_ice_span(span, "found an argument placeholder outside of a function")
+            .emit();
+        Err(InterpreterError::UnknownIdentifier(
+            "<argument>".to_string()
+        ))
+    }
+
+    /// Emit an error for a malformed keyword expression,
+    /// returning the corresponding `InterpreterError`.
+    fn malformed_expression(
+        &self,
+        keyword: &str,
+        span: Range<usize>,
+        label: &str
+    ) -> InterpreterError {
+        self.build_error(&format!("malformed `{}` expression", keyword))
+            .span_label(span, label)
+            .emit();
+
+        InterpreterError::MalformedExpression(keyword.to_string())
+    }
+
+    /// Handle a `define` expression, creating a binding in the current scope.
+    /// `(define name expr)` binds the value of `expr` to `name`, while
+    /// `(define (name args...) body...)` creates a function.
+    fn handle_define(&mut self, span: Range<usize>, mut expressions: Expressions) -> InterpResult {
+        let target = match expressions.next() {
+            Some(target) => target,
+            None => {
+                return Err(self.malformed_expression(
+                    "define",
+                    span,
+                    "expected a name or function signature"
+                ))
+            },
+        };
+
+        match target.kind {
+            ExprKind::Identifier(ident) => {
+                if expressions.len() != 1 {
+                    return Err(self.malformed_expression(
+                        "define",
+                        span,
+                        "expected exactly one value after the name"
+                    ));
                 }
+
+                // nb. the unwrap is safe because of the length check above
+                let value = self.interpret_expr(expressions.next().unwrap())?;
+                self.env.define(ident, value);
+            },
+
+            ExprKind::List(signature) => {
+                let mut signature = signature.iter().cloned();
+                // As above, lists are never empty
+                let name_expr = signature.next().unwrap();
+                let name = match name_expr.kind {
+                    ExprKind::Identifier(name) => name,
+                    _ => {
+                        return Err(self.malformed_expression(
+                            "define",
+                            name_expr.span,
+                            "expected a function name"
+                        ))
+                    },
+                };
+
+                let params = self.parameter_names("define", signature.collect())?;
+
+                if expressions.len() == 0 {
+                    return Err(self.malformed_expression(
+                        "define",
+                        span,
+                        "expected a function body"
+                    ));
+                }
+
+                // Since the function captures the current scope,
+                // it will be able to refer to itself
+                let function = Value::Function(Function::new(
+                    Some(name),
+                    params.into(),
+                    expressions.collect(),
+                    Rc::clone(&self.env)
+                ));
+                self.env.define(name, function);
+            },
+
+            _ => {
+                return Err(self.malformed_expression(
+                    "define",
+                    target.span,
+                    "expected a name or function signature"
+                ))
             },
         }
+
+        Ok(Value::Unit)
     }
 
-    /// Try and execute a function
-    fn handle_function(
+    /// Handle a `set!` expression.
+    /// `(set! name expr)` changes the value of an existing binding
+    /// to the value of `expr`. The binding can be in any enclosing scope.
+    fn handle_set(&mut self, span: Range<usize>, mut expressions: Expressions) -> InterpResult {
+        if expressions.len() != 2 {
+            return Err(self.malformed_expression("set!", span, "expected a name and a value"));
+        }
+
+        // nb. the unwraps are safe because of the length check above
+        let target = expressions.next().unwrap();
+        let value_expr = expressions.next().unwrap();
+
+        if !matches!(target.kind, ExprKind::Identifier(_) | ExprKind::Argument(_)) {
+            return Err(self.malformed_expression("set!", target.span, "expected a name"));
+        }
+
+        let value = self.interpret_expr(value_expr)?;
+
+        match target.kind {
+            ExprKind::Identifier(ident) => self.set_identifier(ident, value, target.span)?,
+            ExprKind::Argument(idx) => self.set_argument(idx, value, target.span)?,
+            _ => unreachable!()
+        }
+
+        Ok(Value::Unit)
+    }
+
+    /// Check that every expression in a parameter list is an identifier,
+    /// returning their names.
+    fn parameter_names(
+        &self,
+        keyword: &str,
+        params: Vec<Expr>
+    ) -> Result<Vec<Symbol>, InterpreterError> {
+        let mut res = Vec::with_capacity(params.len());
+
+        for param in params {
+            match param.kind {
+                ExprKind::Identifier(param) => res.push(param),
+                _ => {
+                    return Err(self.malformed_expression(
+                        keyword,
+                        param.span,
+                        "expected a parameter name"
+                    ))
+                },
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Handle a `fn` expression, creating a function.
+    /// `(fn (args...) body...)` captures the scope it is evaluated in.
+    fn handle_fn(&mut self, span: Range<usize>, mut expressions: Expressions) -> InterpResult {
+        let params_expr = match expressions.next() {
+            Some(params_expr) => params_expr,
+            None => return Err(self.malformed_expression("fn", span, "expected a parameter list"))
+        };
+
+        let params = match params_expr.kind {
+            ExprKind::Unit => Vec::new(),
+            ExprKind::List(params) => self.parameter_names("fn", params.to_vec())?,
+            _ => {
+                return Err(self.malformed_expression(
+                    "fn",
+                    params_expr.span,
+                    "expected a parameter list"
+                ))
+            },
+        };
+
+        if expressions.len() == 0 {
+            return Err(self.malformed_expression("fn", span, "expected a function body"));
+        }
+
+        Ok(Value::Function(Function::new(
+            None,
+            params.into(),
+            expressions.collect(),
+            Rc::clone(&self.env)
+        )))
+    }
+
+    /// Check that a `let` binding list has the form `((name expr)...)`,
+    /// returning the names and expressions.
+    fn let_bindings(
+        &self,
+        keyword: &str,
+        bindings_expr: Expr
+    ) -> Result<Vec<(Symbol, Expr)>, InterpreterError> {
+        let bindings = match bindings_expr.kind {
+            ExprKind::Unit => return Ok(Vec::new()),
+            ExprKind::List(bindings) => bindings.to_vec(),
+            _ => {
+                return Err(self.malformed_expression(
+                    keyword,
+                    bindings_expr.span,
+                    "expected a list of bindings"
+                ))
+            },
+        };
+
+        let mut res = Vec::with_capacity(bindings.len());
+
+        for binding in bindings {
+            let binding_span = binding.span.clone();
+
+            match binding.kind {
+                ExprKind::List(pair) if pair.len() == 2 => {
+                    let mut pair = pair.iter().cloned();
+                    // nb. the unwraps are safe because of the length check above
+                    let name = pair.next().unwrap();
+                    let value = pair.next().unwrap();
+
+                    match name.kind {
+                        ExprKind::Identifier(name) => res.push((name, value)),
+                        _ => {
+                            return Err(self.malformed_expression(
+                                keyword,
+                                name.span,
+                                "expected a name to bind"
+                            ))
+                        },
+                    }
+                },
+
+                _ => {
+                    return Err(self.malformed_expression(
+                        keyword,
+                        binding_span,
+                        "expected a binding of the form `(name value)`"
+                    ))
+                },
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Handle a `let`, `let*`, or `letrec` expression,
+    /// evaluating the body in a new scope with the given bindings.
+    /// `let` evaluates every value in the enclosing scope,
+    /// `let*` lets each value see the bindings before it,
+    /// and `letrec` lets every value see every binding.
+    fn handle_let(
         &mut self,
-        func: &Binding,
+        keyword: Keyword,
+        span: Range<usize>,
+        mut expressions: Expressions
+    ) -> StepResult {
+        let keyword_str = keyword.as_str();
+        let bindings_expr = match expressions.next() {
+            Some(bindings_expr) => bindings_expr,
+            None => {
+                return Err(self.malformed_expression(
+                    keyword_str,
+                    span,
+                    "expected a list of bindings"
+                ))
+            },
+        };
+
+        let bindings = self.let_bindings(keyword_str, bindings_expr)?;
+
+        if expressions.len() == 0 {
+            return Err(self.malformed_expression(keyword_str, span, "expected a body"));
+        }
+
+        let env = match keyword {
+            Keyword::Let => {
+                let env = Environment::child(&self.env, ScopeKind::Let(keyword_str));
+
+                for (name, expr) in bindings {
+                    let value = self.interpret_expr(expr)?;
+                    env.define(name, value);
+                }
+
+                env
+            },
+
+            Keyword::LetStar => {
+                let mut env = Rc::clone(&self.env);
+
+                for (name, expr) in bindings {
+                    let value = self.with_env(Rc::clone(&env), |this| this.interpret_expr(expr))?;
+                    env = Environment::child(&env, ScopeKind::Let(keyword_str));
+                    env.define(name, value);
+                }
+
+                // Give the body its own scope, even if there were no bindings
+                Environment::child(&env, ScopeKind::Let(keyword_str))
+            },
+
+            Keyword::LetRec => {
+                let env = Environment::child(&self.env, ScopeKind::Let(keyword_str));
+
+                for (name, expr) in bindings {
+                    let value = self.with_env(Rc::clone(&env), |this| this.interpret_expr(expr))?;
+                    env.define(name, value);
+                }
+
+                env
+            },
+
+            _ => unreachable!()
+        };
+
+        // `interpret_expr` restores the previous scope once the body is done
+        self.env = env;
+        self.tail_body(expressions)
+    }
+
+    /// Emit an error for a Nightbug function that was called
+    /// with the wrong number of arguments, returning the corresponding
+    /// `InterpreterError`.
+    fn wrong_num_args(
+        &self,
         ident: &str,
         name_span: Range<usize>,
-        expressions: Expressions
-    ) -> InterpResult {
-        // TODO: seems lengthy... can this be trimmed down?
-        assert!(matches!(
-            func,
-            Binding::Function(..) | Binding::NativeFunction(..)
+        expected: usize,
+        arg_spans: &[Range<usize>]
+    ) -> InterpreterError {
+        let mut msg = self.build_error(&format!(
+            "wrong number of arguments for function (expected {}, got {})",
+            expected,
+            arg_spans.len()
         ));
 
-        match func {
-            Binding::Function(num_arguments, body) => {
-                if expressions.len() != *num_arguments {
-                    let num_args_given = expressions.len();
-                    let mut msg = self.error_ctx.build_error(&format!(
-                        "wrong number of arguments for function (expected {}, got {})",
-                        num_arguments, num_args_given
-                    ));
+        if let (Some(first), Some(last)) = (arg_spans.first(), arg_spans.last()) {
+            // Construct a span across the argument list
+            let span = first.start..last.end;
+            msg = msg.span_label(span, &format!("got {} arguments", arg_spans.len()));
+        }
 
-                    if num_args_given != 0 {
-                        let args: Vec<Expr> = expressions.collect();
-                        // Construct a span across the argument list
-                        // These unwraps should be safe since the list is nonempty
-                        let span = args.first().unwrap().span.start..args.last().unwrap().span.end;
-                        msg = msg.span_label(span, &format!("got {} arguments", num_args_given));
-                    }
+        msg.span_label(name_span, &format!("expected {} arguments", expected))
+            .emit();
 
-                    msg.span_label(name_span, &format!("expected {} arguments", num_arguments))
-                        .emit();
+        InterpreterError::WrongNumArgs {
+            ident: ident.to_string(),
+            expected,
+            got: arg_spans.len()
+        }
+    }
 
-                    return Err(InterpreterError::WrongNumArgs {
-                        ident: ident.to_string(),
-                        expected: *num_arguments,
-                        got: num_args_given
-                    });
-                }
+    /// Start building an error, which will show the current call stack.
+    fn build_error(&self, message: &str) -> DiagnosticBuilder<'_, '_> {
+        let frames = self
+            .call_stack
+            .iter()
+            .rev()
+            .map(|frame| (frame.span.clone(), frame.to_string()))
+            .collect();
+
+        self.error_ctx.build_error(message).backtrace(frames)
+    }
+
+    /// Emit an error for a call which would go past the recursion limit,
+    /// returning the corresponding `InterpreterError`.
+    fn stack_overflow(&self, call_span: Range<usize>) -> InterpreterError {
+        self.build_error("stack overflow")
+            .span_label(
+                call_span,
+                &format!(
+                    "this call went past the limit of {} nested calls",
+                    self.recursion_limit
+                )
+            )
+            .help("if the recursion is intentional, try making the recursive call a tail call")
+            .emit();
+
+        InterpreterError::StackOverflow(self.recursion_limit)
+    }
 
-                let mut body = body.clone();
-                let args: Vec<Expr> = expressions.collect();
+    /// Emit a diagnostic for an error returned by a native function,
+    /// given where each of its arguments was written.
+    fn emit_native_error(
+        &self,
+        err: &InterpreterError,
+        call_span: Range<usize>,
+        arg_spans: &[Range<usize>]
+    ) {
+        let diagnostic = match err {
+            InterpreterError::InvalidArgument(ident, binding, idx) => {
+                let msg = self.build_error(&format!("invalid argument to `{}`", ident));
 
-                if let ExprKind::Argument(num) = body.kind {
-                    body = args[num].clone();
+                match idx.and_then(|idx| arg_spans.get(idx)) {
+                    Some(arg_span) => msg.span_label(
+                        arg_span.clone(),
+                        &format!("found {} here", binding.type_name())
+                    ),
+                    None => msg.span_label(
+                        call_span,
+                        &format!("found {} in this call", binding.type_name())
+                    )
                 }
+            },
 
-                match body.kind {
-                    ExprKind::List(contents) => {
-                        let contents: Vec<Expr> = contents
-                            .into_iter()
-                            .map(|expr| match expr.kind {
-                                ExprKind::Argument(num) => args[num].clone(),
-                                _ => expr
-                            })
-                            // Collection needed to avoid recursion limit
-                            .collect();
-                        self.interpret(contents.into_iter())
-                    },
+            InterpreterError::NotABoolean(ident, binding) => self
+                .build_error(&format!("`{}` expects a boolean", ident))
+                .span_label(
+                    call_span,
+                    &format!("expected a boolean, found {}", binding.type_name())
+                ),
 
-                    ExprKind::Identifier(_) => {
-                        self.handle_identifier(ident, body.span, Vec::new().into_iter())
-                    },
+            InterpreterError::NotEnoughArgs { ident, min, got } => self
+                .build_error(&format!(
+                    "not enough arguments for `{}` (expected at least {}, got {})",
+                    ident, min, got
+                ))
+                .with_span(call_span),
 
-                    ExprKind::Integer(_) | ExprKind::Boolean(_) | ExprKind::Unit => {
-                        Ok(Binding::Expression(body))
-                    },
+            InterpreterError::IntegerOverflow {
+                ident,
+                quantity,
+                max
+            } => self
+                .build_error(&format!("integer overflow in `{}`", ident))
+                .span_label(call_span, &format!("this {} is too large", quantity))
+                .note(&format!(
+                    "a {} must fit in a machine integer, which is at most {}",
+                    quantity, max
+                )),
+
+            InterpreterError::DivisionByZero(ident) => self
+                .build_error(&format!("division by zero in `{}`", ident))
+                .span_label(call_span, "attempted to divide by zero"),
+
+            InterpreterError::IndexOutOfRange { ident, index, len } => self
+                .build_error(&format!("index out of range in `{}`", ident))
+                .span_label(
+                    call_span,
+                    &format!("index {} is out of range for length {}", index, len)
+                ),
+
+            InterpreterError::CouldntParseInt(s) => self
+                .build_error(&format!("could not parse {:?} as an integer", s))
+                .with_span(call_span),
+
+            err => self.build_error(&err.to_string()).with_span(call_span)
+        };
+
+        diagnostic.emit();
+    }
+
+    /// Try and execute a function
+    fn handle_function(
+        &mut self,
+        func: &Value,
+        ident: &str,
+        call_span: Range<usize>,
+        name_span: Range<usize>,
+        expressions: Expressions
+    ) -> StepResult {
+        match func {
+            Value::Function(function) => {
+                let arg_exprs: Vec<Expr> = expressions.collect();
+
+                if arg_exprs.len() != function.params.len() {
+                    let arg_spans: Vec<_> =
+                        arg_exprs.iter().map(|expr| expr.span.clone()).collect();
+                    return Err(self.wrong_num_args(
+                        ident,
+                        name_span,
+                        function.params.len(),
+                        &arg_spans
+                    ));
+                }
 
-                    _ => todo!()
+                // Each argument is evaluated exactly once, in the caller's scope
+                let mut args = Vec::with_capacity(arg_exprs.len());
+
+                for expr in arg_exprs {
+                    args.push(self.interpret_expr(expr)?);
                 }
+
+                self.call_function(function, args, call_span)
             },
 
-            Binding::NativeFunction(maybe_num_arguments, func) => {
-                if let Some(num_arguments) = maybe_num_arguments {
+            Value::NativeFunction(native) => {
+                if let Some(num_arguments) = &native.num_arguments {
                     if expressions.len() != *num_arguments {
-                        self.error_ctx
-                            .build_error(&format!(
-                                "wrong number of arguments for function (expected {}, got {})",
-                                num_arguments,
-                                expressions.len()
-                            ))
-                            .span_label(name_span, &format!("expected {} arguments", num_arguments))
-                            .note(&format!(
-                                "cannot show definition for {} because it is a built-in function",
-                                ident
-                            ))
-                            .emit();
+                        self.build_error(&format!(
+                            "wrong number of arguments for function (expected {}, got {})",
+                            num_arguments,
+                            expressions.len()
+                        ))
+                        .span_label(name_span, &format!("expected {} arguments", num_arguments))
+                        .note(&format!(
+                            "cannot show definition for {} because it is a built-in function",
+                            ident
+                        ))
+                        .emit();
 
                         return Err(InterpreterError::WrongNumArgs {
                             ident: ident.to_string(),
@@ -218,52 +1246,135 @@ impl<'src> Interpreter<'src> {
                     }
                 }
 
-                let mut bindings = Vec::with_capacity(expressions.len());
+                let mut args = Vec::with_capacity(expressions.len());
+                let mut arg_spans = Vec::with_capacity(expressions.len());
 
                 for expr in expressions {
-                    let binding = match expr.kind {
-                        ExprKind::List(contents) => self.interpret(contents.into_iter())?,
-                        _ => Binding::Expression(expr)
-                    };
-                    bindings.push(binding);
+                    arg_spans.push(expr.span.clone());
+                    args.push(self.interpret_expr(expr)?);
                 }
 
-                func(bindings.into_iter())
+                self.call_native(native, args, call_span, arg_spans)
+                    .map(Step::Value)
             },
 
             _ => unreachable!()
         }
     }
-}
 
-/// Native variadic function to add numbers
-fn add_native(bindings: Bindings) -> InterpResult {
-    // TODO: HACK: get this from interpreter somehow!
-    let error_ctx = DiagnosticsContext::new("", None);
-    let mut res = 0;
+    /// Call a Nightbug function with evaluated arguments,
+    /// handing back its last expression since it is in tail position.
+    fn call_function(
+        &mut self,
+        function: &Function,
+        args: Vec<Value>,
+        call_span: Range<usize>
+    ) -> StepResult {
+        if self.call_stack.len() >= self.recursion_limit {
+            return Err(self.stack_overflow(call_span));
+        }
+
+        self.collect_if_needed();
 
-    for binding in bindings {
-        match binding {
-            Binding::Expression(Expr {
-                kind: ExprKind::Integer(i),
-                ..
-            }) => {
-                res += i;
-            },
+        self.call_stack.push(CallFrame {
+            function: function.name,
+            span: call_span
+        });
+
+        let env = Environment::call(
+            &function.env,
+            function.name,
+            Rc::clone(&function.params),
+            args
+        );
+        // `interpret_expr` restores the caller's scope once the call is done
+        self.env = env;
+        self.tail_body(Expressions::new(Rc::clone(&function.body)))
+    }
+
+    /// Call a native function with evaluated arguments,
+    /// emitting a diagnostic for any error it returns.
+    fn call_native(
+        &mut self,
+        native: &NativeFunction,
+        args: Vec<Value>,
+        call_span: Range<usize>,
+        arg_spans: Vec<Range<usize>>
+    ) -> InterpResult {
+        let mut ctx = NativeContext::new(self, native.name, call_span.clone(), arg_spans);
+        let res = (native.func)(&mut ctx, args.into_iter());
+
+        if let Err(err) = &res {
+            if !ctx.reported() {
+                let arg_spans = ctx.arg_spans().to_vec();
+                self.emit_native_error(err, call_span, &arg_spans);
+            }
+        }
+
+        res
+    }
+
+    /// Call any function with arguments which have already been evaluated,
+    /// as if it was called at `call_span`. This is how natives call
+    /// back into Nightbug.
+    fn apply(&mut self, func: &Value, args: Vec<Value>, call_span: Range<usize>) -> InterpResult {
+        let expected = match func {
+            Value::Function(function) => Some(function.params.len()),
+            Value::NativeFunction(native) => native.num_arguments,
 
             _ => {
-                error_ctx
-                    .build_error(&format!("unexpected argument to `add`: {:?}", binding))
-                    .note("cannot highlight the argument in your code right now, my apologies")
-                    .note("`add` only expects integers as arguments")
+                self.build_error("tried to call a value that is not a function")
+                    .span_label(call_span, &format!("this called {}", func.type_name()))
                     .emit();
-                return Err(InterpreterError::InvalidArgument(
-                    "add".to_string(),
-                    binding
-                ));
+                return Err(InterpreterError::NotAFunction(func.clone()));
             }
+        };
+
+        if let Some(expected) = expected.filter(|&expected| expected != args.len()) {
+            let ident = match func {
+                Value::Function(function) => function.name.map_or("<anonymous>", Symbol::as_str),
+                Value::NativeFunction(native) => native.name.as_str(),
+                _ => unreachable!()
+            };
+
+            self.build_error(&format!(
+                "wrong number of arguments for function (expected {}, got {})",
+                expected,
+                args.len()
+            ))
+            .span_label(
+                call_span,
+                &format!("this called a function with {} arguments", args.len())
+            )
+            .emit();
+
+            return Err(InterpreterError::WrongNumArgs {
+                ident: ident.to_string(),
+                expected,
+                got: args.len()
+            });
+        }
+
+        match func {
+            Value::Function(function) => match self.backend {
+                Backend::TreeWalker => {
+                    self.evaluate(|this| this.call_function(function, args, call_span))
+                },
+                Backend::Bytecode => self.run_function(function, args, call_span)
+            },
+
+            Value::NativeFunction(native) => {
+                // Arguments from another native weren't written anywhere
+                self.call_native(native, args, call_span, Vec::new())
+            },
+
+            _ => unreachable!()
         }
     }
+}
 
-    Ok(Binding::Expression(Expr::integer(0..0, res)))
+impl<'src> Default for Interpreter<'src> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
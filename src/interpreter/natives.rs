@@ -0,0 +1,1221 @@
+//! Functions built into Nightbug which are implemented in Rust.
+//!
+//! Natives report problems by returning an `InterpreterError`;
+//! the interpreter is responsible for emitting a diagnostic for it,
+//! using the spans of the call and its arguments from the `NativeContext`.
+//! A native can also emit its own diagnostic with `NativeContext::build_error`.
+//! Errors name the native by `NativeContext::name`, since the same function
+//! can be registered under several names.
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Signed, ToPrimitive, Zero};
+use std::{cmp::Ordering, convert::TryFrom, ops::Range, rc::Rc};
+
+use super::{
+    value::{Map, Mutable},
+    Arguments, InterpResult, Interpreter, InterpreterError, Pair, Value
+};
+use crate::{
+    errors::{DiagnosticBuilder, DiagnosticsContext},
+    symbol::Symbol
+};
+
+/// The call a native function was called from.
+pub struct NativeContext<'a, 'src> {
+    interpreter: &'a mut Interpreter<'src>,
+    /// The name the native was registered under
+    name: Symbol,
+    call_span: Range<usize>,
+    /// Where each argument was written, in order
+    arg_spans: Vec<Range<usize>>,
+    /// Whether a diagnostic has already been emitted for an error,
+    /// so that the interpreter doesn't emit another one
+    reported: bool
+}
+
+impl<'a, 'src> NativeContext<'a, 'src> {
+    pub(super) fn new(
+        interpreter: &'a mut Interpreter<'src>,
+        name: Symbol,
+        call_span: Range<usize>,
+        arg_spans: Vec<Range<usize>>
+    ) -> Self {
+        Self {
+            interpreter,
+            name,
+            call_span,
+            arg_spans,
+            reported: false
+        }
+    }
+
+    pub fn interpreter(&mut self) -> &mut Interpreter<'src> {
+        self.interpreter
+    }
+
+    /// The diagnostics context of the program being run.
+    /// Diagnostics emitted through it directly don't stop the interpreter
+    /// from emitting its own for a returned error.
+    pub fn diagnostics(&self) -> &DiagnosticsContext<'src> {
+        &self.interpreter.error_ctx
+    }
+
+    /// The name the native was registered under, which errors
+    /// from it should use. Several names can share the same native
+    /// (ex. `+` and `add`).
+    pub fn name(&self) -> &'static str {
+        self.name.as_str()
+    }
+
+    pub fn call_span(&self) -> Range<usize> {
+        self.call_span.clone()
+    }
+
+    /// Where argument `idx` was written. Arguments passed by another
+    /// native rather than written in the code fall back to the whole call.
+    pub fn arg_span(&self, idx: usize) -> Range<usize> {
+        self.arg_spans
+            .get(idx)
+            .cloned()
+            .unwrap_or_else(|| self.call_span())
+    }
+
+    pub(super) fn arg_spans(&self) -> &[Range<usize>] {
+        &self.arg_spans
+    }
+
+    pub(super) fn reported(&self) -> bool {
+        self.reported
+    }
+
+    /// Start building an error for this call, which will show the
+    /// current call stack. The interpreter won't emit a diagnostic
+    /// of its own for the error the native returns.
+    pub fn build_error(&mut self, message: &str) -> DiagnosticBuilder<'_, '_> {
+        self.reported = true;
+        self.interpreter.build_error(message)
+    }
+
+    /// Call a Nightbug or native function with arguments which have
+    /// already been evaluated. Any error has already been reported.
+    pub fn call(&mut self, func: &Value, args: Vec<Value>) -> InterpResult {
+        let res = self.interpreter.apply(func, args, self.call_span.clone());
+
+        if res.is_err() {
+            self.reported = true;
+        }
+
+        res
+    }
+}
+
+type NativeFn = fn(&mut NativeContext<'_, '_>, Arguments) -> InterpResult;
+
+/// Every native function, along with its name and number of arguments.
+/// Natives with `None` as their number of arguments are variadic.
+pub const NATIVES: &[(&str, Option<usize>, NativeFn)] = &[
+    ("add", None, add_native),
+    ("+", None, add_native),
+    ("sub", None, sub_native),
+    ("-", None, sub_native),
+    ("mul", None, mul_native),
+    ("*", None, mul_native),
+    ("div", Some(2), div_native),
+    ("/", Some(2), div_native),
+    ("mod", Some(2), mod_native),
+    ("neg", Some(1), neg_native),
+    ("abs", Some(1), abs_native),
+    ("min", None, min_native),
+    ("max", None, max_native),
+    ("=", None, eq_native),
+    ("<", None, lt_native),
+    ("<=", None, le_native),
+    (">", None, gt_native),
+    (">=", None, ge_native),
+    ("floor", Some(1), floor_native),
+    ("ceil", Some(1), ceil_native),
+    ("round", Some(1), round_native),
+    ("sqrt", Some(1), sqrt_native),
+    ("exp", Some(1), exp_native),
+    ("log", Some(1), log_native),
+    ("sin", Some(1), sin_native),
+    ("cos", Some(1), cos_native),
+    ("tan", Some(1), tan_native),
+    ("asin", Some(1), asin_native),
+    ("acos", Some(1), acos_native),
+    ("atan", Some(1), atan_native),
+    ("concat", None, concat_native),
+    ("length", Some(1), length_native),
+    ("substring", Some(3), substring_native),
+    ("split", Some(2), split_native),
+    ("to-string", Some(1), to_string_native),
+    ("parse-int", Some(1), parse_int_native),
+    ("cons", Some(2), cons_native),
+    ("car", Some(1), car_native),
+    ("cdr", Some(1), cdr_native),
+    ("second", Some(2), second_native),
+    ("list", None, list_native),
+    ("empty?", Some(1), empty_native),
+    ("append", None, append_native),
+    ("reverse", Some(1), reverse_native),
+    ("nth", Some(2), nth_native),
+    ("map", Some(2), map_native),
+    ("filter", Some(2), filter_native),
+    ("fold-left", Some(3), fold_left_native),
+    ("fold-right", Some(3), fold_right_native),
+    ("box", Some(1), box_native),
+    ("unbox", Some(1), unbox_native),
+    ("set-box!", Some(2), set_box_native),
+    ("vector", None, vector_native),
+    ("vector-ref", Some(2), vector_ref_native),
+    ("vector-set!", Some(3), vector_set_native),
+    ("vector-push!", Some(2), vector_push_native),
+    ("get", Some(2), get_native),
+    ("assoc", Some(3), assoc_native),
+    ("dissoc", Some(2), dissoc_native),
+    ("keys", Some(1), keys_native),
+    ("values", Some(1), values_native),
+    ("contains?", Some(2), contains_native),
+    ("equal?", Some(2), equal_native),
+    ("symbol?", Some(1), is_symbol_native),
+    ("symbol->string", Some(1), symbol_to_string_native),
+    ("string->symbol", Some(1), string_to_symbol_native),
+    ("gensym", Some(0), gensym_native),
+    ("gc", Some(0), gc_native),
+    ("heap-stats", Some(0), heap_stats_native)
+];
+
+/// Build a list out of `items`, ending in `tail` rather than unit.
+fn list_with_tail(items: impl DoubleEndedIterator<Item = Value>, tail: Value) -> Value {
+    items.rev().fold(tail, |res, item| Value::cons(item, res))
+}
+
+fn list(items: impl DoubleEndedIterator<Item = Value>) -> Value {
+    list_with_tail(items, Value::Unit)
+}
+
+/// A number passed to an arithmetic native.
+/// Integers are promoted to exact numbers when they overflow,
+/// and exact numbers are promoted to floats whenever the two are mixed.
+#[derive(Clone, Debug)]
+enum Number {
+    Integer(i32),
+    /// Any exact number, including integers too large for `Integer`
+    Exact(BigRational),
+    Float(f64)
+}
+
+impl Number {
+    fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(i) => f64::from(*i),
+            Number::Exact(r) => r.to_f64().unwrap_or(f64::NAN),
+            Number::Float(x) => *x
+        }
+    }
+
+    /// Returns `None` if this number is a float.
+    fn to_exact(&self) -> Option<BigRational> {
+        match self {
+            Number::Integer(i) => Some(BigRational::from_integer(BigInt::from(*i))),
+            Number::Exact(r) => Some(r.clone()),
+            Number::Float(_) => None
+        }
+    }
+
+    fn is_exact_zero(&self) -> bool {
+        match self {
+            Number::Integer(i) => *i == 0,
+            Number::Exact(r) => r.is_zero(),
+            Number::Float(_) => false
+        }
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Number::Integer(i) => Value::Integer(i),
+            // nb. this demotes exact integers back to `Integer` where possible
+            Number::Exact(r) => Value::rational(r),
+            Number::Float(x) => Value::Float(x)
+        }
+    }
+}
+
+/// A binary arithmetic operation at every level of the numeric tower.
+struct Operation {
+    /// Returns `None` if the result can't be represented as an `i32`,
+    /// in which case the operation is retried with exact numbers
+    int: fn(i32, i32) -> Option<i32>,
+    exact: fn(BigRational, BigRational) -> BigRational,
+    float: fn(f64, f64) -> f64
+}
+
+const ADD: Operation = Operation {
+    int: i32::checked_add,
+    exact: |a, b| a + b,
+    float: |a, b| a + b
+};
+
+const SUB: Operation = Operation {
+    int: i32::checked_sub,
+    exact: |a, b| a - b,
+    float: |a, b| a - b
+};
+
+const MUL: Operation = Operation {
+    int: i32::checked_mul,
+    exact: |a, b| a * b,
+    float: |a, b| a * b
+};
+
+/// nb. callers must check for exact division by zero
+const DIV: Operation = Operation {
+    // Integers which don't divide evenly produce a rational
+    int: |a, b| match a.checked_rem(b) {
+        Some(0) => a.checked_div(b),
+        _ => None
+    },
+    exact: |a, b| a / b,
+    float: |a, b| a / b
+};
+
+/// The modulus, which has the same sign as the divisor.
+/// nb. callers must check for exact division by zero
+const MOD: Operation = Operation {
+    int: |a, b| {
+        // `checked_rem` only fails for `i32::MIN % -1`, which is mathematically 0
+        let rem = a.checked_rem(b).unwrap_or(0);
+
+        if rem != 0 && (rem < 0) != (b < 0) {
+            Some(rem + b)
+        } else {
+            Some(rem)
+        }
+    },
+    exact: |a, b| {
+        let rem = a % &b;
+
+        if !rem.is_zero() && rem.is_negative() != b.is_negative() {
+            rem + b
+        } else {
+            rem
+        }
+    },
+    float: |a, b| {
+        let rem = a % b;
+
+        if rem != 0.0 && (rem < 0.0) != (b < 0.0) {
+            rem + b
+        } else {
+            rem
+        }
+    }
+};
+
+/// Check that argument `idx` to `name` is a number.
+fn expect_number(name: &str, idx: usize, value: Value) -> Result<Number, InterpreterError> {
+    match value {
+        Value::Integer(i) => Ok(Number::Integer(i)),
+        Value::BigInteger(i) => Ok(Number::Exact(BigRational::from_integer(i))),
+        Value::Rational(r) => Ok(Number::Exact(r)),
+        Value::Float(x) => Ok(Number::Float(x)),
+
+        _ => Err(InterpreterError::InvalidArgument(
+            name.to_string(),
+            value,
+            Some(idx)
+        ))
+    }
+}
+
+/// Check that argument `idx` to `name` is a string.
+fn expect_string(name: &str, idx: usize, value: Value) -> Result<Rc<str>, InterpreterError> {
+    match value {
+        Value::String(s) => Ok(s),
+
+        _ => Err(InterpreterError::InvalidArgument(
+            name.to_string(),
+            value,
+            Some(idx)
+        ))
+    }
+}
+
+/// Check that argument `idx` to `name` is a symbol.
+fn expect_symbol(name: &str, idx: usize, value: Value) -> Result<Symbol, InterpreterError> {
+    match value {
+        Value::Symbol(sym) => Ok(sym),
+
+        _ => Err(InterpreterError::InvalidArgument(
+            name.to_string(),
+            value,
+            Some(idx)
+        ))
+    }
+}
+
+/// Check that argument `idx` to `name` is a pair.
+fn expect_pair(name: &str, idx: usize, value: Value) -> Result<Rc<Pair>, InterpreterError> {
+    match value {
+        Value::Pair(pair) => Ok(pair),
+        _ => Err(InterpreterError::InvalidArgument(
+            name.to_string(),
+            value,
+            Some(idx)
+        ))
+    }
+}
+
+/// Check that argument `idx` to `name` is a box.
+fn expect_box(
+    name: &str,
+    idx: usize,
+    value: Value
+) -> Result<Rc<Mutable<Value>>, InterpreterError> {
+    match value {
+        Value::Box(cell) => Ok(cell),
+        _ => Err(InterpreterError::InvalidArgument(
+            name.to_string(),
+            value,
+            Some(idx)
+        ))
+    }
+}
+
+/// Check that argument `idx` to `name` is a vector.
+fn expect_vector(
+    name: &str,
+    idx: usize,
+    value: Value
+) -> Result<Rc<Mutable<Vec<Value>>>, InterpreterError> {
+    match value {
+        Value::Vector(vector) => Ok(vector),
+        _ => Err(InterpreterError::InvalidArgument(
+            name.to_string(),
+            value,
+            Some(idx)
+        ))
+    }
+}
+
+/// Check that argument `idx` to `name` is a map.
+fn expect_map(name: &str, idx: usize, value: Value) -> Result<Rc<Map>, InterpreterError> {
+    match value {
+        Value::Map(map) => Ok(map),
+        _ => Err(InterpreterError::InvalidArgument(
+            name.to_string(),
+            value,
+            Some(idx)
+        ))
+    }
+}
+
+/// Check that argument `idx` to `name` is a proper list
+/// (a chain of pairs ending in unit), collecting its items.
+fn expect_list(name: &str, idx: usize, value: Value) -> Result<Vec<Value>, InterpreterError> {
+    value
+        .list_items()
+        .ok_or_else(|| InterpreterError::InvalidArgument(name.to_string(), value, Some(idx)))
+}
+
+/// Check that every argument to `name` is a number.
+fn number_args(name: &str, args: Arguments) -> Result<Vec<Number>, InterpreterError> {
+    args.enumerate()
+        .map(|(idx, value)| expect_number(name, idx, value))
+        .collect()
+}
+
+/// Check that `name` was given at least `min` arguments.
+fn at_least<T>(name: &str, min: usize, args: &[T]) -> Result<(), InterpreterError> {
+    if args.len() < min {
+        Err(InterpreterError::NotEnoughArgs {
+            ident: name.to_string(),
+            min,
+            got: args.len()
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that argument `idx` to `name` is an integer in `valid`, for indexing
+/// into something of length `len`. Integers too large for a machine integer
+/// are out of range rather than invalid.
+fn expect_index(
+    name: &str,
+    idx: usize,
+    value: Value,
+    valid: Range<usize>,
+    len: usize
+) -> Result<usize, InterpreterError> {
+    let index = match value {
+        Value::Integer(i) => BigInt::from(i),
+        Value::BigInteger(i) => i,
+
+        _ => {
+            return Err(InterpreterError::InvalidArgument(
+                name.to_string(),
+                value,
+                Some(idx)
+            ))
+        },
+    };
+
+    match index.to_usize() {
+        Some(idx) if valid.contains(&idx) => Ok(idx),
+
+        _ => Err(InterpreterError::IndexOutOfRange {
+            ident: name.to_string(),
+            index,
+            len
+        })
+    }
+}
+
+/// Convert a `quantity` (eg. a length) to an integer, failing if it doesn't
+/// fit in a machine integer.
+fn integer_quantity(name: &str, quantity: &'static str, n: usize) -> InterpResult {
+    i32::try_from(n)
+        .map(Value::Integer)
+        .map_err(|_| InterpreterError::IntegerOverflow {
+            ident: name.to_string(),
+            quantity,
+            max: i32::MAX.into()
+        })
+}
+
+/// Apply a binary operation at the lowest level of the numeric tower
+/// which both numbers fit into.
+fn arith(op: &Operation, lhs: Number, rhs: Number) -> Number {
+    if let (Number::Integer(a), Number::Integer(b)) = (&lhs, &rhs) {
+        if let Some(res) = (op.int)(*a, *b) {
+            return Number::Integer(res);
+        }
+    }
+
+    match (lhs.to_exact(), rhs.to_exact()) {
+        (Some(a), Some(b)) => Number::Exact((op.exact)(a, b)),
+        _ => Number::Float((op.float)(lhs.to_f64(), rhs.to_f64()))
+    }
+}
+
+/// Combine every argument using `op`, starting with `init`.
+fn fold_numbers(name: &str, args: Arguments, init: i32, op: &Operation) -> InterpResult {
+    let res = number_args(name, args)?
+        .into_iter()
+        .fold(Number::Integer(init), |res, n| arith(op, res, n));
+
+    Ok(res.into_value())
+}
+
+/// Apply a unary operation at the lowest level of the numeric tower
+/// which the argument fits into.
+fn map_number(
+    name: &str,
+    mut args: Arguments,
+    int_op: fn(i32) -> Option<i32>,
+    exact_op: fn(BigRational) -> BigRational,
+    float_op: fn(f64) -> f64
+) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let n = expect_number(name, 0, args.next().unwrap())?;
+
+    if let Number::Integer(i) = n {
+        if let Some(res) = int_op(i) {
+            return Ok(Value::Integer(res));
+        }
+    }
+
+    let res = match n.to_exact() {
+        Some(r) => Number::Exact(exact_op(r)),
+        None => Number::Float(float_op(n.to_f64()))
+    };
+
+    Ok(res.into_value())
+}
+
+/// Apply a function which always produces a float.
+fn float_function(name: &str, mut args: Arguments, op: fn(f64) -> f64) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let x = expect_number(name, 0, args.next().unwrap())?.to_f64();
+    Ok(Value::Float(op(x)))
+}
+
+/// Native variadic function to add numbers
+fn add_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    fold_numbers(ctx.name(), args, 0, &ADD)
+}
+
+/// Native variadic function to multiply numbers
+fn mul_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    fold_numbers(ctx.name(), args, 1, &MUL)
+}
+
+/// Native function to subtract every argument from the first,
+/// or to negate a single argument
+fn sub_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    let mut args = number_args(ctx.name(), args)?.into_iter();
+
+    let first = match args.next() {
+        Some(first) => first,
+        None => {
+            return Err(InterpreterError::NotEnoughArgs {
+                ident: ctx.name().to_string(),
+                min: 1,
+                got: 0
+            })
+        },
+    };
+
+    if args.len() == 0 {
+        return Ok(arith(&SUB, Number::Integer(0), first).into_value());
+    }
+
+    Ok(args.fold(first, |res, n| arith(&SUB, res, n)).into_value())
+}
+
+/// Check that `name` isn't dividing by an exact zero.
+/// Floats follow IEEE 754 instead, so dividing by `0.0` is allowed.
+fn check_divisor(name: &str, divisor: &Number) -> Result<(), InterpreterError> {
+    if divisor.is_exact_zero() {
+        Err(InterpreterError::DivisionByZero(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Native function to divide two numbers.
+/// Dividing two exact numbers produces an exact result,
+/// so `(div 1 3)` is the rational `1/3`.
+fn div_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    let mut args = number_args(ctx.name(), args)?;
+    // nb. the interpreter checks the number of arguments
+    let rhs = args.pop().unwrap();
+    let lhs = args.pop().unwrap();
+    check_divisor(ctx.name(), &rhs)?;
+    Ok(arith(&DIV, lhs, rhs).into_value())
+}
+
+/// Native function to find the modulus of two numbers.
+/// The result has the same sign as the divisor.
+fn mod_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    let mut args = number_args(ctx.name(), args)?;
+    // nb. the interpreter checks the number of arguments
+    let rhs = args.pop().unwrap();
+    let lhs = args.pop().unwrap();
+    check_divisor(ctx.name(), &rhs)?;
+    Ok(arith(&MOD, lhs, rhs).into_value())
+}
+
+/// Native function to negate a number
+fn neg_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    map_number(ctx.name(), args, i32::checked_neg, |r| -r, |x| -x)
+}
+
+/// Native function to find the absolute value of a number
+fn abs_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    map_number(ctx.name(), args, i32::checked_abs, |r| r.abs(), f64::abs)
+}
+
+/// Compare two numbers exactly, unless either is a float.
+/// Returns `None` if either number is NaN.
+fn compare(lhs: &Number, rhs: &Number) -> Option<Ordering> {
+    if let (Number::Integer(a), Number::Integer(b)) = (lhs, rhs) {
+        return Some(a.cmp(b));
+    }
+
+    match (lhs.to_exact(), rhs.to_exact()) {
+        (Some(a), Some(b)) => Some(a.cmp(&b)),
+        _ => lhs.to_f64().partial_cmp(&rhs.to_f64())
+    }
+}
+
+/// Find the argument which compares as `wanted` against every other argument.
+fn extremum(name: &str, args: Arguments, wanted: Ordering) -> InterpResult {
+    let args = number_args(name, args)?;
+    at_least(name, 1, &args)?;
+    let mut args = args.into_iter();
+    // nb. the unwrap is safe because of the check above
+    let mut res = args.next().unwrap();
+
+    for n in args {
+        if compare(&n, &res) == Some(wanted) {
+            res = n;
+        }
+    }
+
+    Ok(res.into_value())
+}
+
+/// Native variadic function to find the smallest number
+fn min_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    extremum(ctx.name(), args, Ordering::Less)
+}
+
+/// Native variadic function to find the largest number
+fn max_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    extremum(ctx.name(), args, Ordering::Greater)
+}
+
+/// Check that `cmp` holds for every adjacent pair of arguments,
+/// so that `(< a b c)` means `a < b` and `b < c`.
+/// Comparisons involving NaN never hold.
+fn compare_chain(name: &str, args: Arguments, cmp: fn(Ordering) -> bool) -> InterpResult {
+    let args = number_args(name, args)?;
+    at_least(name, 1, &args)?;
+    Ok(Value::Boolean(
+        args.windows(2)
+            .all(|pair| compare(&pair[0], &pair[1]).is_some_and(cmp))
+    ))
+}
+
+/// Native variadic function to check if numbers are equal
+fn eq_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    compare_chain(ctx.name(), args, Ordering::is_eq)
+}
+
+/// Native variadic function to check if numbers are strictly increasing
+fn lt_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    compare_chain(ctx.name(), args, Ordering::is_lt)
+}
+
+/// Native variadic function to check if numbers are increasing
+fn le_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    compare_chain(ctx.name(), args, Ordering::is_le)
+}
+
+/// Native variadic function to check if numbers are strictly decreasing
+fn gt_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    compare_chain(ctx.name(), args, Ordering::is_gt)
+}
+
+/// Native variadic function to check if numbers are decreasing
+fn ge_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    compare_chain(ctx.name(), args, Ordering::is_ge)
+}
+
+/// Native function to round a number down.
+/// Integers are returned unchanged.
+fn floor_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    map_number(ctx.name(), args, Some, |r| r.floor(), f64::floor)
+}
+
+/// Native function to round a number up.
+/// Integers are returned unchanged.
+fn ceil_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    map_number(ctx.name(), args, Some, |r| r.ceil(), f64::ceil)
+}
+
+/// Native function to round a number to the nearest integer,
+/// rounding halfway cases away from zero.
+/// Integers are returned unchanged.
+fn round_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    map_number(ctx.name(), args, Some, |r| r.round(), f64::round)
+}
+
+/// Native function to find the square root of a number
+fn sqrt_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::sqrt)
+}
+
+/// Native function to raise e to the power of a number
+fn exp_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::exp)
+}
+
+/// Native function to find the natural logarithm of a number
+fn log_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::ln)
+}
+
+/// Native function to find the sine of an angle in radians
+fn sin_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::sin)
+}
+
+/// Native function to find the cosine of an angle in radians
+fn cos_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::cos)
+}
+
+/// Native function to find the tangent of an angle in radians
+fn tan_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::tan)
+}
+
+/// Native function to find the arcsine of a number in radians
+fn asin_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::asin)
+}
+
+/// Native function to find the arccosine of a number in radians
+fn acos_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::acos)
+}
+
+/// Native function to find the arctangent of a number in radians
+fn atan_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    float_function(ctx.name(), args, f64::atan)
+}
+
+/// Native variadic function to join strings together
+fn concat_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    let mut res = String::new();
+
+    for (idx, value) in args.enumerate() {
+        res.push_str(&expect_string(ctx.name(), idx, value)?);
+    }
+
+    Ok(Value::string(&res))
+}
+
+/// Native function to find the number of characters in a string
+/// or the number of items in a list, vector, map, or set
+fn length_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let len = match args.next().unwrap() {
+        Value::String(s) => s.chars().count(),
+        Value::Vector(vector) => vector.borrow().len(),
+        Value::Map(map) => map.len(),
+        Value::Set(set) => set.len(),
+
+        value => expect_list(ctx.name(), 0, value)?.len()
+    };
+
+    integer_quantity(ctx.name(), "length", len)
+}
+
+/// Native function to take the characters of a string
+/// from a start index up to (but not including) an end index
+fn substring_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let s = expect_string(ctx.name(), 0, args.next().unwrap())?;
+    let len = s.chars().count();
+    let start_idx = expect_index(ctx.name(), 1, args.next().unwrap(), 0..len + 1, len)?;
+    let end_idx = expect_index(ctx.name(), 2, args.next().unwrap(), start_idx..len + 1, len)?;
+
+    let res: String = s
+        .chars()
+        .skip(start_idx)
+        .take(end_idx - start_idx)
+        .collect();
+    Ok(Value::string(&res))
+}
+
+/// Native function to split a string on every occurrence of a separator
+fn split_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let s = expect_string(ctx.name(), 0, args.next().unwrap())?;
+    let separator = expect_string(ctx.name(), 1, args.next().unwrap())?;
+
+    let parts: Vec<Value> = if separator.is_empty() {
+        s.chars().map(|c| Value::string(&c.to_string())).collect()
+    } else {
+        s.split(&*separator).map(Value::string).collect()
+    };
+
+    Ok(list(parts.into_iter()))
+}
+
+/// Native function to convert any value to a string.
+/// Strings are returned unchanged.
+fn to_string_native(_: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    match args.next().unwrap() {
+        value @ Value::String(_) => Ok(value),
+
+        value => Ok(Value::string(&value.to_string()))
+    }
+}
+
+/// Native function to parse a string as an integer
+fn parse_int_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let s = expect_string(ctx.name(), 0, args.next().unwrap())?;
+    s.trim()
+        .parse()
+        .map(Value::big_integer)
+        .map_err(|_| InterpreterError::CouldntParseInt(s.to_string()))
+}
+
+/// Native function to create a pair
+fn cons_native(_: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let car = args.next().unwrap();
+    let cdr = args.next().unwrap();
+    Ok(Value::cons(car, cdr))
+}
+
+/// Native function to get the first half of a pair
+/// (the head of a list)
+fn car_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let pair = expect_pair(ctx.name(), 0, args.next().unwrap())?;
+    Ok(pair.car.clone())
+}
+
+/// Native function to get the second half of a pair
+/// (the tail of a list)
+fn cdr_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let pair = expect_pair(ctx.name(), 0, args.next().unwrap())?;
+    Ok(pair.cdr.clone())
+}
+
+/// Native function to return the second of two values
+fn second_native(_: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    Ok(args.nth(1).unwrap())
+}
+
+/// Native variadic function to create a list of its arguments
+fn list_native(_: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    Ok(list(args))
+}
+
+/// Native function to check if a value is the empty list
+fn empty_native(_: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    Ok(Value::Boolean(args.next().unwrap().is_unit()))
+}
+
+/// Native variadic function to join lists together.
+/// The last argument isn't copied, so it may be any value.
+fn append_native(ctx: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    let mut args: Vec<Value> = args.collect();
+
+    let mut res = match args.pop() {
+        Some(last) => last,
+        None => return Ok(Value::Unit)
+    };
+
+    for (idx, value) in args.into_iter().enumerate().rev() {
+        res = list_with_tail(expect_list(ctx.name(), idx, value)?.into_iter(), res);
+    }
+
+    Ok(res)
+}
+
+/// Native function to reverse a list
+fn reverse_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let items = expect_list(ctx.name(), 0, args.next().unwrap())?;
+    Ok(list(items.into_iter().rev()))
+}
+
+/// Native function to get the item at an index in a list
+fn nth_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let items = expect_list(ctx.name(), 0, args.next().unwrap())?;
+    let len = items.len();
+    let idx = expect_index(ctx.name(), 1, args.next().unwrap(), 0..len, len)?;
+    Ok(items[idx].clone())
+}
+
+/// Native function to call a function on every item of a list,
+/// producing a list of the results
+fn map_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let func = args.next().unwrap();
+    let items = expect_list(ctx.name(), 1, args.next().unwrap())?;
+    let mut res = Vec::with_capacity(items.len());
+
+    for item in items {
+        res.push(ctx.call(&func, vec![item])?);
+    }
+
+    Ok(list(res.into_iter()))
+}
+
+/// Native function to keep the items of a list which a predicate returns true
+/// for
+fn filter_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let keep = args.next().unwrap();
+    let items = expect_list(ctx.name(), 1, args.next().unwrap())?;
+    let mut res = Vec::new();
+
+    for item in items {
+        match ctx.call(&keep, vec![item.clone()])? {
+            Value::Boolean(true) => res.push(item),
+            Value::Boolean(false) => (),
+
+            value => return Err(InterpreterError::NotABoolean(ctx.name().to_string(), value))
+        }
+    }
+
+    Ok(list(res.into_iter()))
+}
+
+/// Native function to combine the items of a list from the left,
+/// so `(fold-left f acc (list a b))` is `(f (f acc a) b)`
+fn fold_left_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let func = args.next().unwrap();
+    let mut acc = args.next().unwrap();
+
+    for item in expect_list(ctx.name(), 2, args.next().unwrap())? {
+        acc = ctx.call(&func, vec![acc, item])?;
+    }
+
+    Ok(acc)
+}
+
+/// Native function to combine the items of a list from the right,
+/// so `(fold-right f acc (list a b))` is `(f a (f b acc))`
+fn fold_right_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let func = args.next().unwrap();
+    let mut acc = args.next().unwrap();
+
+    for item in expect_list(ctx.name(), 2, args.next().unwrap())?
+        .into_iter()
+        .rev()
+    {
+        acc = ctx.call(&func, vec![item, acc])?;
+    }
+
+    Ok(acc)
+}
+
+/// Native function to create a box holding a value
+fn box_native(_: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    Ok(Value::boxed(args.next().unwrap()))
+}
+
+/// Native function to get the value in a box
+fn unbox_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let cell = expect_box(ctx.name(), 0, args.next().unwrap())?;
+    let res = cell.borrow().clone();
+    Ok(res)
+}
+
+/// Native function to replace the value in a box
+fn set_box_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let cell = expect_box(ctx.name(), 0, args.next().unwrap())?;
+    cell.replace(args.next().unwrap());
+    Ok(Value::Unit)
+}
+
+/// Native variadic function to create a vector of its arguments
+fn vector_native(_: &mut NativeContext<'_, '_>, args: Arguments) -> InterpResult {
+    Ok(Value::vector(args.collect()))
+}
+
+/// Check that argument 1 to `name` is an index into `vector`.
+fn vector_index(name: &str, vector: &[Value], index: Value) -> Result<usize, InterpreterError> {
+    expect_index(name, 1, index, 0..vector.len(), vector.len())
+}
+
+/// Native function to get the item at an index in a vector
+fn vector_ref_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let vector = expect_vector(ctx.name(), 0, args.next().unwrap())?;
+    let items = vector.borrow();
+    let idx = vector_index(ctx.name(), &items, args.next().unwrap())?;
+    Ok(items[idx].clone())
+}
+
+/// Native function to replace the item at an index in a vector
+fn vector_set_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let vector = expect_vector(ctx.name(), 0, args.next().unwrap())?;
+    let idx = vector_index(ctx.name(), &vector.borrow(), args.next().unwrap())?;
+    vector.borrow_mut()[idx] = args.next().unwrap();
+    Ok(Value::Unit)
+}
+
+/// Native function to add an item to the end of a vector
+fn vector_push_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let vector = expect_vector(ctx.name(), 0, args.next().unwrap())?;
+    vector.borrow_mut().push(args.next().unwrap());
+    Ok(Value::Unit)
+}
+
+/// Native function to get the value of a key in a map,
+/// or unit if the map doesn't contain the key
+fn get_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let map = expect_map(ctx.name(), 0, args.next().unwrap())?;
+    let key = args.next().unwrap();
+    Ok(map.get(&key).cloned().unwrap_or(Value::Unit))
+}
+
+/// Native function to create a copy of a map with a key set to a value
+fn assoc_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let map = expect_map(ctx.name(), 0, args.next().unwrap())?;
+    let mut entries = Rc::unwrap_or_clone(map).into_inner();
+    entries.insert(args.next().unwrap(), args.next().unwrap());
+    Ok(Value::map(entries))
+}
+
+/// Native function to create a copy of a map without a key,
+/// or a copy of a set without an element
+fn dissoc_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let collection = args.next().unwrap();
+    let key = args.next().unwrap();
+
+    match collection {
+        Value::Set(set) => {
+            let mut elements = Rc::unwrap_or_clone(set).into_inner();
+            elements.shift_remove(&key);
+            Ok(Value::set(elements))
+        },
+
+        value => {
+            let mut entries = Rc::unwrap_or_clone(expect_map(ctx.name(), 0, value)?).into_inner();
+            entries.shift_remove(&key);
+            Ok(Value::map(entries))
+        }
+    }
+}
+
+/// Native function to list the keys of a map, or the elements of a set,
+/// in the order they were added
+fn keys_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    match args.next().unwrap() {
+        Value::Set(set) => Ok(list(set.iter().cloned())),
+        value => Ok(list(expect_map(ctx.name(), 0, value)?.keys().cloned()))
+    }
+}
+
+/// Native function to list the values of a map, in the order their keys
+/// were added
+fn values_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let map = expect_map(ctx.name(), 0, args.next().unwrap())?;
+    Ok(list(map.values().cloned()))
+}
+
+/// Native function to check if a map contains a key,
+/// or if a set contains an element
+fn contains_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let collection = args.next().unwrap();
+    let key = args.next().unwrap();
+
+    let res = match collection {
+        Value::Set(set) => set.contains(&key),
+        value => expect_map(ctx.name(), 0, value)?.contains_key(&key)
+    };
+
+    Ok(Value::Boolean(res))
+}
+
+/// Native function to check if two values are structurally equal
+fn equal_native(_: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let res = args.next().unwrap() == args.next().unwrap();
+    Ok(Value::Boolean(res))
+}
+
+/// Native function to check if a value is a symbol
+fn is_symbol_native(_: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let res = matches!(args.next().unwrap(), Value::Symbol(_));
+    Ok(Value::Boolean(res))
+}
+
+/// Native function to get the name of a symbol
+fn symbol_to_string_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let sym = expect_symbol(ctx.name(), 0, args.next().unwrap())?;
+    Ok(Value::string(&sym.to_string()))
+}
+
+/// Native function to get the symbol with a given name
+fn string_to_symbol_native(ctx: &mut NativeContext<'_, '_>, mut args: Arguments) -> InterpResult {
+    // nb. the interpreter checks the number of arguments
+    let name = expect_string(ctx.name(), 0, args.next().unwrap())?;
+    Ok(Value::Symbol(Symbol::intern(&name)))
+}
+
+/// Native function to create a symbol distinct from every other symbol
+fn gensym_native(_: &mut NativeContext<'_, '_>, _: Arguments) -> InterpResult {
+    Ok(Value::Symbol(Symbol::gensym()))
+}
+
+/// Native function to free every unreachable value in the heap,
+/// returning how many were freed
+fn gc_native(ctx: &mut NativeContext<'_, '_>, _: Arguments) -> InterpResult {
+    let freed = ctx.interpreter().collect_garbage();
+    integer_quantity(ctx.name(), "count", freed)
+}
+
+/// Native function to describe the heap as an association list
+/// from kinds of values to how many are alive, along with how many
+/// collections have run and how many values they freed in total
+fn heap_stats_native(ctx: &mut NativeContext<'_, '_>, _: Arguments) -> InterpResult {
+    let stats = ctx.interpreter().heap_stats();
+    let fields = [
+        ("pairs", stats.pairs),
+        ("functions", stats.functions),
+        ("environments", stats.environments),
+        ("boxes", stats.boxes),
+        ("vectors", stats.vectors),
+        ("maps", stats.maps),
+        ("sets", stats.sets),
+        ("strings", stats.strings),
+        ("collections", stats.collections),
+        ("freed", stats.freed)
+    ];
+
+    let entries = fields
+        .iter()
+        .map(|&(name, n)| {
+            Ok(Value::cons(
+                Value::Symbol(Symbol::intern(name)),
+                integer_quantity(ctx.name(), "count", n)?
+            ))
+        })
+        .collect::<Result<Vec<_>, InterpreterError>>()?;
+    Ok(list(entries.into_iter()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(func: NativeFn, args: &[i32]) -> InterpResult {
+        let args: Vec<Value> = args.iter().copied().map(Value::Integer).collect();
+        let mut interpreter = Interpreter::new();
+        let mut ctx =
+            NativeContext::new(&mut interpreter, Symbol::intern("test"), 0..0, Vec::new());
+        func(&mut ctx, args.into_iter())
+    }
+
+    fn assert_bool(res: InterpResult, expected: bool) {
+        match res {
+            Ok(Value::Boolean(b)) => assert_eq!(b, expected),
+            res => panic!("expected a boolean, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_bool(call(lt_native, &[1, 2, 3]), true);
+        assert_bool(call(lt_native, &[1, 3, 3]), false);
+        assert_bool(call(le_native, &[1, 3, 3]), true);
+        assert_bool(call(gt_native, &[3, 2, 1]), true);
+        assert_bool(call(ge_native, &[3, 3, 4]), false);
+        assert_bool(call(eq_native, &[2, 2, 2]), true);
+        assert_bool(call(eq_native, &[2, 2, 3]), false);
+        assert_bool(call(eq_native, &[2]), true);
+    }
+
+    #[test]
+    fn comparisons_need_an_argument() {
+        assert!(matches!(
+            call(lt_native, &[]),
+            Err(InterpreterError::NotEnoughArgs { .. })
+        ));
+    }
+}
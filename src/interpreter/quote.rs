@@ -0,0 +1,262 @@
+//! Quoting: `quote` and `quasiquote`, along with `unquote` and
+//! `unquote-splicing` inside quasiquoted expressions.
+//!
+//! Quoted lists become runtime lists made of pairs which remember where
+//! they and their items were written. Quoted map and set literals become
+//! maps and sets of quoted items. Keywords become symbols, and anything
+//! else that is quoted becomes the value it evaluates to.
+
+use std::ops::Range;
+
+use super::{Expressions, InterpResult, Interpreter, InterpreterError, Value};
+use crate::{
+    parser::{Expr, ExprKind, Keyword},
+    symbol::Symbol
+};
+
+/// Turn a quoted expression into data.
+pub fn quote_expr(expr: Expr) -> Value {
+    match expr.kind {
+        ExprKind::List(contents) => {
+            let items = contents
+                .iter()
+                .map(|expr| (expr.span.clone(), quote_expr(expr.clone())))
+                .collect();
+            build_list(items, expr.span)
+        },
+
+        ExprKind::Map(contents) => Value::map_from_items(contents.iter().cloned().map(quote_expr)),
+        ExprKind::Set(contents) => Value::set(contents.iter().cloned().map(quote_expr).collect()),
+
+        ExprKind::Keyword(keyword) => Value::Symbol(Symbol::intern(keyword.as_str())),
+        ExprKind::Identifier(ident) => Value::Symbol(ident),
+        ExprKind::Integer(i) => Value::Integer(i),
+        ExprKind::BigInteger(i) => Value::BigInteger(i),
+        ExprKind::Rational(r) => Value::Rational(r),
+        ExprKind::Float(x) => Value::Float(x),
+        ExprKind::String(s) => Value::string(&s),
+        ExprKind::Boolean(b) => Value::Boolean(b),
+        ExprKind::Unit => Value::Unit,
+        // nb. the `resolve` module never replaces identifiers in quoted code
+        ExprKind::Argument(_) => unreachable!()
+    }
+}
+
+/// Build a list written at `span` out of items and where they were written.
+/// The first pair spans the whole list, while the rest span from
+/// their item to the end of the list.
+fn build_list(items: Vec<(Range<usize>, Value)>, span: Range<usize>) -> Value {
+    let end = span.end;
+
+    items
+        .into_iter()
+        .enumerate()
+        .rev()
+        .fold(Value::Unit, |res, (idx, (item_span, item))| {
+            let pair_span = if idx == 0 {
+                span.clone()
+            } else {
+                item_span.start..end
+            };
+            Value::cons_at(item, item_span, res, pair_span)
+        })
+}
+
+/// The keyword at the start of a list, if any.
+pub(super) fn head_keyword(contents: &[Expr]) -> Option<Keyword> {
+    match contents.first() {
+        Some(Expr {
+            kind: ExprKind::Keyword(keyword),
+            ..
+        }) => Some(*keyword),
+        _ => None
+    }
+}
+
+impl<'src> Interpreter<'src> {
+    /// Check that a quoting expression has exactly one argument.
+    fn quoted_expr(
+        &self,
+        keyword: Keyword,
+        span: Range<usize>,
+        mut expressions: Expressions
+    ) -> Result<Expr, InterpreterError> {
+        if expressions.len() != 1 {
+            return Err(self.malformed_expression(
+                keyword.as_str(),
+                span,
+                "expected exactly one expression"
+            ));
+        }
+
+        // nb. the unwrap is safe because of the check above
+        Ok(expressions.next().unwrap())
+    }
+
+    /// Handle a `quote` expression.
+    /// `(quote x)`, or `'x`, produces `x` as data without evaluating it.
+    pub(super) fn handle_quote(
+        &mut self,
+        span: Range<usize>,
+        expressions: Expressions
+    ) -> InterpResult {
+        let expr = self.quoted_expr(Keyword::Quote, span, expressions)?;
+        Ok(quote_expr(expr))
+    }
+
+    /// Handle a `quasiquote` expression.
+    /// ``(quasiquote x)``, or `` `x ``, is like `(quote x)`, except that
+    /// `(unquote y)` (`,y`) inside of `x` is replaced with the value of `y`,
+    /// and `(unquote-splicing z)` (`,@z`) is replaced with the items of the
+    /// list `z`. Nested quasiquotes must be unquoted once for each level.
+    pub(super) fn handle_quasiquote(
+        &mut self,
+        span: Range<usize>,
+        expressions: Expressions
+    ) -> InterpResult {
+        let expr = self.quoted_expr(Keyword::Quasiquote, span, expressions)?;
+        self.quasiquote(expr, 0)
+    }
+
+    /// Quasiquote an expression which is `depth` quasiquotes deeper
+    /// than the outermost one.
+    fn quasiquote(&mut self, expr: Expr, depth: usize) -> InterpResult {
+        let Expr { span, kind } = expr;
+
+        let contents = match kind {
+            ExprKind::List(contents) => contents.to_vec(),
+
+            // Items of maps and sets can be unquoted, but not spliced
+            ExprKind::Map(contents) => {
+                let items = self.quasiquote_all(contents.to_vec(), depth)?;
+                return Ok(Value::map_from_items(items));
+            },
+
+            ExprKind::Set(contents) => {
+                let items = self.quasiquote_all(contents.to_vec(), depth)?;
+                return Ok(Value::set(items.into_iter().collect()));
+            },
+
+            kind => return Ok(quote_expr(Expr::new(span, kind)))
+        };
+
+        let head = head_keyword(&contents);
+
+        let inner_depth = match head {
+            Some(Keyword::Quasiquote) => depth + 1,
+
+            Some(keyword @ (Keyword::Unquote | Keyword::UnquoteSplicing)) if depth == 0 => {
+                if keyword == Keyword::UnquoteSplicing {
+                    self.build_error("`unquote-splicing` can only be used inside a list")
+                        .with_span(span)
+                        .emit();
+                    return Err(InterpreterError::UnexpectedKeyword(keyword));
+                }
+
+                let mut expressions = Expressions::from(contents);
+                expressions.next();
+                let expr = self.quoted_expr(keyword, span, expressions)?;
+                return self.interpret_expr(expr);
+            },
+
+            Some(Keyword::Unquote | Keyword::UnquoteSplicing) => depth - 1,
+            _ => depth
+        };
+
+        let mut items = Vec::new();
+
+        for expr in contents {
+            let is_splice = match &expr.kind {
+                ExprKind::List(inner) => head_keyword(inner) == Some(Keyword::UnquoteSplicing),
+                _ => false
+            };
+
+            if is_splice && inner_depth == 0 {
+                items.extend(self.splice(expr)?);
+            } else {
+                let item_span = self.item_span(&expr, inner_depth);
+                items.push((item_span, self.quasiquote(expr, inner_depth)?));
+            }
+        }
+
+        Ok(build_list(items, span))
+    }
+
+    fn quasiquote_all(
+        &mut self,
+        exprs: Vec<Expr>,
+        depth: usize
+    ) -> Result<Vec<Value>, InterpreterError> {
+        exprs
+            .into_iter()
+            .map(|expr| self.quasiquote(expr, depth))
+            .collect()
+    }
+
+    /// Where an item of a quasiquoted list `depth` quasiquotes deep was
+    /// written. Values don't have spans, but an unquoted argument of a
+    /// macro keeps the span it had in the macro call.
+    fn item_span(&self, expr: &Expr, depth: usize) -> Range<usize> {
+        if let (0, ExprKind::List(contents)) = (depth, &expr.kind) {
+            if let [Expr {
+                kind: ExprKind::Keyword(Keyword::Unquote),
+                ..
+            }, Expr {
+                kind: ExprKind::Argument(idx),
+                ..
+            }] = &contents[..]
+            {
+                if let Some(span) = self.env.argument_span(*idx) {
+                    return span;
+                }
+            }
+        }
+
+        expr.span.clone()
+    }
+
+    /// Evaluate an `unquote-splicing` expression,
+    /// producing the items of the resulting list.
+    fn splice(&mut self, expr: Expr) -> Result<Vec<(Range<usize>, Value)>, InterpreterError> {
+        let span = expr.span;
+
+        let mut expressions = match expr.kind {
+            ExprKind::List(contents) => Expressions::new(contents),
+            _ => unreachable!()
+        };
+
+        expressions.next();
+        let expr = self.quoted_expr(Keyword::UnquoteSplicing, span.clone(), expressions)?;
+        let value = self.interpret_expr(expr)?;
+
+        let mut items = Vec::new();
+        let mut rest = &value;
+
+        loop {
+            match rest {
+                // Items of quoted lists keep their spans
+                Value::Pair(pair) => {
+                    let item_span = if pair.car_span.is_empty() {
+                        span.clone()
+                    } else {
+                        pair.car_span.clone()
+                    };
+                    items.push((item_span, pair.car.clone()));
+                    rest = &pair.cdr;
+                },
+
+                Value::Unit => return Ok(items),
+                _ => break
+            }
+        }
+
+        self.build_error("`unquote-splicing` expects a list")
+            .span_label(span, &format!("this is {}", value.type_name()))
+            .emit();
+        Err(InterpreterError::InvalidArgument(
+            "unquote-splicing".to_string(),
+            value,
+            None
+        ))
+    }
+}
@@ -0,0 +1,381 @@
+//! Name resolution for function parameters.
+//!
+//! Before a program is run, every reference to a function's parameter
+//! inside that function's body is replaced with an `ExprKind::Argument`,
+//! which the interpreter can find in the current call frame
+//! without searching the scope chain.
+//!
+//! References to the parameters of an enclosing function
+//! (ex. in the body of a nested `fn`) are left alone,
+//! since they must be looked up through the captured scope instead.
+//! So are quoted identifiers, which are data rather than references.
+
+use crate::{
+    parser::{Expr, ExprKind, Keyword},
+    symbol::Symbol
+};
+
+/// The parameters which can be resolved at a given point.
+/// Parameters shadowed by an inner binding are `None`,
+/// which keeps the indices of the others intact.
+type Params = [Option<Symbol>];
+
+/// Resolve parameters in every function defined in a program.
+pub fn resolve_program(program: Vec<Expr>) -> Vec<Expr> {
+    program
+        .into_iter()
+        .map(|expr| resolve_expr(expr, &[]))
+        .collect()
+}
+
+/// Resolve the parameters of a function in its body.
+pub fn resolve_function_body(body: Vec<Expr>, params: &[Symbol]) -> Vec<Expr> {
+    let params: Vec<Option<Symbol>> = params.iter().copied().map(Some).collect();
+    resolve_body(body, &params)
+}
+
+/// Resolve a sequence of expressions in a new scope.
+/// Any names `define`d in the body's scope shadow parameters
+/// throughout the entire body.
+fn resolve_body(body: Vec<Expr>, params: &Params) -> Vec<Expr> {
+    let mut defined = Vec::new();
+
+    for expr in &body {
+        defined_names(expr, &mut defined);
+    }
+
+    let params = shadow(params, &defined);
+
+    body.into_iter()
+        .map(|expr| resolve_expr(expr, &params))
+        .collect()
+}
+
+fn resolve_expr(expr: Expr, params: &Params) -> Expr {
+    let Expr { span, kind } = expr;
+
+    match kind {
+        ExprKind::Identifier(ident) => {
+            match params.iter().position(|&param| param == Some(ident)) {
+                Some(idx) => Expr::argument(span, idx),
+                None => Expr::identifier(span, ident)
+            }
+        },
+
+        ExprKind::List(contents) => Expr::list(span, resolve_list(contents.to_vec(), params)),
+
+        ExprKind::Map(contents) => Expr::map(span, resolve_all(contents.to_vec(), params)),
+        ExprKind::Set(contents) => Expr::set(span, resolve_all(contents.to_vec(), params)),
+
+        kind => Expr::new(span, kind)
+    }
+}
+
+fn resolve_all(exprs: Vec<Expr>, params: &Params) -> Vec<Expr> {
+    exprs
+        .into_iter()
+        .map(|expr| resolve_expr(expr, params))
+        .collect()
+}
+
+fn resolve_list(contents: Vec<Expr>, params: &Params) -> Vec<Expr> {
+    let keyword = match contents.first() {
+        Some(Expr {
+            kind: ExprKind::Keyword(keyword),
+            ..
+        }) => *keyword,
+
+        _ => return resolve_all(contents, params)
+    };
+
+    let mut contents = contents.into_iter();
+    // nb. the unwrap is safe since the list has a keyword at its start
+    let mut res = vec![contents.next().unwrap()];
+
+    match keyword {
+        Keyword::Fn => {
+            // (fn (params...) body...)
+            let params_expr = match contents.next() {
+                Some(params_expr) => params_expr,
+                None => return res
+            };
+
+            let inner_params = parameter_names(&params_expr);
+            let body = resolve_body(contents.collect(), &inner_params);
+            res.push(params_expr);
+            res.extend(body);
+        },
+
+        Keyword::Define => match contents.next() {
+            // (define (name params...) body...)
+            Some(
+                signature @ Expr {
+                    kind: ExprKind::List(_),
+                    ..
+                }
+            ) => {
+                let inner_params: Vec<Option<Symbol>> = match &signature.kind {
+                    ExprKind::List(signature) => signature
+                        .iter()
+                        .skip(1)
+                        .map(|param| match param.kind {
+                            ExprKind::Identifier(param) => Some(param),
+                            _ => None
+                        })
+                        .collect(),
+                    _ => unreachable!()
+                };
+
+                let body = resolve_body(contents.collect(), &inner_params);
+                res.push(signature);
+                res.extend(body);
+            },
+
+            // (define name value)
+            Some(name) => {
+                res.push(name);
+                res.extend(contents.map(|expr| resolve_expr(expr, params)));
+            },
+
+            None => {}
+        },
+
+        Keyword::Let | Keyword::LetStar | Keyword::LetRec => {
+            let bindings_expr = match contents.next() {
+                Some(bindings_expr) => bindings_expr,
+                None => return res
+            };
+
+            let (bindings_span, bindings) = match bindings_expr.kind {
+                ExprKind::List(bindings) => (bindings_expr.span, bindings),
+                _ => {
+                    res.push(bindings_expr);
+                    res.extend(resolve_body(contents.collect(), params));
+                    return res;
+                }
+            };
+
+            let names: Vec<Symbol> = bindings.iter().filter_map(let_binding_name).collect();
+            let all_shadowed = shadow(params, &names);
+
+            let bindings = bindings
+                .iter()
+                .enumerate()
+                .map(|(idx, binding)| {
+                    let value_params = match keyword {
+                        Keyword::Let => params.to_vec(),
+                        // Each value can see the bindings before it
+                        Keyword::LetStar => shadow(params, &names[..idx]),
+                        _ => all_shadowed.clone()
+                    };
+
+                    resolve_let_binding(binding.clone(), &value_params)
+                })
+                .collect();
+
+            res.push(Expr::list(bindings_span, bindings));
+            res.extend(resolve_body(contents.collect(), &all_shadowed));
+        },
+
+        Keyword::Quote => res.extend(contents),
+        Keyword::Quasiquote => {
+            res.extend(contents.map(|expr| resolve_quasiquoted(expr, 0, params)))
+        },
+        _ => res.extend(contents.map(|expr| resolve_expr(expr, params)))
+    }
+
+    res
+}
+
+/// Resolve the unquoted parts of an expression which is `depth` quasiquotes
+/// deeper than the outermost one.
+fn resolve_quasiquoted(expr: Expr, depth: usize, params: &Params) -> Expr {
+    let Expr { span, kind } = expr;
+
+    let contents = match kind {
+        ExprKind::List(contents) => contents.to_vec(),
+
+        ExprKind::Map(contents) => {
+            let contents = resolve_all_quasiquoted(contents.to_vec(), depth, params);
+            return Expr::map(span, contents);
+        },
+
+        ExprKind::Set(contents) => {
+            let contents = resolve_all_quasiquoted(contents.to_vec(), depth, params);
+            return Expr::set(span, contents);
+        },
+
+        kind => return Expr::new(span, kind)
+    };
+
+    let inner_depth = match contents.first().map(|expr| &expr.kind) {
+        Some(ExprKind::Keyword(Keyword::Quasiquote)) => depth + 1,
+
+        Some(ExprKind::Keyword(Keyword::Unquote | Keyword::UnquoteSplicing)) if depth == 0 => {
+            return Expr::list(span, resolve_list(contents, params));
+        },
+
+        Some(ExprKind::Keyword(Keyword::Unquote | Keyword::UnquoteSplicing)) => depth - 1,
+        _ => depth
+    };
+
+    Expr::list(span, resolve_all_quasiquoted(contents, inner_depth, params))
+}
+
+fn resolve_all_quasiquoted(exprs: Vec<Expr>, depth: usize, params: &Params) -> Vec<Expr> {
+    exprs
+        .into_iter()
+        .map(|expr| resolve_quasiquoted(expr, depth, params))
+        .collect()
+}
+
+/// Resolve the value in a `let` binding of the form `(name value)`.
+fn resolve_let_binding(binding: Expr, params: &Params) -> Expr {
+    match binding.kind {
+        ExprKind::List(pair) => {
+            let mut pair = pair.iter().cloned();
+            let mut res: Vec<Expr> = pair.next().into_iter().collect();
+            res.extend(pair.map(|expr| resolve_expr(expr, params)));
+            Expr::list(binding.span, res)
+        },
+
+        _ => binding
+    }
+}
+
+/// The name bound by a `let` binding of the form `(name value)`, if any.
+pub fn let_binding_name(binding: &Expr) -> Option<Symbol> {
+    match &binding.kind {
+        ExprKind::List(pair) => match pair.first() {
+            Some(Expr {
+                kind: ExprKind::Identifier(name),
+                ..
+            }) => Some(*name),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+/// Find the names bound by `define` expressions which are evaluated in the
+/// same scope as `expr`, including ones nested inside conditionals or calls.
+/// Functions and `let`-like expressions have scopes of their own,
+/// and quoted code isn't evaluated, so neither is searched.
+pub fn defined_names(expr: &Expr, names: &mut Vec<Symbol>) {
+    let contents = match &expr.kind {
+        ExprKind::List(contents) | ExprKind::Map(contents) | ExprKind::Set(contents) => contents,
+        _ => return
+    };
+
+    match contents.first().map(|expr| &expr.kind) {
+        Some(ExprKind::Keyword(Keyword::Define)) => match contents.get(1).map(|expr| &expr.kind) {
+            // (define name value)
+            Some(ExprKind::Identifier(name)) => {
+                names.push(*name);
+
+                for value in &contents[2..] {
+                    defined_names(value, names);
+                }
+            },
+
+            // (define (name params...) body...)
+            Some(ExprKind::List(signature)) => {
+                if let Some(Expr {
+                    kind: ExprKind::Identifier(name),
+                    ..
+                }) = signature.first()
+                {
+                    names.push(*name);
+                }
+            },
+
+            _ => ()
+        },
+
+        Some(ExprKind::Keyword(
+            Keyword::Fn
+            | Keyword::Let
+            | Keyword::LetStar
+            | Keyword::LetRec
+            | Keyword::Quote
+            | Keyword::Quasiquote
+        )) => (),
+
+        _ => {
+            for expr in contents.iter() {
+                defined_names(expr, names);
+            }
+        },
+    }
+}
+
+/// The names in a `fn` parameter list.
+/// Anything which isn't an identifier becomes `None`;
+/// the interpreter reports these later.
+pub fn parameter_names(params_expr: &Expr) -> Vec<Option<Symbol>> {
+    match &params_expr.kind {
+        ExprKind::List(params) => params
+            .iter()
+            .map(|param| match param.kind {
+                ExprKind::Identifier(param) => Some(param),
+                _ => None
+            })
+            .collect(),
+
+        _ => Vec::new()
+    }
+}
+
+/// Hide any parameters which are shadowed by `names`.
+fn shadow(params: &Params, names: &[Symbol]) -> Vec<Option<Symbol>> {
+    params
+        .iter()
+        .map(|param| param.filter(|param| !names.contains(param)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::lex, parser::parse};
+
+    fn resolve_str(code: &str) -> Vec<Expr> {
+        resolve_program(parse(lex(code).unwrap(), code).unwrap())
+    }
+
+    /// Find the body of `(define (name params...) body)`
+    fn define_body(expr: &Expr) -> &Expr {
+        match &expr.kind {
+            ExprKind::List(contents) => &contents[2],
+            _ => panic!("not a define expression: {:?}", expr)
+        }
+    }
+
+    #[test]
+    fn resolves_parameters() {
+        let res = resolve_str("(define (f x y) y)");
+        assert_eq!(define_body(&res[0]).kind, ExprKind::Argument(1));
+    }
+
+    #[test]
+    fn leaves_quoted_identifiers_alone() {
+        let res = resolve_str("(define (f x) `(x ,x '(x)))");
+
+        assert_eq!(
+            define_body(&res[0]).to_string(),
+            "(quasiquote (x (unquote #<argument 0>) (quote (x))))"
+        );
+    }
+
+    #[test]
+    fn leaves_nested_functions_alone() {
+        let res = resolve_str("(define (f x) (fn (y) x))");
+
+        match &define_body(&res[0]).kind {
+            ExprKind::List(contents) => {
+                assert_eq!(contents[2].kind, ExprKind::Identifier(Symbol::intern("x")))
+            },
+            kind => panic!("not a list: {:?}", kind)
+        }
+    }
+}
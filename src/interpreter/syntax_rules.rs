@@ -0,0 +1,435 @@
+//! Hygienic macros defined with `syntax-rules`.
+//!
+//! A `syntax-rules` macro is a list of rules, each made of a pattern and a
+//! template. A call to the macro is matched against each pattern in turn,
+//! and the template of the first rule that matches is filled in with the
+//! parts of the call bound to its pattern variables.
+//!
+//! A subpattern followed by `...` matches any number of items, binding each
+//! of its pattern variables to a sequence. In a template, a subtemplate
+//! followed by `...` is repeated once for each item of those sequences.
+//!
+//! Every identifier a template introduces is renamed with `Symbol::rename`,
+//! so it can't capture or be captured by identifiers written by the user.
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    parser::{Expr, ExprKind},
+    symbol::Symbol
+};
+
+/// A hygienic macro defined with `syntax-rules`.
+#[derive(Debug)]
+pub struct SyntaxRules {
+    /// Identifiers which match themselves rather than being pattern variables
+    literals: Vec<Symbol>,
+    rules: Vec<Rule>
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    /// The pattern, not including the macro name at the start
+    pattern: Vec<Expr>,
+    template: Expr
+}
+
+/// Why a rule failed to match a macro call.
+#[derive(Debug)]
+pub struct MatchFailure {
+    pub span: Range<usize>,
+    pub reason: String
+}
+
+/// Why a macro call couldn't be expanded.
+#[derive(Debug)]
+pub enum ExpandError {
+    /// No rule matched, with the reason each rule failed in order
+    NoMatch(Vec<MatchFailure>),
+    /// A template couldn't be filled in, at the given span in the template
+    Template(Range<usize>, String)
+}
+
+/// The part of a macro call bound to a pattern variable.
+#[derive(Clone, Debug)]
+enum Matched {
+    One(Expr),
+    /// Bound by a subpattern followed by `...`
+    Many(Vec<Matched>)
+}
+
+type Matches = HashMap<Symbol, Matched>;
+
+fn ellipsis() -> Symbol {
+    Symbol::intern("...")
+}
+
+fn is_ellipsis(expr: &Expr) -> bool {
+    expr.kind == ExprKind::Identifier(ellipsis())
+}
+
+fn noun(count: usize, singular: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, singular)
+    } else {
+        format!("{} {}s", count, singular)
+    }
+}
+
+/// Check that `...` only ever follows a subpattern or subtemplate,
+/// and that no list in a pattern has more than one `...`.
+/// Returns the span of the offending `...` and the reason on failure.
+pub fn check_ellipses(exprs: &[Expr], is_pattern: bool) -> Result<(), (Range<usize>, String)> {
+    let mut seen = false;
+
+    for (idx, expr) in exprs.iter().enumerate() {
+        if is_ellipsis(expr) {
+            if idx == 0 || is_ellipsis(&exprs[idx - 1]) {
+                return Err((
+                    expr.span.clone(),
+                    "`...` must follow something to repeat".to_string()
+                ));
+            }
+
+            if seen && is_pattern {
+                return Err((
+                    expr.span.clone(),
+                    "a pattern can only use `...` once per list".to_string()
+                ));
+            }
+
+            seen = true;
+        } else if let ExprKind::List(inner) | ExprKind::Map(inner) | ExprKind::Set(inner) =
+            &expr.kind
+        {
+            check_ellipses(inner, is_pattern)?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Rule {
+    pub fn new(pattern: Vec<Expr>, template: Expr) -> Self {
+        Self { pattern, template }
+    }
+}
+
+impl SyntaxRules {
+    pub fn new(literals: Vec<Symbol>, rules: Vec<Rule>) -> Self {
+        Self { literals, rules }
+    }
+
+    /// Expand a call to this macro at `span`, given its arguments.
+    pub fn expand(&self, span: Range<usize>, args: &[Expr]) -> Result<Expr, ExpandError> {
+        let mut failures = Vec::with_capacity(self.rules.len());
+
+        for rule in &self.rules {
+            let mut matches = Matches::new();
+
+            match self.match_list(&rule.pattern, args, &span, "argument", &mut matches) {
+                Ok(()) => {
+                    let mut renames = HashMap::new();
+                    return transcribe(&rule.template, &matches, &mut renames)
+                        .map_err(|(span, reason)| ExpandError::Template(span, reason));
+                },
+
+                Err(failure) => failures.push(failure)
+            }
+        }
+
+        Err(ExpandError::NoMatch(failures))
+    }
+
+    fn match_pattern(
+        &self,
+        pattern: &Expr,
+        input: &Expr,
+        matches: &mut Matches
+    ) -> Result<(), MatchFailure> {
+        let mismatch = || MatchFailure {
+            span: input.span.clone(),
+            reason: format!("expected `{}`", pattern)
+        };
+
+        match &pattern.kind {
+            ExprKind::Identifier(ident) if ident.as_str() == "_" => Ok(()),
+
+            ExprKind::Identifier(ident) if self.literals.contains(ident) => {
+                if input.kind == pattern.kind {
+                    Ok(())
+                } else {
+                    Err(mismatch())
+                }
+            },
+
+            ExprKind::Identifier(ident) => {
+                matches.insert(*ident, Matched::One(input.clone()));
+                Ok(())
+            },
+
+            ExprKind::List(patterns) => match &input.kind {
+                ExprKind::List(inputs) => {
+                    self.match_list(patterns, inputs, &input.span, "item", matches)
+                },
+                ExprKind::Unit => self.match_list(patterns, &[], &input.span, "item", matches),
+                _ => Err(MatchFailure {
+                    span: input.span.clone(),
+                    reason: "expected a list".to_string()
+                })
+            },
+
+            // Everything else, including keywords, matches itself
+            _ => {
+                if input.kind == pattern.kind {
+                    Ok(())
+                } else {
+                    Err(mismatch())
+                }
+            },
+        }
+    }
+
+    /// Match a list of patterns against a list of inputs at `span`,
+    /// describing the inputs as `noun`s in diagnostics.
+    fn match_list(
+        &self,
+        patterns: &[Expr],
+        inputs: &[Expr],
+        span: &Range<usize>,
+        noun_name: &str,
+        matches: &mut Matches
+    ) -> Result<(), MatchFailure> {
+        let ellipsis_idx = patterns.iter().position(is_ellipsis);
+
+        let (before, repeated, after) = match ellipsis_idx {
+            // nb. `check_ellipses` makes sure that `...` never comes first
+            Some(idx) => (
+                &patterns[..idx - 1],
+                Some(&patterns[idx - 1]),
+                &patterns[idx + 1..]
+            ),
+            None => (patterns, None, &[][..])
+        };
+
+        let min_len = before.len() + after.len();
+
+        if repeated.is_none() && inputs.len() != min_len {
+            return Err(MatchFailure {
+                span: span.clone(),
+                reason: format!(
+                    "expected {}, got {}",
+                    noun(min_len, noun_name),
+                    inputs.len()
+                )
+            });
+        }
+
+        if inputs.len() < min_len {
+            return Err(MatchFailure {
+                span: span.clone(),
+                reason: format!(
+                    "expected at least {}, got {}",
+                    noun(min_len, noun_name),
+                    inputs.len()
+                )
+            });
+        }
+
+        let repeated_end = inputs.len() - after.len();
+
+        for (pattern, input) in before.iter().zip(inputs) {
+            self.match_pattern(pattern, input, matches)?;
+        }
+
+        for (pattern, input) in after.iter().zip(&inputs[repeated_end..]) {
+            self.match_pattern(pattern, input, matches)?;
+        }
+
+        if let Some(repeated) = repeated {
+            let mut vars = Vec::new();
+            self.pattern_vars(repeated, &mut vars);
+
+            let mut sequences = vec![Vec::new(); vars.len()];
+
+            for input in &inputs[before.len()..repeated_end] {
+                let mut inner = Matches::new();
+                self.match_pattern(repeated, input, &mut inner)?;
+
+                for (var, sequence) in vars.iter().zip(&mut sequences) {
+                    // nb. every variable in the pattern is bound once it matches
+                    sequence.push(inner.remove(var).unwrap());
+                }
+            }
+
+            for (var, sequence) in vars.into_iter().zip(sequences) {
+                matches.insert(var, Matched::Many(sequence));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find every pattern variable in a pattern.
+    fn pattern_vars(&self, pattern: &Expr, vars: &mut Vec<Symbol>) {
+        match &pattern.kind {
+            ExprKind::Identifier(ident)
+                if ident.as_str() != "_"
+                    && *ident != ellipsis()
+                    && !self.literals.contains(ident) =>
+            {
+                vars.push(*ident)
+            },
+
+            ExprKind::List(patterns) => {
+                for pattern in patterns.iter() {
+                    self.pattern_vars(pattern, vars);
+                }
+            },
+
+            _ => ()
+        }
+    }
+}
+
+/// Find every pattern variable in a template which is bound to a sequence.
+fn sequence_vars(template: &Expr, matches: &Matches, vars: &mut Vec<Symbol>) {
+    match &template.kind {
+        ExprKind::Identifier(ident) => {
+            if let Some(Matched::Many(_)) = matches.get(ident) {
+                if !vars.contains(ident) {
+                    vars.push(*ident);
+                }
+            }
+        },
+
+        ExprKind::List(templates) | ExprKind::Map(templates) | ExprKind::Set(templates) => {
+            for template in templates.iter() {
+                sequence_vars(template, matches, vars);
+            }
+        },
+
+        _ => ()
+    }
+}
+
+/// Fill in a template using the parts of a call bound to pattern variables.
+/// Every other identifier is renamed, using the same new symbol for each
+/// use of an identifier within one expansion.
+fn transcribe(
+    template: &Expr,
+    matches: &Matches,
+    renames: &mut HashMap<Symbol, Symbol>
+) -> Result<Expr, (Range<usize>, String)> {
+    let span = template.span.clone();
+
+    match &template.kind {
+        ExprKind::Identifier(ident) => match matches.get(ident) {
+            Some(Matched::One(expr)) => Ok(expr.clone()),
+
+            Some(Matched::Many(_)) => Err((
+                span,
+                format!(
+                    "`{}` was matched with `...`, so it must be followed by `...`",
+                    ident
+                )
+            )),
+
+            None => {
+                let renamed = *renames.entry(*ident).or_insert_with(|| ident.rename());
+                Ok(Expr::identifier(span, renamed))
+            }
+        },
+
+        ExprKind::List(templates) => {
+            let res = transcribe_items(templates, matches, renames)?;
+
+            if res.is_empty() {
+                Ok(Expr::unit(span))
+            } else {
+                Ok(Expr::list(span, res))
+            }
+        },
+
+        ExprKind::Map(templates) => {
+            let res = transcribe_items(templates, matches, renames)?;
+
+            if res.len() % 2 != 0 {
+                return Err((
+                    span,
+                    "this map template produces a key without a value".to_string()
+                ));
+            }
+
+            Ok(Expr::map(span, res))
+        },
+
+        ExprKind::Set(templates) => {
+            let res = transcribe_items(templates, matches, renames)?;
+            Ok(Expr::set(span, res))
+        },
+
+        _ => Ok(template.clone())
+    }
+}
+
+/// Fill in the items of a list, map, or set template,
+/// repeating any item followed by `...`.
+fn transcribe_items(
+    templates: &[Expr],
+    matches: &Matches,
+    renames: &mut HashMap<Symbol, Symbol>
+) -> Result<Vec<Expr>, (Range<usize>, String)> {
+    let mut res = Vec::with_capacity(templates.len());
+    let mut templates = templates.iter().peekable();
+
+    while let Some(template) = templates.next() {
+        if !templates.peek().is_some_and(|next| is_ellipsis(next)) {
+            res.push(transcribe(template, matches, renames)?);
+            continue;
+        }
+
+        // Skip the `...`
+        templates.next();
+
+        let mut vars = Vec::new();
+        sequence_vars(template, matches, &mut vars);
+
+        let sequences: Vec<&[Matched]> = vars
+            .iter()
+            .map(|var| match &matches[var] {
+                Matched::Many(sequence) => sequence.as_slice(),
+                Matched::One(_) => unreachable!()
+            })
+            .collect();
+
+        let len = match sequences.first() {
+            Some(sequence) => sequence.len(),
+            None => {
+                return Err((
+                    template.span.clone(),
+                    "nothing here was matched with `...`, so it can't be repeated".to_string()
+                ))
+            },
+        };
+
+        if sequences.iter().any(|sequence| sequence.len() != len) {
+            return Err((
+                template.span.clone(),
+                "these pattern variables were matched a different number of times".to_string()
+            ));
+        }
+
+        for idx in 0..len {
+            let mut inner = matches.clone();
+
+            for (var, sequence) in vars.iter().zip(&sequences) {
+                inner.insert(*var, sequence[idx].clone());
+            }
+
+            res.push(transcribe(template, &inner, renames)?);
+        }
+    }
+
+    Ok(res)
+}
@@ -1,22 +1,80 @@
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use std::{cell::Cell, rc::Rc};
+
 use crate::{
-    interpreter::{Binding, Interpreter},
+    interpreter::{Backend, Interpreter, InterpreterError, RuntimeError, Value},
     lexer::lex,
-    parser::{parse, ExprKind}
+    parser::parse,
+    symbol::Symbol
 };
 
-macro_rules! interpret_str {
-    ($s:literal) => {{
-        let exprs = parse(lex($s).unwrap(), $s).unwrap();
-        Interpreter::new().interpret(exprs.into_iter()).unwrap()
-    }};
+fn big(s: &str) -> BigInt {
+    s.parse().unwrap()
+}
+
+fn ratio(numer: i32, denom: i32) -> Value {
+    Value::Rational(BigRational::new(numer.into(), denom.into()))
 }
 
-macro_rules! assert_result_expr {
-    ($code:literal, $kind:expr) => {
-        match interpret_str!($code) {
-            Binding::Expression(expr) => assert_eq!(expr.kind, $kind),
-            binding => panic!("Result of {} is not an expression: {:?}", $code, binding)
+const BACKENDS: [Backend; 2] = [Backend::TreeWalker, Backend::Bytecode];
+
+fn interpret_with(
+    interpreter: &mut Interpreter<'static>,
+    code: &'static str
+) -> Result<Value, RuntimeError> {
+    interpreter.interpret_with_source(parse(lex(code).unwrap(), code).unwrap(), code)
+}
+
+/// Run code on every backend, checking that they agree
+/// on the result or error and the backtrace.
+fn interpret_all(code: &'static str) -> Result<Value, RuntimeError> {
+    let mut results = BACKENDS.iter().map(|&backend| {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(backend);
+        interpret_with(&mut interpreter, code)
+    });
+    // nb. there is always at least one backend
+    let res = results.next().unwrap();
+
+    for other in results {
+        match (&res, &other) {
+            (Ok(a), Ok(b)) => assert_eq!(a.to_string(), b.to_string(), "results of {}", code),
+
+            (Err(a), Err(b)) => {
+                assert_eq!(a.to_string(), b.to_string(), "errors from {}", code);
+
+                let frames = |err: &RuntimeError| -> Vec<_> {
+                    err.backtrace
+                        .iter()
+                        .map(|frame| (frame.function, frame.span.clone()))
+                        .collect()
+                };
+                assert_eq!(frames(a), frames(b), "backtraces from {}", code);
+            },
+
+            _ => panic!("backends disagree on {}: {:?} and {:?}", code, res, other)
         }
+    }
+
+    res
+}
+
+macro_rules! interpret_str {
+    ($s:literal) => {
+        interpret_all($s).unwrap()
+    };
+}
+
+macro_rules! interpret_str_err {
+    ($s:literal) => {
+        *interpret_all($s).unwrap_err().error
+    };
+}
+
+macro_rules! assert_result {
+    ($code:literal, $expected:expr) => {
+        assert_eq!(interpret_str!($code), $expected, "result of {}", $code)
     };
 }
 
@@ -28,37 +86,1811 @@ macro_rules! assert_result_matches {
 
 #[test]
 fn integer_literal() {
-    assert_result_expr!("2", ExprKind::Integer(2));
+    assert_result!("2", Value::Integer(2));
 }
 
 #[test]
 fn boolean_literal() {
-    assert_result_expr!("true", ExprKind::Boolean(true));
-    assert_result_expr!("false", ExprKind::Boolean(false));
+    assert_result!("true", Value::Boolean(true));
+    assert_result!("false", Value::Boolean(false));
 }
 
 #[test]
 fn nightbug_function() {
-    // TODO: define function here
-    assert_result_matches!("second", Binding::Function(..))
+    assert_result_matches!("(fn (x) x)", Value::Function(_));
+    assert_result_matches!("(define (f x) x) f", Value::Function(_));
 }
 
 #[test]
 fn native_function() {
-    assert_result_matches!("add", Binding::NativeFunction(..))
+    assert_result_matches!("add", Value::NativeFunction(..))
 }
 
 #[test]
 fn add() {
-    assert_result_expr!("(add 2 3)", ExprKind::Integer(5));
+    assert_result!("(add 2 3)", Value::Integer(5));
 }
 
 #[test]
 fn second() {
-    assert_result_expr!("(second 2 3)", ExprKind::Integer(3));
+    assert_result!("(second 2 3)", Value::Integer(3));
+    assert_result_matches!("second", Value::NativeFunction(..));
+    assert!(matches!(
+        interpret_str_err!("(second 1)"),
+        InterpreterError::WrongNumArgs { .. }
+    ));
 }
 
 #[test]
 fn composed_add_second() {
-    assert_result_expr!("(add 2 (second 3 4))", ExprKind::Integer(6));
+    assert_result!("(add 2 (second 3 4))", Value::Integer(6));
+}
+
+#[test]
+fn define_value() {
+    assert_result!("(define x 5) x", Value::Integer(5));
+    assert_result!("(define x (add 2 3)) (add x x)", Value::Integer(10));
+}
+
+#[test]
+fn define_returns_unit() {
+    assert_result!("(define x 5)", Value::Unit);
+}
+
+#[test]
+fn define_function() {
+    assert_result!("(define (inc x) (add x 1)) (inc 4)", Value::Integer(5));
+    assert_result!("(define (five) 5) (five)", Value::Integer(5));
+}
+
+#[test]
+fn define_function_nested_body() {
+    assert_result!(
+        "(define (add3 a b c) (add a (add b c))) (add3 1 2 3)",
+        Value::Integer(6)
+    );
+}
+
+#[test]
+fn define_local() {
+    assert_result!(
+        "(define (double x) (define y (add x x)) y) (double 4)",
+        Value::Integer(8)
+    );
+    assert!(matches!(
+        interpret_str_err!("(define (double x) (define y (add x x)) y) (double 4) y"),
+        InterpreterError::UnknownIdentifier(_)
+    ));
+}
+
+#[test]
+fn define_malformed() {
+    assert!(matches!(
+        interpret_str_err!("(define)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(define x)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(define (f 1) 2)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+}
+
+#[test]
+fn call_non_function() {
+    assert!(matches!(
+        interpret_str_err!("(2 3)"),
+        InterpreterError::NotAFunction(_)
+    ));
+}
+
+#[test]
+fn fn_literal() {
+    assert_result_matches!("(fn (x) x)", Value::Function(_));
+    assert_result!("((fn (x y) (add x y)) 1 2)", Value::Integer(3));
+    assert_result!("((fn () 7))", Value::Integer(7));
+}
+
+#[test]
+fn fn_arguments_evaluated_once() {
+    assert_result!("((fn (x) (add x x)) (add 1 2))", Value::Integer(6));
+}
+
+#[test]
+fn closure_captures_environment() {
+    assert_result!(
+        "(define (make_adder) (define n 5) (fn (x) (add x n)))
+         (define add5 (make_adder))
+         (add5 1)",
+        Value::Integer(6)
+    );
+}
+
+#[test]
+fn closure_currying() {
+    assert_result!(
+        "(define curried_add (fn (x) (fn (y) (fn (z) (add x y z)))))
+         (((curried_add 1) 2) 3)",
+        Value::Integer(6)
+    );
+}
+
+#[test]
+fn closure_higher_order() {
+    assert_result!(
+        "(define (twice f x) (f (f x)))
+         (twice (fn (x) (add x 10)) 1)",
+        Value::Integer(21)
+    );
+}
+
+#[test]
+fn closure_shadows_parameter() {
+    assert_result!("(define (f x) ((fn (x) x) 2)) (f 1)", Value::Integer(2));
+}
+
+#[test]
+fn closure_does_not_see_caller_locals() {
+    assert!(matches!(
+        interpret_str_err!(
+            "(define get_y (fn () y))
+             (define f (fn (y) (get_y)))
+             (f 1)"
+        ),
+        InterpreterError::UnknownIdentifier(_)
+    ));
+}
+
+#[test]
+fn closure_wrong_num_args() {
+    assert!(matches!(
+        interpret_str_err!("((fn (x) x) 1 2)"),
+        InterpreterError::WrongNumArgs {
+            expected: 1,
+            got: 2,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn define_recursive_function() {
+    assert_result!(
+        "(define (f x) (define (g y) (add x y)) (g 2)) (f 1)",
+        Value::Integer(3)
+    );
+}
+
+#[test]
+fn let_bindings() {
+    assert_result!("(let ((x 1) (y 2)) (add x y))", Value::Integer(3));
+    assert_result!("(let () 5)", Value::Integer(5));
+}
+
+#[test]
+fn let_uses_enclosing_scope() {
+    assert_result!("(define x 10) (let ((x 1) (y x)) y)", Value::Integer(10));
+}
+
+#[test]
+fn let_star_sequential() {
+    assert_result!(
+        "(let* ((x 1) (y (add x 1)) (x (add y 1))) (add x y))",
+        Value::Integer(5)
+    );
+}
+
+#[test]
+fn letrec_mutual_reference() {
+    assert_result!(
+        "(letrec ((f (fn () (g))) (g (fn () 42))) (f))",
+        Value::Integer(42)
+    );
+    assert!(matches!(
+        interpret_str_err!("(let ((f (fn () (g))) (g (fn () 42))) (f))"),
+        InterpreterError::UnknownIdentifier(_)
+    ));
+}
+
+#[test]
+fn let_shadowing() {
+    assert_result!(
+        "(define x 1) (let ((x 2)) (let ((x 3)) x))",
+        Value::Integer(3)
+    );
+    assert_result!("(define x 1) (let ((x 2)) x) x", Value::Integer(1));
+}
+
+#[test]
+fn let_scope_ends() {
+    assert!(matches!(
+        interpret_str_err!("(let ((x 1)) x) x"),
+        InterpreterError::UnknownIdentifier(_)
+    ));
+}
+
+#[test]
+fn let_malformed() {
+    assert!(matches!(
+        interpret_str_err!("(let (x 1) x)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(let ((x 1)))"),
+        InterpreterError::MalformedExpression(_)
+    ));
+}
+
+#[test]
+fn closure_sees_later_globals() {
+    assert_result!("(define (f) (g)) (define (g) 7) (f)", Value::Integer(7));
+}
+
+#[test]
+fn arguments_deeply_nested() {
+    assert_result!(
+        "(define (f x) (add 1 (add 2 (add 3 x)))) (f 4)",
+        Value::Integer(10)
+    );
+    assert_result!("(second 1 (second 2 (add 1 2)))", Value::Integer(3));
+}
+
+#[test]
+fn arguments_evaluated_in_caller_scope() {
+    assert_result!(
+        "(define (f x) x) (let ((x 1) (y 2)) (f (add x y)))",
+        Value::Integer(3)
+    );
+}
+
+#[test]
+fn parameter_shadowed_by_local_define() {
+    assert_result!("(define (f x) (define x 2) x) (f 1)", Value::Integer(2));
+    assert_result!(
+        "(define (f x) (if true (define x 7) 0) x) (f 1)",
+        Value::Integer(7)
+    );
+    assert_result!(
+        "(define (f x) (when (= x 1) (list (define x 3))) x) (list (f 1) (f 2))",
+        interpret_str!("'(3 2)")
+    );
+    // Nested functions and `let` bodies have scopes of their own
+    assert_result!(
+        "(define (f x) ((fn () (define x 2))) (let () (define x 3)) x) (f 1)",
+        Value::Integer(1)
+    );
+}
+
+#[test]
+fn parameter_shadowed_by_let() {
+    assert_result!("(define (f x) (let ((x 2)) x)) (f 1)", Value::Integer(2));
+    assert_result!(
+        "(define (f x) (let ((y x) (x 2)) (add x y))) (f 1)",
+        Value::Integer(3)
+    );
+    assert_result!(
+        "(define (f x) (let* ((x (add x 1)) (y x)) y)) (f 1)",
+        Value::Integer(2)
+    );
+}
+
+#[test]
+fn parameter_used_in_let_body() {
+    assert_result!(
+        "(define (f x) (let ((y 1)) (add x y))) (f 1)",
+        Value::Integer(2)
+    );
+}
+
+#[test]
+fn nested_function_uses_outer_parameter() {
+    assert_result!(
+        "(define (make_adder n) (fn (x) (add x n)))
+         ((make_adder 2) 3)",
+        Value::Integer(5)
+    );
+}
+
+#[test]
+fn if_expression() {
+    assert_result!("(if true 1 2)", Value::Integer(1));
+    assert_result!("(if false 1 2)", Value::Integer(2));
+    assert_result!("(if false 1)", Value::Unit);
+}
+
+#[test]
+fn if_only_evaluates_taken_branch() {
+    assert_result!("(if true 1 (undefined))", Value::Integer(1));
+    assert_result!("(if false (undefined) 2)", Value::Integer(2));
+}
+
+#[test]
+fn if_requires_boolean() {
+    assert!(matches!(
+        interpret_str_err!("(if 1 2 3)"),
+        InterpreterError::NotABoolean(..)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(if true)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+}
+
+#[test]
+fn cond_expression() {
+    assert_result!("(cond (false 1) (true 2) (true 3))", Value::Integer(2));
+    assert_result!("(cond (false 1) (else 2 3))", Value::Integer(3));
+    assert_result!("(cond (false 1))", Value::Unit);
+    assert!(matches!(
+        interpret_str_err!("(cond (else 1) (true 2))"),
+        InterpreterError::MalformedExpression(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(cond (1 2))"),
+        InterpreterError::NotABoolean(..)
+    ));
+}
+
+#[test]
+fn when_unless() {
+    assert_result!("(when true 1 2)", Value::Integer(2));
+    assert_result!("(when false (undefined))", Value::Unit);
+    assert_result!("(unless false 1)", Value::Integer(1));
+    assert_result!("(unless true (undefined))", Value::Unit);
+}
+
+#[test]
+fn and_or() {
+    assert_result!("(and)", Value::Boolean(true));
+    assert_result!("(or)", Value::Boolean(false));
+    assert_result!("(and true true)", Value::Boolean(true));
+    assert_result!("(and true false)", Value::Boolean(false));
+    assert_result!("(or false true)", Value::Boolean(true));
+    assert_result!("(or false false)", Value::Boolean(false));
+}
+
+#[test]
+fn and_or_short_circuit() {
+    assert_result!("(and false (undefined))", Value::Boolean(false));
+    assert_result!("(or true (undefined))", Value::Boolean(true));
+    assert!(matches!(
+        interpret_str_err!("(and true 5)"),
+        InterpreterError::NotABoolean(..)
+    ));
+}
+
+#[test]
+fn conditionals_in_functions() {
+    assert_result!(
+        "(define (choose c x y) (if c x y)) (choose false 1 2)",
+        Value::Integer(2)
+    );
+}
+
+#[test]
+fn arithmetic() {
+    assert_result!("(sub 10 3 2)", Value::Integer(5));
+    assert_result!("(sub 4)", Value::Integer(-4));
+    assert_result!("(mul 2 3 4)", Value::Integer(24));
+    assert_result!("(mul)", Value::Integer(1));
+    assert_result!("(div 6 3)", Value::Integer(2));
+    assert_result!("(div 7 2)", ratio(7, 2));
+    assert_result!("(div (neg 7) 2)", ratio(-7, 2));
+    assert_result!("(mod 7 3)", Value::Integer(1));
+    assert_result!("(mod (neg 7) 3)", Value::Integer(2));
+    assert_result!("(mod 7 (neg 3))", Value::Integer(-2));
+    assert_result!("(neg 5)", Value::Integer(-5));
+    assert_result!("(abs (neg 5))", Value::Integer(5));
+    assert_result!("(min 3 1 2)", Value::Integer(1));
+    assert_result!("(max 3 1 2)", Value::Integer(3));
+}
+
+#[test]
+fn integer_overflow_promotes() {
+    assert_result!("(add 2147483647 1)", Value::BigInteger(big("2147483648")));
+    assert_result!("(mul 65536 65536)", Value::BigInteger(big("4294967296")));
+    assert_result!(
+        "(sub (neg 2147483647) 2)",
+        Value::BigInteger(big("-2147483649"))
+    );
+    assert_result!(
+        "(abs (sub (neg 2147483647) 1))",
+        Value::BigInteger(big("2147483648"))
+    );
+    assert_result!(
+        "(div (sub (neg 2147483647) 1) (neg 1))",
+        Value::BigInteger(big("2147483648"))
+    );
+    // Results which fit are demoted again
+    assert_result!("(- (+ 2147483647 1) 2)", Value::Integer(2147483646));
+}
+
+#[test]
+fn division_by_zero() {
+    assert!(matches!(
+        interpret_str_err!("(div 1 0)"),
+        InterpreterError::DivisionByZero(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(mod 1 0)"),
+        InterpreterError::DivisionByZero(_)
+    ));
+}
+
+#[test]
+fn arithmetic_invalid_arguments() {
+    assert!(matches!(
+        interpret_str_err!("(mul 2 true)"),
+        InterpreterError::InvalidArgument(..)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(sub)"),
+        InterpreterError::NotEnoughArgs { .. }
+    ));
+    assert!(matches!(
+        interpret_str_err!("(div 1 2 3)"),
+        InterpreterError::WrongNumArgs { .. }
+    ));
+}
+
+#[test]
+fn operator_names() {
+    assert_result!("(+ 1 (* 2 3) (- 4) (/ 9 3))", Value::Integer(6));
+    assert_result!("(<= 1 2 2)", Value::Boolean(true));
+    assert_result!(
+        "(define (zero? n) (= n 0)) (zero? -0)",
+        Value::Boolean(true)
+    );
+
+    // Errors use the name the native was called by
+    let ident = |err| match err {
+        InterpreterError::InvalidArgument(ident, ..)
+        | InterpreterError::NotEnoughArgs { ident, .. }
+        | InterpreterError::DivisionByZero(ident) => ident,
+        err => panic!("unexpected error {:?}", err)
+    };
+    assert_eq!(ident(interpret_str_err!(r#"(+ 1 "x")"#)), "+");
+    assert_eq!(ident(interpret_str_err!(r#"(add 1 "x")"#)), "add");
+    assert_eq!(ident(interpret_str_err!(r#"(* 2 true)"#)), "*");
+    assert_eq!(ident(interpret_str_err!("(-)")), "-");
+    assert_eq!(ident(interpret_str_err!("(/ 1 0)")), "/");
+    assert_eq!(ident(interpret_str_err!("(div 1 0)")), "div");
+}
+
+#[test]
+fn negative_literals() {
+    assert_result!("(+ -5 3)", Value::Integer(-2));
+    assert_result!("(- -5)", Value::Integer(5));
+}
+
+#[test]
+fn factorial() {
+    assert_result!(
+        "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))
+         (fact 10)",
+        Value::Integer(3628800)
+    );
+}
+
+#[test]
+fn string_literals() {
+    assert_result!(r#""hello""#, Value::String("hello".into()));
+    assert_result!(
+        r#"(define greeting "a\tb\u{1F41B}") greeting"#,
+        Value::String("a\tb\u{1F41B}".into())
+    );
+}
+
+#[test]
+fn string_natives() {
+    assert_result!(
+        r#"(concat "night" "bug" "")"#,
+        Value::String("nightbug".into())
+    );
+    assert_result!(r#"(concat)"#, Value::String("".into()));
+    assert_result!(r#"(length "héllo")"#, Value::Integer(5));
+    assert_result!(r#"(substring "héllo" 1 3)"#, Value::String("él".into()));
+    assert_result!(r#"(substring "abc" 3 3)"#, Value::String("".into()));
+    assert_result!(r#"(parse-int " -42 ")"#, Value::Integer(-42));
+    assert_result!(r#"(to-string (+ 1 2))"#, Value::String("3".into()));
+    assert_result!(r#"(to-string true)"#, Value::String("true".into()));
+    assert_result!(r#"(to-string "x")"#, Value::String("x".into()));
+    assert_result!(
+        r#"(to-string (fn (x) x))"#,
+        Value::String("#<anonymous function>".into())
+    );
+}
+
+#[test]
+fn split_strings() {
+    assert_eq!(
+        interpret_str!(r#"(split "a,b,,c" ",")"#).to_string(),
+        r#"("a" "b" "" "c")"#
+    );
+    assert_eq!(
+        interpret_str!(r#"(split "ab" "")"#).to_string(),
+        r#"("a" "b")"#
+    );
+}
+
+#[test]
+fn string_errors() {
+    assert!(matches!(
+        interpret_str_err!(r#"(substring "abc" 2 4)"#),
+        InterpreterError::IndexOutOfRange {
+            index,
+            len: 3,
+            ..
+        } if index == 4.into()
+    ));
+    assert!(matches!(
+        interpret_str_err!(r#"(substring "abc" 2 1)"#),
+        InterpreterError::IndexOutOfRange { index, .. } if index == 1.into()
+    ));
+    assert!(matches!(
+        interpret_str_err!(r#"(substring "abc" -1 1)"#),
+        InterpreterError::IndexOutOfRange { index, .. } if index == (-1).into()
+    ));
+    // Indexes too large for a machine integer are still out of range
+    assert!(matches!(
+        interpret_str_err!(r#"(substring "abc" 0 99999999999)"#),
+        InterpreterError::IndexOutOfRange { index, .. } if index == big("99999999999")
+    ));
+    assert!(matches!(
+        interpret_str_err!(r#"(parse-int "12a")"#),
+        InterpreterError::CouldntParseInt(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!(r#"(concat "a" 1)"#),
+        InterpreterError::InvalidArgument(..)
+    ));
+    assert!(matches!(
+        interpret_str_err!(r#"(length 5)"#),
+        InterpreterError::InvalidArgument(..)
+    ));
+}
+
+#[test]
+fn float_literals() {
+    assert_result!("2.75", Value::Float(2.75));
+    assert_result!("-1e-9", Value::Float(-1e-9));
+}
+
+#[test]
+fn mixed_arithmetic() {
+    assert_result!("(+ 1 2.5)", Value::Float(3.5));
+    assert_result!("(* 2 0.5 3)", Value::Float(3.0));
+    assert_result!("(- 10 0.5)", Value::Float(9.5));
+    assert_result!("(- 0.5)", Value::Float(-0.5));
+    assert_result!("(/ 7 2.0)", Value::Float(3.5));
+    assert_result!("(/ 7 2)", ratio(7, 2));
+    assert_result!("(mod -7.5 2)", Value::Float(0.5));
+    assert_result!("(abs -2.5)", Value::Float(2.5));
+    assert_result!("(max 1 2.5 2)", Value::Float(2.5));
+    assert_result!("(min 1 2.5)", Value::Integer(1));
+    assert_result!(
+        "(define (miles->km miles) (* miles 1.609344)) (miles->km 10)",
+        Value::Float(16.09344)
+    );
+}
+
+#[test]
+fn mixed_comparisons() {
+    assert_result!("(= 1 1.0)", Value::Boolean(true));
+    assert_result!("(< 1 1.5 2)", Value::Boolean(true));
+    assert_result!("(>= 2.0 2 3)", Value::Boolean(false));
+    assert_result!(
+        "(let ((nan (/ 0.0 0.0))) (= nan nan))",
+        Value::Boolean(false)
+    );
+}
+
+#[test]
+fn float_functions() {
+    assert_result!("(floor -2.5)", Value::Float(-3.0));
+    assert_result!("(ceil 2.1)", Value::Float(3.0));
+    assert_result!("(round 2.5)", Value::Float(3.0));
+    assert_result!("(round 7)", Value::Integer(7));
+    assert_result!("(sqrt 16)", Value::Float(4.0));
+    assert_result!("(exp 0)", Value::Float(1.0));
+    assert_result!("(log 1)", Value::Float(0.0));
+    assert_result!("(sin 0)", Value::Float(0.0));
+    assert_result!("(cos 0.0)", Value::Float(1.0));
+    assert_result!("(tan 0)", Value::Float(0.0));
+    assert_result!("(asin 0)", Value::Float(0.0));
+    assert_result!("(acos 1)", Value::Float(0.0));
+    assert_result!("(atan 0)", Value::Float(0.0));
+    assert!(matches!(
+        interpret_str_err!("(sqrt true)"),
+        InterpreterError::InvalidArgument(..)
+    ));
+}
+
+#[test]
+fn floats_to_string() {
+    assert_result!("(to-string 2.0)", Value::String("2.0".into()));
+    assert_result!("(to-string 1e-9)", Value::String("1e-9".into()));
+}
+
+#[test]
+fn big_integer_literals() {
+    assert_result!(
+        "123456789012345678901234567890",
+        Value::BigInteger(big("123456789012345678901234567890"))
+    );
+    assert_result!(
+        "(* 99999999999999999999 99999999999999999999)",
+        Value::BigInteger(big("9999999999999999999800000000000000000001"))
+    );
+    assert_result!(
+        "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))
+         (fact 25)",
+        Value::BigInteger(big("15511210043330985984000000"))
+    );
+}
+
+#[test]
+fn exact_rationals() {
+    assert_result!("(div 1 3)", ratio(1, 3));
+    assert_result!("(+ 1/3 1/6)", ratio(1, 2));
+    assert_result!("(* 3 (/ 1 3))", Value::Integer(1));
+    assert_result!("(- 1/2)", ratio(-1, 2));
+    assert_result!("(abs -3/4)", ratio(3, 4));
+    assert_result!("(mod 7/2 -1)", ratio(-1, 2));
+    assert_result!("(+ 1/2 0.25)", Value::Float(0.75));
+    assert_result!("(= 1/2 0.5 2/4)", Value::Boolean(true));
+    assert_result!("(< 1/3 0.34 1/2)", Value::Boolean(true));
+    assert_result!("(max 1/3 1/4)", ratio(1, 3));
+    assert_result!("(floor -7/2)", Value::Integer(-4));
+    assert_result!("(ceil 7/2)", Value::Integer(4));
+    assert_result!("(round 5/2)", Value::Integer(3));
+    assert_result!("(sqrt 1/4)", Value::Float(0.5));
+    assert_result!("(to-string (/ 2 -6))", Value::String("-1/3".into()));
+    assert_result!(
+        r#"(parse-int "4294967296")"#,
+        Value::BigInteger(big("4294967296"))
+    );
+    assert!(matches!(
+        interpret_str_err!("(div 1/2 0)"),
+        InterpreterError::DivisionByZero(_)
+    ));
+}
+
+#[test]
+fn pairs() {
+    assert_result!("(car (cons 1 2))", Value::Integer(1));
+    assert_result!("(cdr (cons 1 2))", Value::Integer(2));
+    assert_result!("(cdr (list 1))", Value::Unit);
+    assert_result!("(empty? (list))", Value::Boolean(true));
+    assert_result!("(empty? (cons 1 ()))", Value::Boolean(false));
+    assert!(matches!(
+        interpret_str_err!("(car ())"),
+        InterpreterError::InvalidArgument(..)
+    ));
+}
+
+#[test]
+fn list_library() {
+    assert_result!("(length (list 1 2 3))", Value::Integer(3));
+    assert_result!("(length ())", Value::Integer(0));
+    assert_result!("(nth (list 1 2 3) 2)", Value::Integer(3));
+    assert_eq!(
+        interpret_str!("(append (list 1 2) () (list 3) (list 4 5))").to_string(),
+        "(1 2 3 4 5)"
+    );
+    assert_eq!(interpret_str!("(append (list 1) 2)").to_string(), "(1 . 2)");
+    assert_eq!(
+        interpret_str!("(reverse (list 1 2 3))").to_string(),
+        "(3 2 1)"
+    );
+    assert!(matches!(
+        interpret_str_err!("(nth (list 1 2 3) 3)"),
+        InterpreterError::IndexOutOfRange {
+            index,
+            len: 3,
+            ..
+        } if index == 3.into()
+    ));
+    assert!(matches!(
+        interpret_str_err!("(nth (list 1 2 3) -99999999999)"),
+        InterpreterError::IndexOutOfRange { index, .. } if index == big("-99999999999")
+    ));
+    assert!(matches!(
+        interpret_str_err!("(length (cons 1 2))"),
+        InterpreterError::InvalidArgument(..)
+    ));
+}
+
+#[test]
+fn higher_order_list_functions() {
+    assert_eq!(
+        interpret_str!("(map (fn (x) (* x x)) (list 1 2 3))").to_string(),
+        "(1 4 9)"
+    );
+    assert_eq!(
+        interpret_str!("(filter (fn (x) (> x 1)) (list 3 1 2))").to_string(),
+        "(3 2)"
+    );
+    assert_result!("(fold-left - 0 (list 1 2 3))", Value::Integer(-6));
+    assert_result!("(fold-right - 0 (list 1 2 3))", Value::Integer(2));
+    assert_eq!(
+        interpret_str!("(fold-right cons () (list 1 2 3))").to_string(),
+        "(1 2 3)"
+    );
+}
+
+#[test]
+fn higher_order_natives_call_back() {
+    assert!(matches!(
+        interpret_str_err!("(map (fn (a b) a) (list 1 2))"),
+        InterpreterError::WrongNumArgs {
+            expected: 2,
+            got: 1,
+            ..
+        }
+    ));
+    assert!(matches!(
+        interpret_str_err!("(filter (fn (x) x) (list 1 2))"),
+        InterpreterError::NotABoolean(..)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(map 1 (list 1 2))"),
+        InterpreterError::NotAFunction(_)
+    ));
+    // Natives can be passed to natives too
+    assert_eq!(
+        interpret_str!("(map car (list (list 1 2) (list 3)))").to_string(),
+        "(1 3)"
+    );
+
+    // Errors inside a callback show the calls leading to them
+    let code = "(define (f x) (car x)) (map f (list 1))";
+    let err = interpret_all(code).unwrap_err();
+    assert!(matches!(*err.error, InterpreterError::InvalidArgument(..)));
+    let frames: Vec<_> = err
+        .backtrace
+        .iter()
+        .map(|frame| (frame.function.map(Symbol::as_str), frame.span.clone()))
+        .collect();
+    assert_eq!(frames, [(Some("f"), 23..39)]);
+}
+
+#[test]
+fn invalid_argument_index() {
+    assert!(matches!(
+        interpret_str_err!(r#"(substring "abc" 1 "x")"#),
+        InterpreterError::InvalidArgument(_, _, Some(2))
+    ));
+    assert!(matches!(
+        interpret_str_err!(r#"(+ 1 2 "three")"#),
+        InterpreterError::InvalidArgument(_, _, Some(2))
+    ));
+    assert!(matches!(
+        interpret_str_err!("(map (fn (x) x) 1)"),
+        InterpreterError::InvalidArgument(_, _, Some(1))
+    ));
+}
+
+#[test]
+fn print_lists() {
+    assert_eq!(
+        interpret_str!(r#"(list 1 "two" (list 3.0 true) ())"#).to_string(),
+        r#"(1 "two" (3.0 true) ())"#
+    );
+    assert_eq!(
+        interpret_str!("(cons 1 (cons 2 3))").to_string(),
+        "(1 2 . 3)"
+    );
+    assert_eq!(
+        interpret_str!("(list (vector 1 (box '(2 . 3))) {'a '(1)} #{()})").to_string(),
+        "(#<vector 1 #<box (2 . 3)>> {a (1)} #{()})"
+    );
+
+    // Printing lists nested in their first item mustn't recurse for each one
+    assert_result!(
+        "(define (nest n acc) (if (= n 0) acc (nest (- n 1) (list acc))))
+         (length (to-string (nest 30000 ())))",
+        Value::Integer(60002)
+    );
+}
+
+#[test]
+fn quote() {
+    assert_eq!(
+        interpret_str!("'(1 (2 \"three\") x)").to_string(),
+        "(1 (2 \"three\") x)"
+    );
+    assert_eq!(
+        interpret_str!("(quote (define x 1))").to_string(),
+        "(define x 1)"
+    );
+    assert_result!("'x", Value::Symbol(Symbol::intern("x")));
+    assert_result!("(car '(1 2))", Value::Integer(1));
+    assert_result!("(length '(a b c))", Value::Integer(3));
+    assert_result!("'()", Value::Unit);
+    assert_eq!(interpret_str!("''a").to_string(), "(quote a)");
+    assert_eq!(
+        interpret_str!("(define (f x) '(x)) (f 1)").to_string(),
+        "(x)"
+    );
+}
+
+#[test]
+fn quoted_data_keeps_spans() {
+    match interpret_str!("  '(a b)") {
+        Value::Pair(pair) => {
+            assert_eq!(pair.span, 3..8);
+            assert_eq!(pair.car_span, 4..5);
+        },
+        value => panic!("expected a list, got {:?}", value)
+    }
+}
+
+#[test]
+fn quasiquote() {
+    assert_eq!(
+        interpret_str!("(define x 2) `(1 ,x ,(+ x 1))").to_string(),
+        "(1 2 3)"
+    );
+    assert_eq!(
+        interpret_str!("(define xs '(2 3)) `(1 ,@xs 4 ,@'())").to_string(),
+        "(1 2 3 4)"
+    );
+    assert_eq!(
+        interpret_str!("(define (f x) `(x ,x)) (f 5)").to_string(),
+        "(x 5)"
+    );
+    assert_eq!(
+        interpret_str!("(define x 1) `(a `(b ,(c ,x)))").to_string(),
+        "(a (quasiquote (b (unquote (c 1)))))"
+    );
+}
+
+#[test]
+fn quasiquote_errors() {
+    assert!(matches!(
+        interpret_str_err!(",x"),
+        InterpreterError::UnexpectedKeyword(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("`,@'()"),
+        InterpreterError::UnexpectedKeyword(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("`(1 ,@2)"),
+        InterpreterError::InvalidArgument(..)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(quote 1 2)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+}
+
+#[test]
+fn symbols() {
+    assert_result!("(symbol? 'a)", Value::Boolean(true));
+    assert_result!("(symbol? \"a\")", Value::Boolean(false));
+    assert_result!("(symbol? (car '(a)))", Value::Boolean(true));
+    assert_result!(
+        "(symbol->string 'list->vector)",
+        Value::String("list->vector".into())
+    );
+    assert_result!(
+        "(string->symbol \"hello\")",
+        Value::Symbol(Symbol::intern("hello"))
+    );
+    assert!(matches!(
+        interpret_str_err!("(symbol->string \"a\")"),
+        InterpreterError::InvalidArgument(..)
+    ));
+}
+
+#[test]
+fn gensyms() {
+    // Each backend creates a different symbol, so they can't be compared
+    for backend in BACKENDS {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(backend);
+
+        match interpret_with(&mut interpreter, "(gensym)") {
+            Ok(Value::Symbol(sym)) => {
+                assert_ne!(sym, Symbol::intern(&sym.to_string()));
+            },
+            res => panic!("expected a symbol, got {:?}", res)
+        }
+    }
+
+    assert_result!("(symbol? (gensym))", Value::Boolean(true));
+}
+
+#[test]
+fn macros() {
+    assert_result!(
+        "(defmacro my-unless (c x y) `(if ,c ,y ,x))
+         (my-unless false 1 2)",
+        Value::Integer(1)
+    );
+    // Arguments aren't evaluated
+    assert_result!(
+        "(defmacro ignore (x) 0)
+         (ignore (car '()))",
+        Value::Integer(0)
+    );
+    // Macros can use other macros, and expand to macro calls
+    assert_result!(
+        "(defmacro my-not (x) `(if ,x false true))
+         (defmacro my-nand (a b) `(my-not (and ,a ,b)))
+         (my-nand true false)",
+        Value::Boolean(true)
+    );
+    assert_result!(
+        "(defmacro my-or (a b)
+           (let ((tmp (gensym)))
+             `(let ((,tmp ,a)) (if ,tmp ,tmp ,b))))
+         (define tmp 5)
+         (my-or false tmp)",
+        Value::Integer(5)
+    );
+    // Local variables hide macros with the same name
+    assert_result!(
+        "(defmacro m (x) 0)
+         (define (f m) (m 3))
+         (f (fn (x) (+ x 1)))",
+        Value::Integer(4)
+    );
+    assert_result!(
+        "(defmacro m (x) 0)
+         (let ((m (fn (x) (* x 2)))) (m 3))",
+        Value::Integer(6)
+    );
+    assert_result!(
+        "(defmacro m (x) 0)
+         (define (f)
+           (define (m x) (- x 1))
+           (m 3))
+         (f)",
+        Value::Integer(2)
+    );
+    assert_eq!(
+        interpret_str!(
+            "(defmacro m (x) 0)
+             (define (f m) (let ((y 1)) (m y)))
+             (list (f (fn (x) x)) (m 5))"
+        )
+        .to_string(),
+        "(1 0)"
+    );
+    // Macros can use anything defined above them
+    assert_result!(
+        "(define (helper x) `(+ ,x 1))
+         (defmacro m (x) (helper x))
+         (m 1)",
+        Value::Integer(2)
+    );
+    assert_result!(
+        "(define y 5)
+         (defmacro m () y)
+         (m)",
+        Value::Integer(5)
+    );
+}
+
+#[test]
+fn variadic_macros() {
+    assert_eq!(
+        interpret_str!(
+            "(defmacro my-list (. items) `(list ,@items))
+             (my-list 1 2 (+ 1 2))"
+        )
+        .to_string(),
+        "(1 2 3)"
+    );
+    assert_result!(
+        "(defmacro my-begin (first . rest) `((fn () ,first ,@rest)))
+         (my-begin 1 2 3)",
+        Value::Integer(3)
+    );
+    assert!(matches!(
+        interpret_str_err!("(defmacro m (a . rest) a) (m)"),
+        InterpreterError::NotEnoughArgs { .. }
+    ));
+}
+
+#[test]
+fn macro_errors() {
+    assert!(matches!(
+        interpret_str_err!("(defmacro m (a) a) (m 1 2)"),
+        InterpreterError::WrongNumArgs { .. }
+    ));
+    assert!(matches!(
+        interpret_str_err!("(defmacro m () car) (m)"),
+        InterpreterError::InvalidExpansion(..)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(define (f) (defmacro m () 1))"),
+        InterpreterError::MalformedExpression(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(defmacro m (a . b c) a)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+}
+
+#[test]
+fn macroexpand() {
+    assert_eq!(
+        interpret_str!(
+            "(defmacro my-not (x) `(if ,x false true))
+             (defmacro my-nand (a b) `(my-not (and ,a ,b)))
+             (macroexpand '(my-nand x y))"
+        )
+        .to_string(),
+        "(if (and x y) false true)"
+    );
+    // Quoted macro calls aren't expanded
+    assert_eq!(
+        interpret_str!(
+            "(defmacro m () 1)
+             '(m)"
+        )
+        .to_string(),
+        "(m)"
+    );
+    assert_eq!(
+        interpret_str!("(macroexpand '(+ 1 2))").to_string(),
+        "(+ 1 2)"
+    );
+}
+
+#[test]
+fn expansions_point_at_call_site() {
+    // `(if ...)` comes from the macro, while `x` comes from the call
+    match interpret_str!("(defmacro m (x) `(if ,x 1 2)) (macroexpand '(m x))") {
+        Value::Pair(pair) => {
+            assert_eq!(pair.span, 44..49);
+            match &pair.cdr {
+                Value::Pair(rest) => assert_eq!(rest.car_span, 47..48),
+                value => panic!("expected a list, got {:?}", value)
+            }
+        },
+        value => panic!("expected a list, got {:?}", value)
+    }
+}
+
+#[test]
+fn syntax_rules() {
+    assert_result!(
+        "(define-syntax my-if
+           (syntax-rules ()
+             ((_ c then otherwise) (cond (c then) (true otherwise)))))
+         (my-if false 1 2)",
+        Value::Integer(2)
+    );
+    assert_result!(
+        "(define-syntax my-let
+           (syntax-rules ()
+             ((_ ((name value) ...) body ...) ((fn (name ...) body ...) value ...))))
+         (my-let ((a 1) (b 2)) (+ a b))",
+        Value::Integer(3)
+    );
+    assert_result!(
+        "(define-syntax my-cond
+           (syntax-rules (else)
+             ((_ (else e)) e)
+             ((_ (c e) clause ...) (if c e (my-cond clause ...)))))
+         (my-cond (false 1) ((= 1 2) 2) (else 3))",
+        Value::Integer(3)
+    );
+    assert_result!(
+        "(define-syntax arrow
+           (syntax-rules (=>)
+             ((_ x => f) (f x))))
+         (arrow 1 => neg)",
+        Value::Integer(-1)
+    );
+    assert_eq!(
+        interpret_str!(
+            "(define-syntax rev-list
+               (syntax-rules ()
+                 ((_ first rest ... last) (list last rest ... first))))
+             (rev-list 1 2 3 4)"
+        )
+        .to_string(),
+        "(4 2 3 1)"
+    );
+}
+
+#[test]
+fn syntax_rules_hygiene() {
+    // The `tmp` introduced by the macro doesn't capture the user's `tmp`
+    assert_result!(
+        "(define-syntax my-or
+           (syntax-rules ()
+             ((_) false)
+             ((_ e) e)
+             ((_ e rest ...) (let ((tmp e)) (if tmp tmp (my-or rest ...))))))
+         (define tmp 5)
+         (my-or false tmp)",
+        Value::Integer(5)
+    );
+    // The user's `list` doesn't capture the `list` used by the macro
+    assert_eq!(
+        interpret_str!(
+            "(define-syntax my-list
+               (syntax-rules ()
+                 ((_ x ...) (list x ...))))
+             (let ((list 1)) (my-list list 2))"
+        )
+        .to_string(),
+        "(1 2)"
+    );
+    assert_eq!(
+        interpret_str!(
+            "(define-syntax my-list
+               (syntax-rules ()
+                 ((_ x ...) (list x ...))))
+             (macroexpand '(my-list 1 2))"
+        )
+        .to_string(),
+        "(list 1 2)"
+    );
+}
+
+#[test]
+fn syntax_rules_errors() {
+    assert!(matches!(
+        interpret_str_err!(
+            "(define-syntax arrow
+               (syntax-rules (=>)
+                 ((_ x => f) (f x))))
+             (arrow 1 2 neg)"
+        ),
+        InterpreterError::NoMatchingRule(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!(
+            "(define-syntax m
+               (syntax-rules ()
+                 ((_ a b) a)))
+             (m 1)"
+        ),
+        InterpreterError::NoMatchingRule(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(define-syntax m (syntax-rules () ((_ ... a) a)))"),
+        InterpreterError::MalformedExpression(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(define-syntax m (syntax-rules () ((_ a ... b ...) a)))"),
+        InterpreterError::MalformedExpression(_)
+    ));
+    // `x` is matched with `...`, so it must be used with `...`
+    assert!(matches!(
+        interpret_str_err!(
+            "(define-syntax m (syntax-rules () ((_ x ...) (list x))))
+             (m 1 2)"
+        ),
+        InterpreterError::MalformedExpression(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(define-syntax m (fn () 1))"),
+        InterpreterError::MalformedExpression(_)
+    ));
+}
+
+#[test]
+fn tail_calls() {
+    assert_result!(
+        "(define (loop n) (if (= n 0) 0 (loop (sub n 1))))
+         (loop 1000000)",
+        Value::Integer(0)
+    );
+    // Tail positions inside `cond`, `when`, and `let` bodies
+    assert_result!(
+        "(define (count n acc)
+           (cond ((= n 0) acc)
+                 (else (let ((m (sub n 1))) (when true (count m (add acc 1)))))))
+         (count 10000 0)",
+        Value::Integer(10000)
+    );
+    // Mutual recursion
+    assert_result!(
+        "(define (even? n) (if (= n 0) true (odd? (sub n 1))))
+         (define (odd? n) (if (= n 0) false (even? (sub n 1))))
+         (even? 100001)",
+        Value::Boolean(false)
+    );
+    // Accumulators built up by tail calls can be as deep as the loop,
+    // and must still be freed without recursing once for each level
+    assert_result!(
+        "(define (build n acc) (if (= n 0) acc (build (sub n 1) (fn () acc))))
+         (define (unwrap f n) (if (= n 0) f (unwrap (f) (sub n 1))))
+         (define chain (build 30000 7))
+         (define res (unwrap chain 30000))
+         (set! chain ())
+         res",
+        Value::Integer(7)
+    );
+    assert_result!(
+        "(define (build n acc) (if (= n 0) acc (build (sub n 1) (list acc))))
+         (define nested (build 30000 ()))
+         (set! nested ())
+         nested",
+        Value::Unit
+    );
+}
+
+#[test]
+fn deep_recursion() {
+    assert_result!(
+        "(define (f n) (if (= n 0) 0 (+ 1 (f (- n 1)))))
+         (f 5000)",
+        Value::Integer(5000)
+    );
+    assert!(matches!(
+        interpret_str_err!(
+            "(define (f n) (+ 1 (f n)))
+             (f 0)"
+        ),
+        InterpreterError::StackOverflow(Interpreter::DEFAULT_RECURSION_LIMIT)
+    ));
+}
+
+#[test]
+fn infinite_macro_expansion() {
+    assert!(matches!(
+        interpret_str_err!(
+            "(defmacro inf (x) `(inf ,x))
+             (inf 1)"
+        ),
+        InterpreterError::StackOverflow(Interpreter::DEFAULT_RECURSION_LIMIT)
+    ));
+    assert!(matches!(
+        interpret_str_err!(
+            "(define-syntax inf
+               (syntax-rules ()
+                 ((_ x) (list (inf x)))))
+             (inf 1)"
+        ),
+        InterpreterError::StackOverflow(Interpreter::DEFAULT_RECURSION_LIMIT)
+    ));
+    assert!(matches!(
+        interpret_str_err!(
+            "(defmacro inf (x) `(inf ,x))
+             (macroexpand '(inf 1))"
+        ),
+        InterpreterError::StackOverflow(Interpreter::DEFAULT_RECURSION_LIMIT)
+    ));
+
+    // Macros can still expand to calls to themselves which finish
+    assert_result!(
+        "(defmacro count-down (n)
+           (if (= n 0) 0 `(+ 1 (count-down ,(- n 1)))))
+         (count-down 100)",
+        Value::Integer(100)
+    );
+}
+
+#[test]
+fn recursion_limit() {
+    for backend in BACKENDS {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(backend);
+        interpreter.set_recursion_limit(10);
+        let res = interpret_with(
+            &mut interpreter,
+            "(define (f n) (if (= n 0) 0 (+ 1 (f (- n 1))))) (f 20)"
+        );
+        assert!(matches!(
+            res.map_err(|err| *err.error),
+            Err(InterpreterError::StackOverflow(10))
+        ));
+
+        // The interpreter can still be used after a stack overflow
+        match interpret_with(&mut interpreter, "(f 5)") {
+            Ok(Value::Integer(5)) => {},
+            res => panic!("expected 5, got {:?}", res)
+        }
+    }
+}
+
+#[test]
+fn backtraces() {
+    let code = "(define (g x) (car x)) (define (f x) (+ 1 (g x))) (f 1)";
+    let err = interpret_all(code).unwrap_err();
+    assert!(matches!(*err.error, InterpreterError::InvalidArgument(..)));
+
+    let frames: Vec<_> = err
+        .backtrace
+        .iter()
+        .map(|frame| (frame.function.map(Symbol::as_str), frame.span.clone()))
+        .collect();
+    assert_eq!(frames, [(Some("g"), 42..47), (Some("f"), 50..55)]);
+
+    // Tail calls replace the frame they were made from
+    let code = "(define (g x) (car x)) (define (f x) (g x)) (f 1)";
+    let err = interpret_all(code).unwrap_err();
+    let names: Vec<_> = err
+        .backtrace
+        .iter()
+        .map(|frame| frame.to_string())
+        .collect();
+    assert_eq!(names, ["`g`"]);
+
+    // Errors outside of any function have an empty backtrace
+    let code = "(car 1)";
+    let err = interpret_all(code).unwrap_err();
+    assert!(err.backtrace.is_empty());
+}
+
+#[test]
+fn register_native() {
+    for backend in BACKENDS {
+        let calls = Rc::new(Cell::new(0));
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(backend);
+
+        let counter = Rc::clone(&calls);
+        interpreter.register_native("count", Some(1), move |_, mut args| {
+            counter.set(counter.get() + 1);
+            Ok(args.next().unwrap())
+        });
+
+        let res = interpret_with(&mut interpreter, "(define f count) (+ (count 1) (f 2))");
+        assert!(matches!(res, Ok(Value::Integer(3))));
+        assert_eq!(calls.get(), 2);
+
+        let res = interpret_with(&mut interpreter, "count").unwrap();
+        assert_eq!(res.clone().to_string(), "#<native function count>");
+
+        let err = interpret_with(&mut interpreter, "(count 1 2)").unwrap_err();
+        assert!(matches!(*err.error, InterpreterError::WrongNumArgs { .. }));
+        assert_eq!(calls.get(), 2);
+    }
+}
+
+#[test]
+fn opaque_values() {
+    struct Point(i32, i32);
+
+    for backend in BACKENDS {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(backend);
+
+        interpreter.register_native("point", Some(0), |_, _| Ok(Value::opaque(Point(3, 4))));
+        interpreter.register_native("point-x", Some(1), |_, mut args| {
+            let point = args.next().unwrap();
+            Ok(Value::Integer(point.downcast_ref::<Point>().unwrap().0))
+        });
+
+        let res = interpret_with(&mut interpreter, "(define p (point)) (point-x p)");
+        assert!(matches!(res, Ok(Value::Integer(3))));
+
+        let res = interpret_with(&mut interpreter, "p").unwrap();
+        assert_eq!(res.to_string(), "#<opaque value>");
+        assert_eq!(res.downcast_ref::<Point>().unwrap().1, 4);
+        assert!(res.downcast_ref::<i32>().is_none());
+    }
+}
+
+#[test]
+fn switching_backends() {
+    let mut interpreter = Interpreter::new();
+    interpret_with(&mut interpreter, "(define (f x) (* x 2))").unwrap();
+    interpreter.set_backend(Backend::Bytecode);
+    interpret_with(&mut interpreter, "(define (g x) (f (+ x 1)))").unwrap();
+    interpreter.set_backend(Backend::TreeWalker);
+
+    match interpret_with(&mut interpreter, "(map g (list 1 2))") {
+        Ok(res) => assert_eq!(res.to_string(), "(4 6)"),
+        res => panic!("expected a list, got {:?}", res)
+    }
+}
+
+#[test]
+fn garbage_collection() {
+    // Each call leaves behind a scope which binds a function
+    // that captured it, which reference counting can't free.
+    // nb. the result of the previous expression is still alive
+    assert_result!(
+        "(define (make) (define (f) f) f)
+         (define kept (make))
+         (make)
+         (make)
+         0
+         (gc)",
+        Value::Integer(4)
+    );
+    assert_eq!(
+        interpret_str!(
+            "(define (make) (define (f) f) f)
+             (define kept (make))
+             (gc)
+             (kept)"
+        )
+        .to_string(),
+        "#<function f>"
+    );
+
+    assert_eq!(
+        interpret_str!("(map car (heap-stats))").to_string(),
+        "(pairs functions environments boxes vectors maps sets strings collections freed)"
+    );
+    // Each interpreter has a heap of its own
+    assert_eq!(
+        interpret_str!(
+            "(define (stat n) (cdr (nth (heap-stats) n)))
+             (gc)
+             (list (stat 8) (stat 9))"
+        )
+        .to_string(),
+        "(1 0)"
+    );
+
+    // Collecting in one interpreter leaves another's garbage alone
+    let mut first = Interpreter::new();
+    interpret_with(&mut first, "(define (make) (define (f) f) f) (make) 0").unwrap();
+    let mut second = Interpreter::new();
+    assert!(matches!(
+        interpret_with(&mut second, "(gc)"),
+        Ok(Value::Integer(0))
+    ));
+    assert!(matches!(
+        interpret_with(&mut first, "(gc)"),
+        Ok(Value::Integer(2))
+    ));
+
+    // Dropping an interpreter frees the cycles left in its heap
+    struct Token(Rc<Cell<bool>>);
+
+    impl Drop for Token {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    let dropped = Rc::new(Cell::new(false));
+    let mut interpreter = Interpreter::new();
+    let flag = Rc::clone(&dropped);
+    interpreter.register_native("token", Some(0), move |_, _| {
+        Ok(Value::opaque(Token(Rc::clone(&flag))))
+    });
+    interpret_with(
+        &mut interpreter,
+        "(define (make) (define t (token)) (define (f) t) f)
+         (define kept (make))"
+    )
+    .unwrap();
+    assert!(!dropped.get());
+    drop(interpreter);
+    assert!(dropped.get());
+
+    // Strings can't form cycles, so creating them doesn't lead to a collection
+    let mut interpreter = Interpreter::new();
+    interpreter.register_native("make-strings", Some(0), |_, _| {
+        let strings = (0..30_000).map(|i| Value::string(&i.to_string()));
+        Ok(Value::vector(strings.collect()))
+    });
+    assert!(matches!(
+        interpret_with(
+            &mut interpreter,
+            "(define strings (make-strings))
+             ((fn () (cdr (nth (heap-stats) 8))))"
+        ),
+        Ok(Value::Integer(0))
+    ));
+
+    // Strings are tracked too, and freed as soon as they're unused
+    assert_eq!(
+        interpret_str!(
+            "(define (strings) (cdr (nth (heap-stats) 7)))
+             (define before (strings))
+             (define s (list (to-string 1) (to-string 2)))
+             (define during (strings))
+             (set! s ())
+             (list (- during before) (- (strings) before))"
+        )
+        .to_string(),
+        "(2 0)"
+    );
+}
+
+#[test]
+fn drop_deeply_nested_values() {
+    // Each call nests the accumulator one level deeper,
+    // and dropping it mustn't recurse once for each level
+    assert_result!(
+        "(define (loop n acc) (if (= n 0) 0 (loop (- n 1) (fn () acc))))
+         (loop 30000 ())",
+        Value::Integer(0)
+    );
+    assert_result!(
+        "(define (loop n acc) (if (= n 0) 0 (loop (- n 1) (cons acc ()))))
+         (loop 30000 ())",
+        Value::Integer(0)
+    );
+    assert_result!(
+        "(define (loop n acc) (if (= n 0) 0 (loop (- n 1) (box acc))))
+         (loop 30000 ())",
+        Value::Integer(0)
+    );
+    assert_result!(
+        "(define (loop n acc) (if (= n 0) 0 (loop (- n 1) (vector acc))))
+         (loop 30000 ())",
+        Value::Integer(0)
+    );
+}
+
+#[test]
+fn set() {
+    assert_result!("(define x 1) (set! x 2) x", Value::Integer(2));
+    assert_result!("(define x 1) (let ((x 2)) (set! x 3)) x", Value::Integer(1));
+    assert_result!("(define (f x) (set! x (+ x 1)) x) (f 1)", Value::Integer(2));
+    assert_result!("(set! car 1)", Value::Unit);
+
+    // Closures share the bindings they capture
+    assert_result!(
+        "(define (counter)
+           (let ((n 0))
+             (fn () (set! n (+ n 1)) n)))
+         (define c (counter))
+         (c)
+         (c)",
+        Value::Integer(2)
+    );
+    assert_result!(
+        "(define (make x) (list (fn () x) (fn (y) (set! x y))))
+         (define fs (make 1))
+         ((car (cdr fs)) 5)
+         ((car fs))",
+        Value::Integer(5)
+    );
+}
+
+#[test]
+fn set_undefined() {
+    assert!(matches!(
+        interpret_str_err!("(set! x 1)"),
+        InterpreterError::UnknownIdentifier(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(define (f) (set! y 1)) (f)"),
+        InterpreterError::UnknownIdentifier(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(set! 1 2)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+    assert!(matches!(
+        interpret_str_err!("(define x 1) (set! x)"),
+        InterpreterError::MalformedExpression(_)
+    ));
+}
+
+#[test]
+fn boxes() {
+    assert_result!("(unbox (box 1))", Value::Integer(1));
+    assert_result!(
+        "(define b (box 1)) (set-box! b (+ (unbox b) 1)) (unbox b)",
+        Value::Integer(2)
+    );
+    assert_eq!(interpret_str!("(box '(1 2))").to_string(), "#<box (1 2)>");
+    assert!(matches!(
+        interpret_str_err!("(unbox 1)"),
+        InterpreterError::InvalidArgument(_, _, Some(0))
+    ));
+}
+
+#[test]
+fn vectors() {
+    assert_result!("(vector-ref (vector 1 2 3) 1)", Value::Integer(2));
+    assert_result!("(length (vector))", Value::Integer(0));
+    assert_eq!(
+        interpret_str!(
+            "(define v (vector 1 2))
+             (vector-set! v 0 'a)
+             (vector-push! v \"b\")
+             v"
+        )
+        .to_string(),
+        "#<vector a 2 \"b\">"
+    );
+    assert!(matches!(
+        interpret_str_err!("(vector-ref (vector 1 2) 2)"),
+        InterpreterError::IndexOutOfRange {
+            index,
+            len: 2,
+            ..
+        } if index == 2.into()
+    ));
+    assert!(matches!(
+        interpret_str_err!("(vector-set! (vector) -1 0)"),
+        InterpreterError::IndexOutOfRange {
+            index,
+            len: 0,
+            ..
+        } if index == (-1).into()
+    ));
+    assert!(matches!(
+        interpret_str_err!("(vector-ref (vector 1 2) 99999999999)"),
+        InterpreterError::IndexOutOfRange { index, .. } if index == big("99999999999")
+    ));
+}
+
+#[test]
+fn mutable_cycles() {
+    assert_eq!(
+        interpret_str!("(define b (box 0)) (set-box! b b) b").to_string(),
+        "#<box ...>"
+    );
+    assert_eq!(
+        interpret_str!("(define v (vector 1)) (vector-push! v v) v").to_string(),
+        "#<vector 1 ...>"
+    );
+    assert_result!(
+        "(define b (box 0))
+         (set-box! b (vector b))
+         (set! b 0)
+         (gc)",
+        Value::Integer(2)
+    );
+}
+
+#[test]
+fn map_and_set_literals() {
+    assert_eq!(
+        interpret_str!("(define x 2) {'a 1 \"b\" x (+ x 1) (list x)}").to_string(),
+        "{a 1 \"b\" 2 3 (2)}"
+    );
+    assert_eq!(interpret_str!("#{1 2 (+ 1 1) 'c}").to_string(), "#{1 2 c}");
+    assert_eq!(interpret_str!("{'a 1 'a 2}").to_string(), "{a 2}");
+    assert_eq!(interpret_str!("(list {} #{})").to_string(), "({} #{})");
+
+    // Quoted maps and sets hold quoted items
+    assert_eq!(
+        interpret_str!("'{a (b c) #{d} {}}").to_string(),
+        "{a (b c) #{d} {}}"
+    );
+    assert_eq!(
+        interpret_str!("(define x 1) `{a ,x b #{,(+ x 1)}}").to_string(),
+        "{a 1 b #{2}}"
+    );
+    assert_eq!(
+        interpret_str!("(define (f x) {'x x}) (f 3)").to_string(),
+        "{x 3}"
+    );
+}
+
+#[test]
+fn map_and_set_natives() {
+    assert_result!("(get {'a 1 'b 2} 'b)", Value::Integer(2));
+    assert_result!("(get {'a 1} 'b)", Value::Unit);
+    assert_result!("(length {'a 1 'b 2})", Value::Integer(2));
+    assert_result!("(length #{1 1 2})", Value::Integer(2));
+    assert_result!("(contains? {'a 1} 'a)", Value::Boolean(true));
+    assert_result!("(contains? #{1 2} 3)", Value::Boolean(false));
+
+    assert_eq!(
+        interpret_str!("(define m {'a 1}) (list (assoc m 'b 2) (assoc m 'a 3) m)").to_string(),
+        "({a 1 b 2} {a 3} {a 1})"
+    );
+    assert_eq!(
+        interpret_str!("(list (dissoc {'a 1 'b 2 'c 3} 'b) (dissoc #{1 2 3} 1))").to_string(),
+        "({a 1 c 3} #{2 3})"
+    );
+    assert_eq!(
+        interpret_str!("(define m {'b 1 'a 2}) (list (keys m) (values m) (keys #{3 1}))")
+            .to_string(),
+        "((b a) (1 2) (3 1))"
+    );
+    assert!(matches!(
+        interpret_str_err!("(get #{1} 1)"),
+        InterpreterError::InvalidArgument(_, Value::Set(_), Some(0))
+    ));
+}
+
+#[test]
+fn structural_equality() {
+    assert_result!(
+        "(equal? '(1 (2 \"x\")) (list 1 (list 2 \"x\")))",
+        Value::Boolean(true)
+    );
+    assert_result!("(equal? '(1 2) '(1 2 3))", Value::Boolean(false));
+    assert_result!("(equal? {'a 1 'b 2} {'b 2 'a 1})", Value::Boolean(true));
+    assert_result!("(equal? #{1 2} #{2 1})", Value::Boolean(true));
+    assert_result!("(equal? 1 1.0)", Value::Boolean(false));
+    assert_result!("(equal? (/ 1 2) (/ 2 4))", Value::Boolean(true));
+
+    // Mutable values and functions are only equal to themselves
+    assert_result!("(equal? (box 1) (box 1))", Value::Boolean(false));
+    assert_result!("(define v (vector)) (equal? v v)", Value::Boolean(true));
+    assert_result!("(equal? car car)", Value::Boolean(true));
+    assert_result!("(equal? (fn () 1) (fn () 1))", Value::Boolean(false));
+
+    // Any value can be a key, including maps, sets, and lists
+    assert_result!(
+        "(get {'(1 2) 'list {'a 1} 'map #{1 2} 'set} #{2 1})",
+        Value::Symbol(Symbol::intern("set"))
+    );
+    assert_result!(
+        "(define m {{'a 1 'b 2} 1}) (get m {'b 2 'a 1})",
+        Value::Integer(1)
+    );
+    assert_result!(
+        "(define b (box 0)) (list (contains? #{b} b) (contains? #{b} (box 0)))",
+        interpret_str!("'(true false)")
+    );
+}
+
+#[test]
+fn deeply_nested_equality() {
+    // Comparing, hashing and dropping these mustn't recurse for each level
+    assert_eq!(
+        interpret_str!(
+            "(define (nest n acc) (if (= n 0) acc (nest (- n 1) (list acc))))
+             (define a (nest 100000 1))
+             (define b (nest 100000 1))
+             (list (equal? a b) (equal? a (nest 100000 2)) (get {a 'found} b))"
+        )
+        .to_string(),
+        "(true false found)"
+    );
+    assert_eq!(
+        interpret_str!(
+            "(define (nest n acc) (if (= n 0) acc (nest (- n 1) {'a acc})))
+             (define a (nest 30000 1))
+             (define b (nest 30000 1))
+             (list (equal? a b) (equal? a (nest 30000 2)) (contains? #{a} b))"
+        )
+        .to_string(),
+        "(true false true)"
+    );
+}
+
+#[test]
+fn maps_in_macros() {
+    assert_eq!(
+        interpret_str!(
+            "(defmacro entry (k v) `{',k ,v})
+             (entry a (+ 1 2))"
+        )
+        .to_string(),
+        "{a 3}"
+    );
+    assert_eq!(
+        interpret_str!(
+            "(define-syntax with-default
+               (syntax-rules ()
+                 ((with-default x ...) {x ... 'default 0})))
+             (define-syntax make-set
+               (syntax-rules ()
+                 ((make-set x ...) #{x ...})))
+             (list (with-default 'a 1) (make-set 1 2 1))"
+        )
+        .to_string(),
+        "({a 1 default 0} #{1 2})"
+    );
+    assert!(matches!(
+        interpret_str_err!(
+            "(define-syntax with-default
+               (syntax-rules ()
+                 ((with-default x ...) {x ... 'default 0})))
+             (with-default 'a)"
+        ),
+        InterpreterError::MalformedExpression(..)
+    ));
+}
+
+#[test]
+fn map_cycles() {
+    assert_result!(
+        "(define b (box 0))
+         (set-box! b {'self b})
+         (set! b 0)
+         (gc)",
+        Value::Integer(2)
+    );
 }
@@ -0,0 +1,680 @@
+//! Values produced by running Nightbug code.
+//!
+//! Values are separate from the `Expr`s they are computed from, so they
+//! don't carry spans of their own. Quoted lists are the exception, since
+//! macros turn them back into code: each pair remembers where its list
+//! and its first item were written.
+//!
+//! Most values are immutable. Boxes and vectors can be changed in place,
+//! which means they can end up containing themselves.
+//!
+//! Values are compared and hashed structurally, so that they can be used as
+//! the keys of maps and the elements of sets. Boxes, vectors, functions and
+//! opaque values are the exception: they are only equal to themselves, since
+//! the contents of a key mustn't change while it is in a map.
+
+use indexmap::{IndexMap, IndexSet};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::hash_map::DefaultHasher,
+    convert::TryFrom,
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+    ops::{Deref, Range},
+    rc::Rc
+};
+
+use super::{
+    heap::{self, Children, Owned},
+    Function, NativeFunction
+};
+use crate::{parser, symbol::Symbol};
+
+/// A runtime value.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Integer(i32),
+    /// An integer too large for `Integer`
+    BigInteger(BigInt),
+    /// An exact fraction which isn't an integer
+    Rational(BigRational),
+    Float(f64),
+    String(Rc<str>),
+    Boolean(bool),
+    Symbol(Symbol),
+    Unit,
+    /// A cons cell. Proper lists are chains of pairs ending in unit.
+    Pair(Rc<Pair>),
+    /// A mutable cell holding a single value
+    Box(Rc<Mutable<Value>>),
+    /// A growable array of values which can be changed in place
+    Vector(Rc<Mutable<Vec<Value>>>),
+    /// An immutable map, which remembers the order its keys were added in
+    Map(Rc<Map>),
+    /// An immutable set, which remembers the order its elements were added in
+    Set(Rc<Set>),
+    /// A function defined in Nightbug
+    Function(Rc<Function>),
+    /// A function defined in Rust
+    NativeFunction(Rc<NativeFunction>),
+    /// A value from the program embedding the interpreter,
+    /// which only natives can look inside
+    Opaque(Rc<dyn Any>)
+}
+
+impl Value {
+    /// Describe what kind of value this is, for use in diagnostics.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) | Value::BigInteger(_) => "an integer",
+            Value::Rational(_) => "a rational",
+            Value::Float(_) => "a float",
+            Value::String(_) => "a string",
+            Value::Boolean(_) => "a boolean",
+            Value::Symbol(_) => "a symbol",
+            Value::Unit => "unit",
+            Value::Pair(_) => "a list",
+            Value::Box(_) => "a box",
+            Value::Vector(_) => "a vector",
+            Value::Map(_) => "a map",
+            Value::Set(_) => "a set",
+            Value::Function(_) | Value::NativeFunction(_) => "a function",
+            Value::Opaque(_) => "an opaque value"
+        }
+    }
+
+    /// Create an exact integer, which is an `Integer` if it fits
+    /// and a `BigInteger` otherwise.
+    pub fn big_integer(num: BigInt) -> Self {
+        match i32::try_from(&num) {
+            Ok(num) => Value::Integer(num),
+            Err(_) => Value::BigInteger(num)
+        }
+    }
+
+    /// Create an exact number, which is only a `Rational`
+    /// if it isn't an integer.
+    pub fn rational(num: BigRational) -> Self {
+        if num.is_integer() {
+            Self::big_integer(num.to_integer())
+        } else {
+            Value::Rational(num)
+        }
+    }
+
+    pub fn string(s: &str) -> Self {
+        let s: Rc<str> = s.into();
+        heap::register_string(&s);
+        Value::String(s)
+    }
+
+    /// Wrap a value from the program embedding the interpreter.
+    pub fn opaque(value: impl Any) -> Self {
+        Value::Opaque(Rc::new(value))
+    }
+
+    /// Get the value inside an opaque value, if it has type `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        match self {
+            Value::Opaque(value) => value.downcast_ref(),
+            _ => None
+        }
+    }
+
+    /// Create a pair which wasn't written in the code.
+    pub fn cons(car: Value, cdr: Value) -> Self {
+        Self::cons_at(car, 0..0, cdr, 0..0)
+    }
+
+    /// Create a pair for a quoted list written at `span`,
+    /// whose first item was written at `car_span`.
+    pub fn cons_at(car: Value, car_span: Range<usize>, cdr: Value, span: Range<usize>) -> Self {
+        let pair = Rc::new(Pair {
+            car,
+            cdr,
+            span,
+            car_span
+        });
+        heap::register_pair(&pair);
+        Value::Pair(pair)
+    }
+
+    /// Create a box holding `value`.
+    pub fn boxed(value: Value) -> Self {
+        let cell = Rc::new(Mutable::new(value));
+        heap::register_box(&cell);
+        Value::Box(cell)
+    }
+
+    /// Create a vector holding `items`.
+    pub fn vector(items: Vec<Value>) -> Self {
+        let vector = Rc::new(Mutable::new(items));
+        heap::register_vector(&vector);
+        Value::Vector(vector)
+    }
+
+    /// Create a map holding `entries`.
+    pub fn map(entries: IndexMap<Value, Value>) -> Self {
+        let map = Rc::new(Immutable(entries));
+        heap::register_map(&map);
+        Value::Map(map)
+    }
+
+    /// Create a map out of alternating keys and values.
+    /// If a key appears more than once, its last value is used.
+    pub fn map_from_items(items: impl IntoIterator<Item = Value>) -> Self {
+        let mut entries = IndexMap::new();
+        let mut items = items.into_iter();
+
+        while let (Some(key), Some(value)) = (items.next(), items.next()) {
+            entries.insert(key, value);
+        }
+
+        Self::map(entries)
+    }
+
+    /// Create a set holding `elements`.
+    pub fn set(elements: IndexSet<Value>) -> Self {
+        let set = Rc::new(Immutable(elements));
+        heap::register_set(&set);
+        Value::Set(set)
+    }
+
+    pub fn is_unit(&self) -> bool {
+        matches!(self, Value::Unit)
+    }
+
+    /// Collect the items of a proper list (a chain of pairs ending in unit).
+    /// Returns `None` if this isn't a proper list.
+    pub fn list_items(&self) -> Option<Vec<Value>> {
+        let mut res = Vec::new();
+        let mut rest = self;
+
+        loop {
+            match rest {
+                Value::Pair(pair) => {
+                    res.push(pair.car.clone());
+                    rest = &pair.cdr;
+                },
+
+                Value::Unit => return Some(res),
+                _ => return None
+            }
+        }
+    }
+}
+
+/// Compares values structurally. Numbers are only equal to numbers of the same
+/// kind, so `1` and `1.0` are different keys, and floats are compared bit for
+/// bit, so that every float is equal to itself.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        // Nested values are compared from a stack of pairs left to compare,
+        // since deeply nested lists would otherwise overflow the stack.
+        // Only the keys of maps and elements of sets are compared separately,
+        // when they are looked up in the other map or set.
+        let mut stack = Vec::new();
+        let (mut left, mut right) = (self, other);
+
+        loop {
+            let equal = match (left, right) {
+                (Value::Pair(a), Value::Pair(b)) => {
+                    if !Rc::ptr_eq(a, b) {
+                        stack.push((&a.cdr, &b.cdr));
+                        stack.push((&a.car, &b.car));
+                    }
+
+                    true
+                },
+
+                (Value::Map(a), Value::Map(b)) => {
+                    Rc::ptr_eq(a, b)
+                        || a.len() == b.len()
+                            && a.iter().all(|(key, value)| match b.get(key) {
+                                Some(other) => {
+                                    stack.push((value, other));
+                                    true
+                                },
+                                None => false
+                            })
+                },
+
+                (Value::Set(a), Value::Set(b)) => {
+                    Rc::ptr_eq(a, b) || a.len() == b.len() && a.iter().all(|item| b.contains(item))
+                },
+
+                (Value::Integer(a), Value::Integer(b)) => a == b,
+                (Value::BigInteger(a), Value::BigInteger(b)) => a == b,
+                (Value::Rational(a), Value::Rational(b)) => a == b,
+                (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+                (Value::String(a), Value::String(b)) => a == b,
+                (Value::Boolean(a), Value::Boolean(b)) => a == b,
+                (Value::Symbol(a), Value::Symbol(b)) => a == b,
+                (Value::Unit, Value::Unit) => true,
+                (Value::Box(a), Value::Box(b)) => Rc::ptr_eq(a, b),
+                (Value::Vector(a), Value::Vector(b)) => Rc::ptr_eq(a, b),
+                (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+                (Value::NativeFunction(a), Value::NativeFunction(b)) => Rc::ptr_eq(a, b),
+                (Value::Opaque(a), Value::Opaque(b)) => Rc::ptr_eq(a, b),
+                _ => false
+            };
+
+            if !equal {
+                return false;
+            }
+
+            match stack.pop() {
+                Some((a, b)) => (left, right) = (a, b),
+                None => return true
+            }
+        }
+    }
+}
+
+impl Eq for Value {}
+
+/// Part of a value which is waiting to be hashed.
+enum HashTask<'a> {
+    Value(&'a Value),
+    /// Hash the following values on their own, as an item of a map or set
+    StartItem,
+    /// Add the hash of an item to the total for its map or set
+    EndItem,
+    /// Hash the total of a map or set's items
+    EndItems
+}
+
+/// Where hashes are currently being written:
+/// the hasher for the innermost item of a map or set, if there is one.
+fn hash_target<'a, H: Hasher>(
+    state: &'a mut H,
+    items: &'a mut [DefaultHasher]
+) -> &'a mut dyn Hasher {
+    match items.last_mut() {
+        Some(item) => item,
+        None => state
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Like `eq`, nested values are hashed from a stack. The items of maps
+        // and sets are each hashed on their own, and the hashes are summed,
+        // since equal maps and sets can have their items in a different order.
+        let mut tasks = vec![HashTask::Value(self)];
+        let mut items: Vec<DefaultHasher> = Vec::new();
+        let mut totals: Vec<u64> = Vec::new();
+
+        while let Some(task) = tasks.pop() {
+            let value = match task {
+                HashTask::Value(value) => value,
+                HashTask::StartItem => {
+                    items.push(DefaultHasher::new());
+                    continue;
+                },
+
+                HashTask::EndItem => {
+                    // nb. every item is inside a map or set
+                    let hash = items.pop().unwrap().finish();
+                    let total = totals.last_mut().unwrap();
+                    *total = total.wrapping_add(hash);
+                    continue;
+                },
+
+                HashTask::EndItems => {
+                    let total = totals.pop().unwrap();
+                    hash_target(state, &mut items).write_u64(total);
+                    continue;
+                }
+            };
+
+            let mut target = hash_target(state, &mut items);
+            mem::discriminant(value).hash(&mut target);
+
+            match value {
+                Value::Pair(pair) => {
+                    tasks.push(HashTask::Value(&pair.cdr));
+                    tasks.push(HashTask::Value(&pair.car));
+                },
+
+                Value::Map(map) => {
+                    target.write_usize(map.len());
+                    totals.push(0);
+                    tasks.push(HashTask::EndItems);
+
+                    for (key, value) in map.iter() {
+                        tasks.push(HashTask::EndItem);
+                        tasks.push(HashTask::Value(value));
+                        tasks.push(HashTask::Value(key));
+                        tasks.push(HashTask::StartItem);
+                    }
+                },
+
+                Value::Set(set) => {
+                    target.write_usize(set.len());
+                    totals.push(0);
+                    tasks.push(HashTask::EndItems);
+
+                    for element in set.iter() {
+                        tasks.push(HashTask::EndItem);
+                        tasks.push(HashTask::Value(element));
+                        tasks.push(HashTask::StartItem);
+                    }
+                },
+
+                Value::Integer(i) => i.hash(&mut target),
+                Value::BigInteger(i) => i.hash(&mut target),
+                Value::Rational(r) => r.hash(&mut target),
+                Value::Float(x) => x.to_bits().hash(&mut target),
+                Value::String(s) => s.hash(&mut target),
+                Value::Boolean(b) => b.hash(&mut target),
+                Value::Symbol(sym) => sym.hash(&mut target),
+                Value::Unit => (),
+                Value::Box(cell) => Rc::as_ptr(cell).hash(&mut target),
+                Value::Vector(vector) => Rc::as_ptr(vector).hash(&mut target),
+                Value::Function(function) => Rc::as_ptr(function).hash(&mut target),
+                Value::NativeFunction(native) => Rc::as_ptr(native).hash(&mut target),
+                Value::Opaque(value) => (Rc::as_ptr(value) as *const ()).hash(&mut target)
+            }
+        }
+    }
+}
+
+/// A cons cell, holding its `car` (the head of a list)
+/// and its `cdr` (the tail of a list).
+#[derive(Debug)]
+pub struct Pair {
+    pub car: Value,
+    pub cdr: Value,
+    /// Where the list starting at this pair was written,
+    /// if it was quoted, or `0..0` otherwise
+    pub span: Range<usize>,
+    /// Where the car was written, if it was quoted, or `0..0` otherwise
+    pub car_span: Range<usize>
+}
+
+impl Children for Pair {
+    fn take_children(&mut self, owned: &mut Vec<Owned>) {
+        self.car.take_children(owned);
+        self.cdr.take_children(owned);
+    }
+}
+
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let mut owned = Vec::new();
+        self.take_children(&mut owned);
+        heap::drop_all(owned);
+    }
+}
+
+/// The contents of a box or vector.
+pub struct Mutable<T: Children>(RefCell<T>);
+
+impl<T: Children> Children for Mutable<T> {
+    fn take_children(&mut self, owned: &mut Vec<Owned>) {
+        self.0.get_mut().take_children(owned);
+    }
+}
+
+impl<T: Children> Drop for Mutable<T> {
+    fn drop(&mut self) {
+        let mut owned = Vec::new();
+        self.take_children(&mut owned);
+        heap::drop_all(owned);
+    }
+}
+
+impl<T: Children> Mutable<T> {
+    fn new(contents: T) -> Self {
+        Self(RefCell::new(contents))
+    }
+}
+
+impl<T: Children> Deref for Mutable<T> {
+    type Target = RefCell<T>;
+
+    fn deref(&self) -> &RefCell<T> {
+        &self.0
+    }
+}
+
+thread_local! {
+    /// The boxes and vectors which are currently being printed
+    static PRINTING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Print the contents of the box or vector at `address` using `print`,
+/// unless it is already being printed because it contains itself,
+/// in which case it is printed as `...`.
+fn write_mutable(
+    f: &mut fmt::Formatter<'_>,
+    address: *const (),
+    print: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result
+) -> fmt::Result {
+    let cycle = PRINTING.with(|printing| {
+        let mut printing = printing.borrow_mut();
+        let cycle = printing.contains(&address);
+        printing.push(address);
+        cycle
+    });
+
+    let res = if cycle { write!(f, "...") } else { print(f) };
+
+    PRINTING.with(|printing| printing.borrow_mut().pop());
+    res
+}
+
+/// The entries of a map or the elements of a set. These never change,
+/// so this only exists to drop them without recursing.
+#[derive(Clone, Debug)]
+pub struct Immutable<T: Children>(T);
+
+pub type Map = Immutable<IndexMap<Value, Value>>;
+pub type Set = Immutable<IndexSet<Value>>;
+
+impl<T: Children + Default> Immutable<T> {
+    /// Take the contents, to make a changed copy of them.
+    pub fn into_inner(mut self) -> T {
+        mem::take(&mut self.0)
+    }
+}
+
+impl<T: Children> Children for Immutable<T> {
+    fn take_children(&mut self, owned: &mut Vec<Owned>) {
+        self.0.take_children(owned);
+    }
+}
+
+impl<T: Children> Drop for Immutable<T> {
+    fn drop(&mut self) {
+        let mut owned = Vec::new();
+        self.take_children(&mut owned);
+        heap::drop_all(owned);
+    }
+}
+
+impl<T: Children> Deref for Immutable<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Children + fmt::Debug> fmt::Debug for Mutable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_mutable(f, self as *const Self as *const (), |f| {
+            fmt::Debug::fmt(&self.0, f)
+        })
+    }
+}
+
+/// Prints a value as it would appear in Nightbug source code.
+/// Lists are printed as s-expressions (ex. `(1 2 3)`, or `(1 . 2)`
+/// if they don't end in unit). Other values have no source representation,
+/// so they are printed as (ex.) `#<function name>` or `#<vector 1 2>`.
+/// Maps and sets are printed as literals (ex. `{a 1}` or `#{1 2}`).
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Printing the inner values of lists and containers recursively
+        // would overflow the stack for deeply nested ones,
+        // so they're printed from a stack of pieces which are left to print
+        let depth = PRINTING.with(|printing| printing.borrow().len());
+        let mut pieces = vec![Piece::Value(self.clone())];
+        let mut res = Ok(());
+
+        while let Some(piece) = pieces.pop() {
+            res = write_piece(f, piece, &mut pieces);
+
+            if res.is_err() {
+                break;
+            }
+        }
+
+        PRINTING.with(|printing| printing.borrow_mut().truncate(depth));
+        res
+    }
+}
+
+/// Part of a value which is waiting to be printed.
+enum Piece {
+    Value(Value),
+    /// The rest of a list after its first item
+    Rest(Value),
+    Text(&'static str),
+    /// The end of a box or vector, which can be printed again
+    /// without being a cycle
+    EndMutable
+}
+
+/// Print a piece of a value, pushing the pieces inside it
+/// onto `pieces` in reverse order.
+fn write_piece(f: &mut fmt::Formatter<'_>, piece: Piece, pieces: &mut Vec<Piece>) -> fmt::Result {
+    let value = match piece {
+        Piece::Value(value) => value,
+        Piece::Text(text) => return write!(f, "{}", text),
+
+        Piece::EndMutable => {
+            PRINTING.with(|printing| printing.borrow_mut().pop());
+            return Ok(());
+        },
+
+        Piece::Rest(Value::Pair(pair)) => {
+            pieces.push(Piece::Rest(pair.cdr.clone()));
+            pieces.push(Piece::Value(pair.car.clone()));
+            return write!(f, " ");
+        },
+
+        Piece::Rest(Value::Unit) => return write!(f, ")"),
+
+        Piece::Rest(rest) => {
+            pieces.push(Piece::Text(")"));
+            pieces.push(Piece::Value(rest));
+            return write!(f, " . ");
+        }
+    };
+
+    match value {
+        Value::Integer(i) => write!(f, "{}", i),
+        Value::BigInteger(i) => write!(f, "{}", i),
+        Value::Rational(r) => write!(f, "{}", r),
+        // nb. `Debug` always includes a decimal point or exponent,
+        // so the output is read back as a float
+        Value::Float(x) => write!(f, "{:?}", x),
+        Value::String(s) => parser::write_string(f, &s),
+        Value::Boolean(b) => write!(f, "{}", b),
+        Value::Symbol(sym) => write!(f, "{}", sym),
+        Value::Unit => write!(f, "()"),
+
+        Value::Function(function) => match &function.name {
+            Some(name) => write!(f, "#<function {}>", name),
+            None => write!(f, "#<anonymous function>")
+        },
+
+        Value::NativeFunction(native) => write!(f, "#<native function {}>", native.name),
+        Value::Opaque(_) => write!(f, "#<opaque value>"),
+
+        Value::Box(cell) => {
+            if !start_mutable(Rc::as_ptr(&cell) as *const (), pieces) {
+                return write!(f, "...");
+            }
+
+            pieces.push(Piece::Text(">"));
+            pieces.push(Piece::Value(cell.borrow().clone()));
+            write!(f, "#<box ")
+        },
+
+        Value::Vector(vector) => {
+            if !start_mutable(Rc::as_ptr(&vector) as *const (), pieces) {
+                return write!(f, "...");
+            }
+
+            pieces.push(Piece::Text(">"));
+
+            for item in vector.borrow().iter().rev() {
+                pieces.push(Piece::Value(item.clone()));
+                pieces.push(Piece::Text(" "));
+            }
+
+            write!(f, "#<vector")
+        },
+
+        Value::Map(map) => {
+            pieces.push(Piece::Text("}"));
+
+            for (idx, (key, value)) in map.iter().enumerate().rev() {
+                pieces.push(Piece::Value(value.clone()));
+                pieces.push(Piece::Text(" "));
+                pieces.push(Piece::Value(key.clone()));
+
+                if idx != 0 {
+                    pieces.push(Piece::Text(" "));
+                }
+            }
+
+            write!(f, "{{")
+        },
+
+        Value::Set(set) => {
+            pieces.push(Piece::Text("}"));
+
+            for (idx, element) in set.iter().enumerate().rev() {
+                pieces.push(Piece::Value(element.clone()));
+
+                if idx != 0 {
+                    pieces.push(Piece::Text(" "));
+                }
+            }
+
+            write!(f, "#{{")
+        },
+
+        Value::Pair(pair) => {
+            pieces.push(Piece::Rest(pair.cdr.clone()));
+            pieces.push(Piece::Value(pair.car.clone()));
+            write!(f, "(")
+        }
+    }
+}
+
+/// Start printing the contents of the box or vector at `address`,
+/// unless it is already being printed because it contains itself,
+/// in which case it should be printed as `...`.
+fn start_mutable(address: *const (), pieces: &mut Vec<Piece>) -> bool {
+    PRINTING.with(|printing| {
+        let mut printing = printing.borrow_mut();
+
+        if printing.contains(&address) {
+            return false;
+        }
+
+        printing.push(address);
+        pieces.push(Piece::EndMutable);
+        true
+    })
+}
@@ -0,0 +1,334 @@
+//! A stack-based virtual machine which runs bytecode from the `compile` module.
+//!
+//! Values are kept on an operand stack, and calls to Nightbug functions push
+//! a frame rather than recursing, so a tail call can simply replace the frame
+//! of the call it was made from. Scopes and call frames for diagnostics are
+//! shared with the tree-walking interpreter, so the two backends see the same
+//! bindings and report the same errors.
+
+use std::{ops::Range, rc::Rc};
+
+use super::{
+    compile::{self, CallSite, Chunk, Op},
+    environment::{Environment, ScopeKind},
+    CallFrame, Expr, Function, InterpResult, Interpreter, InterpreterError, Value, STACK_GROWTH,
+    STACK_RED_ZONE
+};
+
+/// A call to a Nightbug function which the VM is running.
+struct Frame {
+    chunk: Rc<Chunk>,
+    /// The index of the next instruction
+    ip: usize,
+    /// The scope to go back to once the call returns
+    caller_env: Rc<Environment>
+}
+
+/// What to do after calling a function.
+enum Called {
+    /// The function produced a value straight away
+    Value(Value),
+    /// The function's body has to be run, in a scope which is already current
+    Enter(Rc<Chunk>)
+}
+
+impl<'src> Interpreter<'src> {
+    /// Compile and run a program which has already been expanded and resolved.
+    pub(super) fn run_program(&mut self, program: &[Expr]) -> InterpResult {
+        let chunk = Rc::new(compile::compile_program(program));
+        self.execute(|_| Ok(chunk))
+    }
+
+    /// Run a Nightbug function with arguments which have already been
+    /// evaluated, as if it was called at `call_span`.
+    pub(super) fn run_function(
+        &mut self,
+        function: &Function,
+        args: Vec<Value>,
+        call_span: Range<usize>
+    ) -> InterpResult {
+        self.execute(|this| this.enter_function(function, args, call_span))
+    }
+
+    /// Run the chunk produced by `enter` until it returns, restoring the
+    /// current scope and call stack afterwards.
+    fn execute(
+        &mut self,
+        enter: impl FnOnce(&mut Self) -> Result<Rc<Chunk>, InterpreterError>
+    ) -> InterpResult {
+        // Natives which call back into Nightbug run the VM recursively
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || {
+            let prev_env = Rc::clone(&self.env);
+            let prev_depth = self.call_stack.len();
+
+            let res = enter(self).and_then(|chunk| self.run(chunk, Rc::clone(&prev_env)));
+
+            if res.is_err() && self.error_backtrace.is_none() {
+                self.error_backtrace = Some(self.call_stack.iter().rev().cloned().collect());
+            }
+
+            self.env = prev_env;
+            self.call_stack.truncate(prev_depth);
+            res
+        })
+    }
+
+    /// Switch to the scope for a call to a Nightbug function,
+    /// returning the function's compiled body.
+    fn enter_function(
+        &mut self,
+        function: &Function,
+        args: Vec<Value>,
+        call_span: Range<usize>
+    ) -> Result<Rc<Chunk>, InterpreterError> {
+        if self.call_stack.len() >= self.recursion_limit {
+            return Err(self.stack_overflow(call_span));
+        }
+
+        self.collect_if_needed();
+
+        self.call_stack.push(CallFrame {
+            function: function.name,
+            span: call_span
+        });
+
+        self.env = Environment::call(
+            &function.env,
+            function.name,
+            Rc::clone(&function.params),
+            args
+        );
+
+        let chunk = function
+            .chunk
+            .get_or_init(|| Rc::new(compile::compile_function(&function.body)));
+        Ok(Rc::clone(chunk))
+    }
+
+    /// Check a function before its arguments are evaluated,
+    /// like the tree-walking interpreter does.
+    fn prepare_call(&mut self, func: &Value, site: &CallSite) -> Result<(), InterpreterError> {
+        let argc = site.arg_spans.len();
+
+        match func {
+            Value::Function(function) if function.params.len() != argc => Err(self.wrong_num_args(
+                site.ident,
+                site.name_span.clone(),
+                function.params.len(),
+                &site.arg_spans
+            )),
+
+            Value::NativeFunction(native) => match native.num_arguments {
+                Some(expected) if expected != argc => {
+                    self.build_error(&format!(
+                        "wrong number of arguments for function (expected {}, got {})",
+                        expected, argc
+                    ))
+                    .span_label(
+                        site.name_span.clone(),
+                        &format!("expected {} arguments", expected)
+                    )
+                    .note(&format!(
+                        "cannot show definition for {} because it is a built-in function",
+                        site.ident
+                    ))
+                    .emit();
+
+                    Err(InterpreterError::WrongNumArgs {
+                        ident: site.ident.to_string(),
+                        expected,
+                        got: argc
+                    })
+                },
+                _ => Ok(())
+            },
+
+            Value::Function(_) => Ok(()),
+
+            _ if argc > 0 => {
+                self.build_error("tried to call a value that is not a function")
+                    .span_label(site.name_span.clone(), "this is not a function")
+                    .emit();
+                Err(InterpreterError::NotAFunction(func.clone()))
+            },
+
+            _ => Ok(())
+        }
+    }
+
+    /// Call a function which `prepare_call` has already checked.
+    fn call(
+        &mut self,
+        func: Value,
+        args: Vec<Value>,
+        site: &CallSite
+    ) -> Result<Called, InterpreterError> {
+        match func {
+            Value::Function(function) => self
+                .enter_function(&function, args, site.span.clone())
+                .map(Called::Enter),
+
+            Value::NativeFunction(native) => self
+                .call_native(&native, args, site.span.clone(), site.arg_spans.clone())
+                .map(Called::Value),
+
+            // `(x)` is the same as `x`
+            _ => Ok(Called::Value(func))
+        }
+    }
+
+    /// Run a chunk until it returns, starting in the current scope.
+    /// `caller_env` is the scope to go back to afterwards.
+    fn run(&mut self, chunk: Rc<Chunk>, caller_env: Rc<Environment>) -> InterpResult {
+        let mut frames = vec![Frame {
+            chunk,
+            ip: 0,
+            caller_env
+        }];
+        let mut stack: Vec<Value> = Vec::new();
+
+        loop {
+            // nb. the loop returns once the last frame does
+            let frame = frames.last_mut().unwrap();
+            let chunk = Rc::clone(&frame.chunk);
+            let ip = frame.ip;
+            frame.ip += 1;
+
+            let span = || chunk.spans[ip].clone();
+
+            match chunk.code[ip] {
+                Op::Constant(idx) => stack.push(chunk.constants[idx].clone()),
+                Op::Argument(idx) => stack.push(self.handle_argument(idx, span())?),
+                Op::Lookup(ident) => stack.push(self.handle_identifier(ident, span())?),
+
+                Op::Define(name) => {
+                    let value = stack.pop().unwrap();
+                    self.env.define(name, value);
+                    stack.push(Value::Unit);
+                },
+
+                Op::Bind(name) => {
+                    let value = stack.pop().unwrap();
+                    self.env.define(name, value);
+                },
+
+                Op::Set(name) => {
+                    let value = stack.pop().unwrap();
+                    self.set_identifier(name, value, span())?;
+                    stack.push(Value::Unit);
+                },
+
+                Op::SetArgument(idx) => {
+                    let value = stack.pop().unwrap();
+                    self.set_argument(idx, value, span())?;
+                    stack.push(Value::Unit);
+                },
+
+                Op::Pop => {
+                    stack.pop();
+                },
+
+                Op::Branch {
+                    keyword,
+                    jump_if,
+                    target
+                } => {
+                    let condition = stack.pop().unwrap();
+
+                    if self.check_condition(keyword.as_str(), condition, span())? == jump_if {
+                        frames.last_mut().unwrap().ip = target;
+                    }
+                },
+
+                Op::Jump(target) => frames.last_mut().unwrap().ip = target,
+
+                Op::Closure(idx) => {
+                    let template = &chunk.functions[idx];
+                    let function = Function::compiled(template, Rc::clone(&self.env));
+                    stack.push(Value::Function(function));
+                },
+
+                Op::PushScope(keyword) => {
+                    self.env = Environment::child(&self.env, ScopeKind::Let(keyword.as_str()));
+                },
+
+                Op::PopScopes(count) => {
+                    for _ in 0..count {
+                        // nb. the compiler only pops scopes which it pushed
+                        let parent = Rc::clone(self.env.parent().unwrap());
+                        self.env = parent;
+                    }
+                },
+
+                Op::PrepareCall(idx) => {
+                    let site = &chunk.calls[idx];
+                    self.prepare_call(&stack[stack.len() - 1], site)?;
+                },
+
+                Op::Call(idx) => {
+                    let site = &chunk.calls[idx];
+                    let args = stack.split_off(stack.len() - site.arg_spans.len());
+                    let func = stack.pop().unwrap();
+                    let caller_env = Rc::clone(&self.env);
+
+                    match self.call(func, args, site)? {
+                        Called::Value(res) => stack.push(res),
+                        Called::Enter(chunk) => frames.push(Frame {
+                            chunk,
+                            ip: 0,
+                            caller_env
+                        })
+                    }
+                },
+
+                Op::TailCall(idx) => {
+                    let site = &chunk.calls[idx];
+                    let args = stack.split_off(stack.len() - site.arg_spans.len());
+                    let func = stack.pop().unwrap();
+                    // The current call is replaced, so it doesn't need a frame
+                    // in the call stack any more once the new one is pushed
+                    let depth = self.call_stack.len();
+
+                    match self.call(func, args, site)? {
+                        Called::Value(res) => stack.push(res),
+                        Called::Enter(chunk) => {
+                            self.call_stack.remove(depth - 1);
+                            let frame = frames.last_mut().unwrap();
+                            frame.chunk = chunk;
+                            frame.ip = 0;
+                        }
+                    }
+                },
+
+                Op::MakeMap(len) => {
+                    let items = stack.split_off(stack.len() - len);
+                    stack.push(Value::map_from_items(items));
+                },
+
+                Op::MakeSet(len) => {
+                    let items = stack.split_off(stack.len() - len);
+                    stack.push(Value::set(items.into_iter().collect()));
+                },
+
+                Op::Return => {
+                    // nb. every chunk leaves exactly one value for `Return`
+                    let res = stack.pop().unwrap();
+                    let frame = frames.pop().unwrap();
+                    self.env = frame.caller_env;
+
+                    if frames.is_empty() {
+                        return Ok(res);
+                    }
+
+                    self.call_stack.pop();
+                    stack.push(res);
+                },
+
+                Op::Interpret(idx) => {
+                    let res = self.interpret_expr(chunk.exprs[idx].clone())?;
+                    stack.push(res);
+                }
+            }
+        }
+    }
+}
@@ -1,41 +1,98 @@
+use num_bigint::{BigInt, ParseBigIntError};
+use num_rational::BigRational;
+use num_traits::Zero;
 use std::{
+    convert::TryFrom,
     iter::{Enumerate, Peekable},
-    num::ParseIntError,
+    num::ParseFloatError,
     ops::Range,
     str::Chars
 };
 use thiserror::Error;
 
-use crate::errors::DiagnosticsContext;
+use crate::{errors::DiagnosticsContext, symbol::Symbol};
 
 type CharStream<'a> = Peekable<Enumerate<Chars<'a>>>;
 
+/// Characters which can't appear in identifiers because
+/// they have (or are reserved for) a special meaning.
+const DELIMITERS: &[char] = &[
+    '(', ')', '[', ']', '{', '}', '"', '\'', '`', ',', ';', '#', '|'
+];
+
+/// Symbols which can appear anywhere in an identifier, alongside letters.
+const IDENT_SYMBOLS: &[char] = &[
+    '!', '$', '%', '&', '*', '+', '-', '.', '/', ':', '<', '=', '>', '?', '^', '_', '~'
+];
+
+/// Checks if a character can start an identifier
+/// (ex. "foo", "+", "<=", "null?", "λ").
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || IDENT_SYMBOLS.contains(&c)
+}
+
+/// Checks if a character can appear after the start of an identifier
+/// (ex. "list->vector", "x1").
+fn is_ident_continue(c: char) -> bool {
+    !c.is_whitespace() && !DELIMITERS.contains(&c)
+}
+
 /// Signals error encountered during lexing.
 #[derive(Debug, Error)]
 pub enum LexError {
     #[error("Unexpected character {0}")]
     UnexpectedChar(char, usize),
     #[error("Failed to parse {0}")]
-    CouldntParseInt(String, #[source] ParseIntError)
+    CouldntParseInt(String, #[source] ParseBigIntError),
+    #[error("Failed to parse {0}")]
+    CouldntParseFloat(String, #[source] ParseFloatError),
+    #[error("Unterminated string starting at character {0}")]
+    UnterminatedString(usize),
+    #[error("Invalid escape sequence {0} at character {1}")]
+    InvalidEscape(String, usize),
+    #[error("Zero denominator in {0} at character {1}")]
+    ZeroDenominator(String, usize)
 }
 
 /// Distinguishes between `Token`s.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum TokenKind {
-    /// Identifier or keyword ("foo", "define", "true")
-    IdentOrKeyword(String),
-    /// Integer ("4", "535325", "0")
+    /// Identifier or keyword ("foo", "define", "true", "+", "null?")
+    IdentOrKeyword(Symbol),
+    /// Integer ("4", "-535325", "0")
     Integer(i32),
+    /// Integer too large to fit in an `i32` ("4294967296")
+    BigInteger(BigInt),
+    /// Exact fraction, already in lowest terms ("1/3", "-22/7")
+    Rational(BigRational),
+    /// Float ("3.14", "-0.5", "1e-9", "6.02E23")
+    Float(f64),
+    /// String, with any escape sequences already processed ("\"hello\\n\"")
+    String(String),
     /// Open parenthesis ("(")
     OpenParen,
     /// Close parenthesis (")")
     CloseParen,
+    /// Open brace, which starts a map ("{")
+    OpenBrace,
+    /// Open brace after a hash, which starts a set ("#{")
+    OpenSetBrace,
+    /// Close brace, which ends a map or set ("}")
+    CloseBrace,
+    /// Quote ("'")
+    Quote,
+    /// Quasiquote ("`")
+    Quasiquote,
+    /// Unquote (",")
+    Unquote,
+    /// Unquote-splicing (",@")
+    UnquoteSplicing,
     /// Internally used for whitespace (" ")
     Whitespace
 }
 
 /// A lexical token read from a source stream.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub struct Token {
     /// Indicates the region in the source code
     /// which this token corresponds to.
@@ -84,18 +141,13 @@ impl<'src> Lexer<'src> {
         let span_c = idx..idx + 1;
 
         match c {
-            'A'..='Z' | 'a'..='z' | '_' => {
-                // TODO: be more permissive w/ identifiers
-                Ok(Some(self.consume_ident()))
-            },
-
-            '0'..='9' => {
-                // TODO: negative integers
-                match self.consume_integer() {
-                    Ok(res) => Ok(Some(res)),
-                    Err((num_str, err)) => Err(LexError::CouldntParseInt(num_str, err))
-                }
+            '0'..='9' => self.consume_number().map(Some),
+            // "-5" is a number, but "-" and "-x" are identifiers
+            '+' | '-' if matches!(self.peek_second(), Some('0'..='9')) => {
+                self.consume_number().map(Some)
             },
+            c if is_ident_start(c) => Ok(Some(self.consume_ident())),
+            '"' => self.consume_string().map(Some),
 
             '(' => {
                 self.chars.next();
@@ -105,7 +157,38 @@ impl<'src> Lexer<'src> {
                 self.chars.next();
                 ok_some_token!(span_c, TokenKind::CloseParen)
             },
-            ' ' | '\t' | '\n' | '\r' => {
+            '{' => {
+                self.chars.next();
+                ok_some_token!(span_c, TokenKind::OpenBrace)
+            },
+            '#' if self.peek_second() == Some('{') => {
+                self.chars.next();
+                self.chars.next();
+                ok_some_token!(idx..idx + 2, TokenKind::OpenSetBrace)
+            },
+            '}' => {
+                self.chars.next();
+                ok_some_token!(span_c, TokenKind::CloseBrace)
+            },
+            '\'' => {
+                self.chars.next();
+                ok_some_token!(span_c, TokenKind::Quote)
+            },
+            '`' => {
+                self.chars.next();
+                ok_some_token!(span_c, TokenKind::Quasiquote)
+            },
+            ',' => {
+                self.chars.next();
+
+                if let Some((_, '@')) = self.chars.peek() {
+                    self.chars.next();
+                    ok_some_token!(idx..idx + 2, TokenKind::UnquoteSplicing)
+                } else {
+                    ok_some_token!(span_c, TokenKind::Unquote)
+                }
+            },
+            c if c.is_whitespace() => {
                 self.chars.next();
                 ok_some_token!(span_c, TokenKind::Whitespace)
             },
@@ -118,52 +201,271 @@ impl<'src> Lexer<'src> {
         }
     }
 
+    /// Look at the character after the next one without consuming anything.
+    fn peek_second(&self) -> Option<char> {
+        let mut lookahead = self.chars.clone();
+        lookahead.next();
+        lookahead.next().map(|(_, c)| c)
+    }
+
     /// Take every character that could be considered part of an identifier
     /// and produce an `IdentOrKeyword` token.
     fn consume_ident(&mut self) -> Token {
         let mut res = String::new();
         let start = self.chars.peek().unwrap().0;
+        let mut end = start;
 
-        while let Some((_, c)) = self.chars.peek() {
-            // This if statement is seperated from the while statement
-            // for readability purposes
-            // TODO: be more permissive
-            if matches!(c, 'A'..='Z' | 'a'..='z' | '_' | '0'..='9') {
-                // nb. we are using source.peek() above
-                res.push(self.chars.next().unwrap().1);
+        while let Some(&(idx, c)) = self.chars.peek() {
+            if is_ident_continue(c) {
+                res.push(c);
+                end = idx + 1;
+                self.chars.next();
             } else {
                 break;
             }
         }
 
-        Token::new(start..start + res.len(), TokenKind::IdentOrKeyword(res))
+        Token::new(start..end, TokenKind::IdentOrKeyword(Symbol::intern(&res)))
+    }
+
+    /// Take every character up to the closing quote of a string literal
+    /// and produce a `String` token.
+    fn consume_string(&mut self) -> Result<Token, LexError> {
+        // nb. the opening quote has only been peeked
+        let start = self.chars.next().unwrap().0;
+        let mut res = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some((idx, '"')) => return Ok(Token::new(start..idx + 1, TokenKind::String(res))),
+                Some((idx, '\\')) => res.push(self.consume_escape(start, idx)?),
+                Some((_, c)) => res.push(c),
+                None => return Err(self.unterminated_string(start))
+            }
+        }
+    }
+
+    /// Emit an error for a string literal which is never closed.
+    fn unterminated_string(&self, start: usize) -> LexError {
+        self.error_ctx
+            .build_error("unterminated string literal")
+            .span_label(start..start + 1, "this string is never closed")
+            .emit();
+        LexError::UnterminatedString(start)
+    }
+
+    /// Take the rest of an escape sequence after its backslash,
+    /// producing the character it represents.
+    fn consume_escape(&mut self, string_start: usize, backslash: usize) -> Result<char, LexError> {
+        let c = match self.chars.next() {
+            Some((_, c)) => c,
+            None => return Err(self.unterminated_string(string_start))
+        };
+
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '"' => Ok('"'),
+            '\\' => Ok('\\'),
+            'u' => self.consume_unicode_escape(string_start, backslash),
+
+            _ => {
+                self.error_ctx
+                    .build_error(&format!("unknown escape sequence `\\{}`", c))
+                    .span_label(backslash..backslash + 2, "unknown escape sequence")
+                    .help("valid escape sequences are `\\n`, `\\t`, `\\r`, `\\0`, `\\\"`, `\\\\`, and `\\u{...}`")
+                    .emit();
+                Err(LexError::InvalidEscape(format!("\\{}", c), backslash))
+            }
+        }
+    }
+
+    /// Take the rest of a unicode escape sequence (ex. `\u{1F41B}`)
+    /// after its `u`, producing the character it represents.
+    fn consume_unicode_escape(
+        &mut self,
+        string_start: usize,
+        backslash: usize
+    ) -> Result<char, LexError> {
+        let mut digits = String::new();
+        let mut end = backslash + 2;
+
+        let invalid = |lexer: &Self, digits: &str, end: usize, label: &str| {
+            lexer
+                .error_ctx
+                .build_error("invalid unicode escape sequence")
+                .span_label(backslash..end, label)
+                .help("unicode escape sequences look like `\\u{1F41B}`")
+                .emit();
+            LexError::InvalidEscape(format!("\\u{{{}}}", digits), backslash)
+        };
+
+        match self.chars.peek() {
+            Some(&(idx, '{')) => {
+                self.chars.next();
+                end = idx + 1;
+            },
+            Some(_) => return Err(invalid(self, &digits, end, "expected `{` after `\\u`")),
+            None => return Err(self.unterminated_string(string_start))
+        }
+
+        loop {
+            match self.chars.peek() {
+                Some(&(idx, '}')) => {
+                    self.chars.next();
+                    end = idx + 1;
+                    break;
+                },
+
+                Some(&(idx, c)) if c.is_ascii_hexdigit() && digits.len() < 6 => {
+                    self.chars.next();
+                    digits.push(c);
+                    end = idx + 1;
+                },
+
+                Some(_) => {
+                    return Err(invalid(
+                        self,
+                        &digits,
+                        end,
+                        "expected up to 6 hexadecimal digits followed by `}`"
+                    ))
+                },
+
+                None => return Err(self.unterminated_string(string_start))
+            }
+        }
+
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| invalid(self, &digits, end, "not a valid unicode scalar value"))
     }
 
-    /// Take every character that could be considered part of an integer
-    /// and produce an `Integer` token.
-    /// On failure, returns a tuple including the number that failed to parse
-    /// and the error produced by the parse function.
-    fn consume_integer(&mut self) -> Result<Token, (String, ParseIntError)> {
+    /// Take every character that could be considered part of a number
+    /// and produce an `Integer`, `BigInteger`, `Rational` or `Float` token.
+    /// Numbers with a fractional part (ex. "1.5") or an exponent
+    /// (ex. "1e-9") are floats, and two integers separated by a slash
+    /// (ex. "1/3") are rationals.
+    fn consume_number(&mut self) -> Result<Token, LexError> {
         let start = self.chars.peek().unwrap().0;
         let mut num_str = String::new();
+        let mut is_float = false;
 
-        while let Some((_, '0'..='9')) = self.chars.peek() {
+        if let Some((_, '+' | '-')) = self.chars.peek() {
             num_str.push(self.chars.next().unwrap().1);
         }
 
-        num_str
-            .parse::<i32>()
-            .map(|res| Token::new(start..start + num_str.len(), TokenKind::Integer(res)))
-            .or_else(|err| {
-                self.error_ctx
-                    .build_ice_span(
-                        start..start + num_str.len(),
-                        &format!("could not parse {} into an integer", num_str)
-                    )
-                    .note(&format!("str::parse::<i32> says: {}", err))
-                    .emit();
-                Err((num_str, err))
-            })
+        self.consume_digits(&mut num_str);
+
+        let is_slash = matches!(self.chars.peek(), Some((_, '/')));
+        if is_slash && matches!(self.peek_second(), Some('0'..='9')) {
+            return self.consume_rational(start, num_str);
+        }
+
+        // "1." is the integer 1 followed by the identifier "."
+        let is_point = matches!(self.chars.peek(), Some((_, '.')));
+        if is_point && matches!(self.peek_second(), Some('0'..='9')) {
+            is_float = true;
+            num_str.push(self.chars.next().unwrap().1);
+            self.consume_digits(&mut num_str);
+        }
+
+        if let Some((_, 'e' | 'E')) = self.chars.peek() {
+            let mut lookahead = self.chars.clone();
+            lookahead.next();
+            let exponent_follows = match lookahead.next() {
+                Some((_, '0'..='9')) => true,
+                Some((_, '+' | '-')) => matches!(lookahead.next(), Some((_, '0'..='9'))),
+                _ => false
+            };
+
+            if exponent_follows {
+                is_float = true;
+                num_str.push(self.chars.next().unwrap().1);
+
+                if let Some((_, '+' | '-')) = self.chars.peek() {
+                    num_str.push(self.chars.next().unwrap().1);
+                }
+
+                self.consume_digits(&mut num_str);
+            }
+        }
+
+        let span = start..start + num_str.len();
+
+        if is_float {
+            num_str
+                .parse::<f64>()
+                .map(|res| Token::new(span.clone(), TokenKind::Float(res)))
+                .map_err(|err| {
+                    self.error_ctx
+                        .build_ice_span(span, &format!("could not parse {} into a float", num_str))
+                        .note(&format!("str::parse::<f64> says: {}", err))
+                        .emit();
+                    LexError::CouldntParseFloat(num_str.clone(), err)
+                })
+        } else {
+            let res = self.parse_integer(&num_str, start)?;
+
+            match i32::try_from(&res) {
+                Ok(res) => Ok(Token::new(span, TokenKind::Integer(res))),
+                Err(_) => Ok(Token::new(span, TokenKind::BigInteger(res)))
+            }
+        }
+    }
+
+    /// Take the rest of a rational after its numerator
+    /// and produce a `Rational` token.
+    fn consume_rational(&mut self, start: usize, numer_str: String) -> Result<Token, LexError> {
+        // nb. the slash has only been peeked
+        let slash = self.chars.next().unwrap().0;
+        let mut denom_str = String::new();
+        self.consume_digits(&mut denom_str);
+
+        let span = start..slash + 1 + denom_str.len();
+        let text = format!("{}/{}", numer_str, denom_str);
+        let numer = self.parse_integer(&numer_str, start)?;
+        let denom = self.parse_integer(&denom_str, slash + 1)?;
+
+        if denom.is_zero() {
+            self.error_ctx
+                .build_error(&format!(
+                    "rational literal `{}` has a zero denominator",
+                    text
+                ))
+                .span_label(slash + 1..span.end, "this denominator is zero")
+                .emit();
+            return Err(LexError::ZeroDenominator(text, start));
+        }
+
+        Ok(Token::new(
+            span,
+            TokenKind::Rational(BigRational::new(numer, denom))
+        ))
+    }
+
+    /// Parse a string of digits starting at `start` into an integer.
+    fn parse_integer(&self, num_str: &str, start: usize) -> Result<BigInt, LexError> {
+        num_str.parse::<BigInt>().map_err(|err| {
+            self.error_ctx
+                .build_ice_span(
+                    start..start + num_str.len(),
+                    &format!("could not parse {} into an integer", num_str)
+                )
+                .note(&format!("str::parse::<BigInt> says: {}", err))
+                .emit();
+            LexError::CouldntParseInt(num_str.to_string(), err)
+        })
+    }
+
+    /// Take every decimal digit at the start of the stream into `num_str`.
+    fn consume_digits(&mut self, num_str: &mut String) {
+        while let Some((_, '0'..='9')) = self.chars.peek() {
+            num_str.push(self.chars.next().unwrap().1);
+        }
     }
 }
 
@@ -180,3 +482,196 @@ pub fn lex(source: &str) -> Result<Vec<Token>, LexError> {
 
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        lex(source)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    fn ident(s: &str) -> TokenKind {
+        TokenKind::IdentOrKeyword(Symbol::intern(s))
+    }
+
+    #[test]
+    fn operator_identifiers() {
+        assert_eq!(
+            kinds("+ - * / <= >= = list->vector null? set!"),
+            vec![
+                ident("+"),
+                ident("-"),
+                ident("*"),
+                ident("/"),
+                ident("<="),
+                ident(">="),
+                ident("="),
+                ident("list->vector"),
+                ident("null?"),
+                ident("set!")
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let tokens = lex("(λ über)").unwrap();
+        assert_eq!(tokens[1].kind, ident("λ"));
+        assert_eq!(tokens[2].kind, ident("über"));
+        assert_eq!(tokens[2].span, 3..7);
+    }
+
+    #[test]
+    fn signed_integers() {
+        assert_eq!(
+            kinds("-5 +3 - -x 1-"),
+            vec![
+                TokenKind::Integer(-5),
+                TokenKind::Integer(3),
+                ident("-"),
+                ident("-x"),
+                TokenKind::Integer(1),
+                ident("-")
+            ]
+        );
+        assert_eq!(kinds("-2147483648"), vec![TokenKind::Integer(i32::MIN)]);
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(
+            kinds("2.75 -0.5 1e-9 6.02E23 +2e3 1. 1e x1.5"),
+            vec![
+                TokenKind::Float(2.75),
+                TokenKind::Float(-0.5),
+                TokenKind::Float(1e-9),
+                TokenKind::Float(6.02e23),
+                TokenKind::Float(2e3),
+                TokenKind::Integer(1),
+                ident("."),
+                TokenKind::Integer(1),
+                ident("e"),
+                ident("x1.5")
+            ]
+        );
+        assert_eq!(lex("(f 1.25)").unwrap()[2].span, 3..7);
+    }
+
+    #[test]
+    fn big_integers() {
+        assert_eq!(
+            kinds("2147483648 -99999999999999999999"),
+            vec![
+                TokenKind::BigInteger(BigInt::from(2147483648u32)),
+                TokenKind::BigInteger("-99999999999999999999".parse().unwrap())
+            ]
+        );
+    }
+
+    #[test]
+    fn rationals() {
+        let ratio = |numer: i32, denom: i32| {
+            TokenKind::Rational(BigRational::new(numer.into(), denom.into()))
+        };
+
+        assert_eq!(
+            kinds("1/3 -6/4 1/x"),
+            vec![
+                ratio(1, 3),
+                ratio(-3, 2),
+                TokenKind::Integer(1),
+                ident("/x")
+            ]
+        );
+        assert_eq!(lex("(f 10/20)").unwrap()[2].span, 3..8);
+        assert!(matches!(lex("1/0"), Err(LexError::ZeroDenominator(..))));
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            kinds("'a `(b ,c ,@d)"),
+            vec![
+                TokenKind::Quote,
+                ident("a"),
+                TokenKind::Quasiquote,
+                TokenKind::OpenParen,
+                ident("b"),
+                TokenKind::Unquote,
+                ident("c"),
+                TokenKind::UnquoteSplicing,
+                ident("d"),
+                TokenKind::CloseParen
+            ]
+        );
+        assert_eq!(lex("x ,@y").unwrap()[1].span, 2..4);
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(
+            kinds("{a 1} #{b}"),
+            vec![
+                TokenKind::OpenBrace,
+                ident("a"),
+                TokenKind::Integer(1),
+                TokenKind::CloseBrace,
+                TokenKind::OpenSetBrace,
+                ident("b"),
+                TokenKind::CloseBrace
+            ]
+        );
+        assert_eq!(lex("x #{}").unwrap()[1].span, 2..4);
+        assert!(matches!(lex("# {}"), Err(LexError::UnexpectedChar('#', 0))));
+    }
+
+    #[test]
+    fn strings() {
+        let tokens = lex(r#"(concat "a b" "")"#).unwrap();
+        assert_eq!(tokens[2].kind, TokenKind::String("a b".to_string()));
+        assert_eq!(tokens[2].span, 8..13);
+        assert_eq!(tokens[3].kind, TokenKind::String(String::new()));
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(
+            kinds(r#""\n\t\"\\\u{1F41B}\u{e9}""#),
+            vec![TokenKind::String("\n\t\"\\\u{1F41B}\u{e9}".to_string())]
+        );
+    }
+
+    #[test]
+    fn bad_strings() {
+        assert!(matches!(
+            lex(r#"(concat "abc)"#),
+            Err(LexError::UnterminatedString(8))
+        ));
+        assert!(matches!(lex(r#""\q""#), Err(LexError::InvalidEscape(_, 1))));
+        assert!(matches!(
+            lex(r#""\u{110000}""#),
+            Err(LexError::InvalidEscape(..))
+        ));
+        assert!(matches!(
+            lex(r#""\u1234""#),
+            Err(LexError::InvalidEscape(..))
+        ));
+        assert!(matches!(
+            lex(r#""abc\"#),
+            Err(LexError::UnterminatedString(0))
+        ));
+    }
+
+    #[test]
+    fn delimiters_end_identifiers() {
+        assert_eq!(
+            kinds("(foo)"),
+            vec![TokenKind::OpenParen, ident("foo"), TokenKind::CloseParen]
+        );
+    }
+}
@@ -7,6 +7,7 @@ pub mod errors;
 pub mod interpreter;
 pub mod lexer;
 pub mod parser;
+pub mod symbol;
 
 fn main() {
     let code = "(add 2 (second 3 4))";
@@ -28,8 +29,8 @@ fn main() {
     println!();
 
     let mut interpreter = interpreter::Interpreter::new();
-    println!(
-        "Result: {:?}",
-        interpreter.interpret_with_source(expressions, code)
-    );
+    // nb. errors have already been reported by the interpreter
+    if let Ok(res) = interpreter.interpret_with_source(expressions, code) {
+        println!("Result: {}", res);
+    }
 }
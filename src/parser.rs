@@ -1,9 +1,12 @@
-use std::ops::Range;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use std::{convert::TryFrom, fmt, ops::Range, rc::Rc};
 use thiserror::Error;
 
 use crate::{
     errors::DiagnosticsContext,
-    lexer::{Token, TokenKind}
+    lexer::{Token, TokenKind},
+    symbol::Symbol
 };
 
 #[derive(Debug, Error)]
@@ -11,7 +14,11 @@ pub enum ParseError {
     #[error("Unclosed delimiter at character {location}")]
     UnclosedDelimiter { location: usize, eof: usize },
     #[error("Unexpected closing delimiter at character {0}")]
-    UnexpectedCloseDelimiter(usize)
+    UnexpectedCloseDelimiter(usize),
+    #[error("Nothing to quote after character {0}")]
+    NothingQuoted(usize),
+    #[error("Map key without a value at character {0}")]
+    MissingMapValue(usize)
 }
 
 /// A keyword
@@ -19,18 +26,93 @@ pub enum ParseError {
 pub enum Keyword {
     /// Create a binding
     Define,
+    /// Change the value of an existing binding
+    Set,
     /// Declare a function
-    Fn
+    Fn,
+    /// Bind values in a new scope
+    Let,
+    /// Bind values in a new scope, one after another
+    LetStar,
+    /// Bind values in a new scope which they can all refer to
+    LetRec,
+    /// Choose between two expressions
+    If,
+    /// Choose between any number of expressions
+    Cond,
+    /// The fallback case of a `cond` expression
+    Else,
+    /// Evaluate expressions if a condition is true
+    When,
+    /// Evaluate expressions if a condition is false
+    Unless,
+    /// Short-circuiting logical and
+    And,
+    /// Short-circuiting logical or
+    Or,
+    /// Use an expression as data without evaluating it
+    Quote,
+    /// Quote an expression, except for any parts which are unquoted
+    Quasiquote,
+    /// Evaluate part of a quasiquoted expression
+    Unquote,
+    /// Evaluate part of a quasiquoted list, inserting the items of the result
+    UnquoteSplicing,
+    /// Define a macro, which rewrites code before it is evaluated
+    DefMacro,
+    /// Expand a macro call given as data
+    MacroExpand,
+    /// Define a hygienic macro
+    DefineSyntax,
+    /// The pattern-matching rules of a hygienic macro
+    SyntaxRules
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+impl Keyword {
+    /// The keyword as it appears in source code
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Keyword::Define => "define",
+            Keyword::Set => "set!",
+            Keyword::Fn => "fn",
+            Keyword::Let => "let",
+            Keyword::LetStar => "let*",
+            Keyword::LetRec => "letrec",
+            Keyword::If => "if",
+            Keyword::Cond => "cond",
+            Keyword::Else => "else",
+            Keyword::When => "when",
+            Keyword::Unless => "unless",
+            Keyword::And => "and",
+            Keyword::Or => "or",
+            Keyword::Quote => "quote",
+            Keyword::Quasiquote => "quasiquote",
+            Keyword::Unquote => "unquote",
+            Keyword::UnquoteSplicing => "unquote-splicing",
+            Keyword::DefMacro => "defmacro",
+            Keyword::MacroExpand => "macroexpand",
+            Keyword::DefineSyntax => "define-syntax",
+            Keyword::SyntaxRules => "syntax-rules"
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ExprKind {
     /// eg. "define"
     Keyword(Keyword),
     /// eg. "foo"
-    Identifier(String),
+    Identifier(Symbol),
     /// eg. "456"
     Integer(i32),
+    /// An integer too large for `Integer` (eg. "4294967296")
+    BigInteger(BigInt),
+    /// An exact fraction which isn't an integer (eg. "1/3")
+    Rational(BigRational),
+    /// eg. "3.14", "1e-9"
+    Float(f64),
+    /// eg. "\"hello\""
+    String(String),
     /// eg. "false"
     Boolean(bool),
     /// ()
@@ -38,11 +120,16 @@ pub enum ExprKind {
     /// Used internally for functions
     Argument(usize),
     /// S-expression (eg. "(add 2 2)")
-    List(Vec<Expr>)
+    List(Rc<[Expr]>),
+    /// Map literal, alternating between keys and values (eg. "{a 1 b 2}").
+    /// The parser makes sure every key has a value.
+    Map(Rc<[Expr]>),
+    /// Set literal (eg. "#{1 2 3}")
+    Set(Rc<[Expr]>)
 }
 
 /// An expression
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Expr {
     pub span: Range<usize>,
     pub kind: ExprKind
@@ -59,7 +146,7 @@ impl Expr {
     }
 
     /// Convenience function to create an identifier expression
-    pub fn identifier(span: Range<usize>, ident: String) -> Self {
+    pub fn identifier(span: Range<usize>, ident: Symbol) -> Self {
         Self::new(span, ExprKind::Identifier(ident))
     }
 
@@ -68,6 +155,35 @@ impl Expr {
         Self::new(span, ExprKind::Integer(num))
     }
 
+    /// Convenience function to create an exact integer expression,
+    /// which is an `Integer` if it fits and a `BigInteger` otherwise
+    pub fn big_integer(span: Range<usize>, num: BigInt) -> Self {
+        match i32::try_from(&num) {
+            Ok(num) => Self::integer(span, num),
+            Err(_) => Self::new(span, ExprKind::BigInteger(num))
+        }
+    }
+
+    /// Convenience function to create an exact number expression,
+    /// which is only a `Rational` if it isn't an integer
+    pub fn rational(span: Range<usize>, num: BigRational) -> Self {
+        if num.is_integer() {
+            Self::big_integer(span, num.to_integer())
+        } else {
+            Self::new(span, ExprKind::Rational(num))
+        }
+    }
+
+    /// Convenience function to create a float expression
+    pub fn float(span: Range<usize>, num: f64) -> Self {
+        Self::new(span, ExprKind::Float(num))
+    }
+
+    /// Convenience function to create a string expression
+    pub fn string(span: Range<usize>, s: String) -> Self {
+        Self::new(span, ExprKind::String(s))
+    }
+
     /// Convenience function to create a boolean expression
     pub fn boolean(span: Range<usize>, b: bool) -> Self {
         Self::new(span, ExprKind::Boolean(b))
@@ -85,16 +201,51 @@ impl Expr {
 
     /// Convenience function to create a list expression
     pub fn list(span: Range<usize>, exprs: Vec<Expr>) -> Self {
-        Self::new(span, ExprKind::List(exprs))
+        Self::new(span, ExprKind::List(exprs.into()))
+    }
+
+    /// Convenience function to create a map literal expression,
+    /// from keys and values which alternate
+    pub fn map(span: Range<usize>, exprs: Vec<Expr>) -> Self {
+        Self::new(span, ExprKind::Map(exprs.into()))
+    }
+
+    /// Convenience function to create a set literal expression
+    pub fn set(span: Range<usize>, exprs: Vec<Expr>) -> Self {
+        Self::new(span, ExprKind::Set(exprs.into()))
     }
 
     /// Converts a string slice into an expression,
     /// ex. "define" becomes a keyword, "false" becomes a boolean,
     /// and "foobar" becomes an identifier.
-    fn ident_to_expr(span: Range<usize>, ident: String) -> Self {
+    pub fn ident_to_expr(span: Range<usize>, ident: Symbol) -> Self {
+        // Gensyms are never keywords, and their names aren't stored
+        if ident.is_gensym() {
+            return Self::identifier(span, ident);
+        }
+
         match ident.as_str() {
             "define" => Self::keyword(span, Keyword::Define),
+            "set!" => Self::keyword(span, Keyword::Set),
             "fn" => Self::keyword(span, Keyword::Fn),
+            "let" => Self::keyword(span, Keyword::Let),
+            "let*" => Self::keyword(span, Keyword::LetStar),
+            "letrec" => Self::keyword(span, Keyword::LetRec),
+            "if" => Self::keyword(span, Keyword::If),
+            "cond" => Self::keyword(span, Keyword::Cond),
+            "else" => Self::keyword(span, Keyword::Else),
+            "when" => Self::keyword(span, Keyword::When),
+            "unless" => Self::keyword(span, Keyword::Unless),
+            "and" => Self::keyword(span, Keyword::And),
+            "or" => Self::keyword(span, Keyword::Or),
+            "quote" => Self::keyword(span, Keyword::Quote),
+            "quasiquote" => Self::keyword(span, Keyword::Quasiquote),
+            "unquote" => Self::keyword(span, Keyword::Unquote),
+            "unquote-splicing" => Self::keyword(span, Keyword::UnquoteSplicing),
+            "defmacro" => Self::keyword(span, Keyword::DefMacro),
+            "macroexpand" => Self::keyword(span, Keyword::MacroExpand),
+            "define-syntax" => Self::keyword(span, Keyword::DefineSyntax),
+            "syntax-rules" => Self::keyword(span, Keyword::SyntaxRules),
             "true" => Self::boolean(span, true),
             "false" => Self::boolean(span, false),
             _ => Self::identifier(span, ident)
@@ -102,6 +253,65 @@ impl Expr {
     }
 }
 
+/// Print a string literal, escaping any characters which need it.
+pub fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            '\0' => write!(f, "\\0")?,
+            c => write!(f, "{}", c)?
+        }
+    }
+
+    write!(f, "\"")
+}
+
+/// Print expressions separated by spaces, between two delimiters.
+fn write_items(f: &mut fmt::Formatter<'_>, open: &str, exprs: &[Expr], close: &str) -> fmt::Result {
+    write!(f, "{}", open)?;
+
+    for (idx, expr) in exprs.iter().enumerate() {
+        if idx != 0 {
+            write!(f, " ")?;
+        }
+
+        write!(f, "{}", expr)?;
+    }
+
+    write!(f, "{}", close)
+}
+
+/// Prints an expression as Nightbug source code.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Keyword(keyword) => write!(f, "{}", keyword.as_str()),
+            ExprKind::Identifier(ident) => write!(f, "{}", ident),
+            ExprKind::Integer(i) => write!(f, "{}", i),
+            ExprKind::BigInteger(i) => write!(f, "{}", i),
+            ExprKind::Rational(r) => write!(f, "{}", r),
+            // nb. `Debug` always includes a decimal point or exponent,
+            // so the output is read back as a float
+            ExprKind::Float(x) => write!(f, "{:?}", x),
+            ExprKind::Boolean(b) => write!(f, "{}", b),
+            ExprKind::Unit => write!(f, "()"),
+            ExprKind::Argument(idx) => write!(f, "#<argument {}>", idx),
+
+            ExprKind::String(s) => write_string(f, s),
+
+            ExprKind::List(exprs) => write_items(f, "(", exprs, ")"),
+            ExprKind::Map(exprs) => write_items(f, "{", exprs, "}"),
+            ExprKind::Set(exprs) => write_items(f, "#{", exprs, "}")
+        }
+    }
+}
+
 struct Parser<'src> {
     // Is there a better way?
     tokens: Box<dyn Iterator<Item = Token>>,
@@ -131,77 +341,122 @@ impl<'src> Parser<'src> {
             TokenKind::IdentOrKeyword(id_or_kw) => Ok(Expr::ident_to_expr(span, id_or_kw)),
 
             TokenKind::Integer(i) => Ok(Expr::integer(span, i)),
+            TokenKind::BigInteger(i) => Ok(Expr::big_integer(span, i)),
+            TokenKind::Rational(r) => Ok(Expr::rational(span, r)),
+            TokenKind::Float(x) => Ok(Expr::float(span, x)),
+            TokenKind::String(s) => Ok(Expr::string(span, s)),
 
             TokenKind::OpenParen => {
-                let mut contents = Vec::new();
-
-                if let Some(next_token) = self.tokens.next() {
-                    if next_token.kind == TokenKind::CloseParen {
-                        return Ok(Expr::new(span.start..span.end + 1, ExprKind::Unit));
-                    } else {
-                        contents.push(self.parse_token(next_token)?);
-                    }
+                let (contents, end) = self.parse_delimited(span.clone(), TokenKind::CloseParen)?;
+
+                if contents.is_empty() {
+                    Ok(Expr::unit(span.start..end))
                 } else {
-                    // Span end is one after our token
-                    self.emit_unclosed_delimiter_err(span.start, span.end - 1);
-                    return Err(ParseError::UnclosedDelimiter {
-                        location: span.start,
-                        eof: span.end - 1
-                    });
+                    Ok(Expr::list(span.start..end, contents))
                 }
+            },
 
-                // nb. the unwrap here should be infallible --
-                // all branches in the above if expression either return or push
-                // to contents
-                let mut prev_expr_span_end = contents.last().unwrap().span.end - 1;
-
-                loop {
-                    let next_token = match self.tokens.next() {
-                        Some(next_token) => next_token,
-
-                        None => {
-                            self.emit_unclosed_delimiter_err(span.start, prev_expr_span_end);
-                            return Err(ParseError::UnclosedDelimiter {
-                                location: span.start,
-                                eof: prev_expr_span_end
-                            });
-                        }
-                    };
-
-                    if next_token.kind == TokenKind::CloseParen {
-                        break;
-                    }
-
-                    match self.parse_token(next_token) {
-                        Ok(expr) => {
-                            prev_expr_span_end = expr.span.end - 1;
-                            contents.push(expr);
-                        },
-
-                        Err(err) => {
-                            // probably already emitted an error, propagate it
-                            return Err(err);
-                        }
-                    }
+            TokenKind::OpenBrace => {
+                let (contents, end) = self.parse_delimited(span.clone(), TokenKind::CloseBrace)?;
+
+                if contents.len() % 2 != 0 {
+                    // nb. there is at least one item, since the length is odd
+                    let key_span = contents.last().unwrap().span.clone();
+                    self.error_ctx
+                        .build_error("map literal has a key without a value")
+                        .span_label(key_span.clone(), "this key has no value")
+                        .emit();
+                    return Err(ParseError::MissingMapValue(key_span.start));
                 }
 
-                Ok(Expr::new(
-                    span.start..prev_expr_span_end,
-                    ExprKind::List(contents)
-                ))
+                Ok(Expr::map(span.start..end, contents))
+            },
+
+            TokenKind::OpenSetBrace => {
+                let (contents, end) = self.parse_delimited(span.clone(), TokenKind::CloseBrace)?;
+                Ok(Expr::set(span.start..end, contents))
             },
 
             TokenKind::CloseParen => {
                 self.error_ctx
-                    .build_error_span(0..0, "unexpected closing parenthesis")
+                    .build_error_span(span.clone(), "unexpected closing parenthesis")
+                    .emit();
+                Err(ParseError::UnexpectedCloseDelimiter(span.start))
+            },
+
+            TokenKind::CloseBrace => {
+                self.error_ctx
+                    .build_error_span(span.clone(), "unexpected closing brace")
                     .emit();
-                Err(ParseError::UnexpectedCloseDelimiter(0))
+                Err(ParseError::UnexpectedCloseDelimiter(span.start))
             },
 
+            TokenKind::Quote => self.parse_quoted(span, Keyword::Quote),
+            TokenKind::Quasiquote => self.parse_quoted(span, Keyword::Quasiquote),
+            TokenKind::Unquote => self.parse_quoted(span, Keyword::Unquote),
+            TokenKind::UnquoteSplicing => self.parse_quoted(span, Keyword::UnquoteSplicing),
+
             TokenKind::Whitespace => unreachable!()
         }
     }
 
+    /// Parse expressions up to the delimiter `close`, which ends the list,
+    /// map, or set opened at `span`. Returns the expressions and where
+    /// the closing delimiter ends.
+    fn parse_delimited(
+        &mut self,
+        span: Range<usize>,
+        close: TokenKind
+    ) -> Result<(Vec<Expr>, usize), ParseError> {
+        let mut contents = Vec::new();
+        // Where the last thing before the end of the file was,
+        // in case the delimiter is never closed
+        let mut prev_end = span.end - 1;
+
+        loop {
+            let next_token = match self.tokens.next() {
+                Some(next_token) => next_token,
+
+                None => {
+                    self.emit_unclosed_delimiter_err(span.start, prev_end);
+                    return Err(ParseError::UnclosedDelimiter {
+                        location: span.start,
+                        eof: prev_end
+                    });
+                }
+            };
+
+            if next_token.kind == close {
+                return Ok((contents, next_token.span.end));
+            }
+
+            // nb. this has already emitted an error if it fails
+            let expr = self.parse_token(next_token)?;
+            prev_end = expr.span.end - 1;
+            contents.push(expr);
+        }
+    }
+
+    /// Expand reader shorthand for quoting (ex. `'x`)
+    /// into the equivalent list (ex. `(quote x)`).
+    fn parse_quoted(&mut self, span: Range<usize>, keyword: Keyword) -> Result<Expr, ParseError> {
+        let quoted = match self.parse_next()? {
+            Some(quoted) => quoted,
+            None => {
+                self.error_ctx
+                    .build_error(&format!("expected an expression to {}", keyword.as_str()))
+                    .span_label(span.clone(), "reached end of file after this")
+                    .emit();
+                return Err(ParseError::NothingQuoted(span.start));
+            }
+        };
+
+        Ok(Expr::list(
+            span.start..quoted.span.end,
+            vec![Expr::keyword(span, keyword), quoted]
+        ))
+    }
+
     /// Convenience for parsing the next token in self.tokens
     fn parse_next(&mut self) -> Result<Option<Expr>, ParseError> {
         let next_token = self.tokens.next();
@@ -249,4 +504,67 @@ mod tests {
         let res = parse(lex(code).unwrap(), code);
         assert!(matches!(res, Err(ParseError::UnclosedDelimiter { .. })));
     }
+
+    #[test]
+    fn display_round_trips() {
+        let code = r#"(define (f x) (concat "a\"\n" x -1 true ()))"#;
+        let res = parse(lex(code).unwrap(), code).unwrap();
+        assert_eq!(res[0].to_string(), code);
+    }
+
+    #[test]
+    fn quote_shorthand() {
+        let code = "'(a ,b ,@c) `d";
+        let res = parse(lex(code).unwrap(), code).unwrap();
+        assert_eq!(
+            res[0].to_string(),
+            "(quote (a (unquote b) (unquote-splicing c)))"
+        );
+        assert_eq!(res[0].span, 0..11);
+        assert_eq!(res[1].to_string(), "(quasiquote d)");
+        assert_eq!(res[1].span, 12..14);
+
+        let code = "(f ')";
+        assert!(parse(lex(code).unwrap(), code).is_err());
+        let code = "'";
+        assert!(matches!(
+            parse(lex(code).unwrap(), code),
+            Err(ParseError::NothingQuoted(0))
+        ));
+    }
+
+    #[test]
+    fn maps_and_sets() {
+        let code = "{a 1 \"b\" (f x)} #{1 {}} #{}";
+        let res = parse(lex(code).unwrap(), code).unwrap();
+        assert_eq!(res[0].to_string(), "{a 1 \"b\" (f x)}");
+        assert_eq!(res[0].span, 0..15);
+        assert_eq!(res[1].to_string(), "#{1 {}}");
+        assert_eq!(res[1].span, 16..23);
+        assert_eq!(res[2].kind, ExprKind::Set(Rc::from([])));
+
+        let code = "{a 1 b}";
+        assert!(matches!(
+            parse(lex(code).unwrap(), code),
+            Err(ParseError::MissingMapValue(5))
+        ));
+        let code = "{a 1)";
+        assert!(matches!(
+            parse(lex(code).unwrap(), code),
+            Err(ParseError::UnexpectedCloseDelimiter(4))
+        ));
+        let code = "#{a";
+        assert!(matches!(
+            parse(lex(code).unwrap(), code),
+            Err(ParseError::UnclosedDelimiter { .. })
+        ));
+    }
+
+    #[test]
+    fn list_span_includes_delimiters() {
+        let code = "(add 2 3) ( )";
+        let res = parse(lex(code).unwrap(), code).unwrap();
+        assert_eq!(res[0].span, 0..9);
+        assert_eq!(res[1].span, 10..13);
+    }
 }
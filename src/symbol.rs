@@ -0,0 +1,200 @@
+//! Interned symbols.
+//!
+//! Every identifier is interned into a global table when it is lexed,
+//! so that comparing or hashing identifiers only needs to look at
+//! an integer ID rather than the whole string.
+//!
+//! Hygienic macros rename the identifiers they introduce, creating fresh
+//! symbols which print the same as the originals but never compare equal
+//! to them. An identifier which isn't bound by the expansion it came from
+//! refers back to its original symbol instead.
+//!
+//! Gensyms aren't interned at all: their IDs come from a counter of their
+//! own, and their names are only stored if they're needed as a string.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    sync::{Mutex, OnceLock}
+};
+
+/// An interned string, usually the name of an identifier.
+/// Two symbols are equal if and only if they have the same ID.
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Symbol(u32);
+
+/// Symbols with this bit set in their ID were created by `Symbol::gensym`,
+/// and the rest of their ID counts which gensym they were.
+const GENSYM: u32 = 1 << 31;
+
+#[derive(Default)]
+struct Interner {
+    /// The name of every symbol other than gensyms, indexed by ID.
+    /// Names are leaked since symbols live for the rest of the program.
+    names: Vec<&'static str>,
+    /// The IDs of interned symbols
+    ids: HashMap<&'static str, Symbol>,
+    /// The symbol every renamed symbol was originally renamed from
+    originals: HashMap<Symbol, Symbol>,
+    /// How many gensyms have been created
+    gensyms: u32,
+    /// The names of gensyms which have been asked for with `Symbol::as_str`.
+    /// Printing a gensym doesn't need one, so most are never stored.
+    gensym_names: HashMap<Symbol, &'static str>
+}
+
+fn leak(name: &str) -> &'static str {
+    Box::leak(name.to_string().into_boxed_str())
+}
+
+impl Interner {
+    fn push(&mut self, name: &'static str) -> Symbol {
+        // Running out of IDs would need billions of distinct symbols
+        let id = u32::try_from(self.names.len())
+            .ok()
+            .filter(|&id| id < GENSYM)
+            .expect("ran out of symbol IDs");
+        self.names.push(name);
+        Symbol(id)
+    }
+
+    fn name(&mut self, sym: Symbol) -> &'static str {
+        if sym.is_gensym() {
+            self.gensym_names
+                .entry(sym)
+                .or_insert_with(|| leak(&sym.to_string()))
+        } else {
+            self.names[sym.0 as usize]
+        }
+    }
+}
+
+fn with_interner<T>(f: impl FnOnce(&mut Interner) -> T) -> T {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    let mut interner = INTERNER
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    f(&mut interner)
+}
+
+impl Symbol {
+    /// Find the symbol for `name`, creating it if it doesn't exist yet.
+    pub fn intern(name: &str) -> Self {
+        with_interner(|interner| match interner.ids.get(name) {
+            Some(&res) => res,
+            None => {
+                let res = interner.push(leak(name));
+                let name = interner.names[res.0 as usize];
+                interner.ids.insert(name, res);
+                res
+            }
+        })
+    }
+
+    /// Create a symbol which is distinct from every other symbol,
+    /// including ones with the same name. Gensyms are named `g` followed
+    /// by a number, but they don't take up any space in the interner
+    /// unless they're passed to `Symbol::as_str`.
+    pub fn gensym() -> Self {
+        with_interner(|interner| {
+            let res = Symbol(GENSYM | interner.gensyms);
+            interner.gensyms = interner
+                .gensyms
+                .checked_add(1)
+                .filter(|&n| n < GENSYM)
+                .expect("ran out of gensym IDs");
+            res
+        })
+    }
+
+    /// Whether this symbol was created by `Symbol::gensym`.
+    pub fn is_gensym(self) -> bool {
+        self.gensym_number().is_some()
+    }
+
+    /// Which gensym this symbol is, if it was created by `Symbol::gensym`.
+    fn gensym_number(self) -> Option<u32> {
+        if self.0 & GENSYM == 0 {
+            None
+        } else {
+            Some(self.0 & !GENSYM)
+        }
+    }
+
+    /// Create a symbol with the same name as this one which is still
+    /// distinct from every other symbol, remembering where it came from.
+    pub fn rename(self) -> Self {
+        with_interner(|interner| {
+            let name = interner.name(self);
+            let res = interner.push(name);
+            let original = interner.originals.get(&self).copied().unwrap_or(self);
+            interner.originals.insert(res, original);
+            res
+        })
+    }
+
+    /// The symbol this one was created from by `Symbol::rename`, if any.
+    /// Renaming a renamed symbol again still leads back to the first one.
+    pub fn original(self) -> Option<Self> {
+        with_interner(|interner| interner.originals.get(&self).copied())
+    }
+
+    /// The name of this symbol. The name of a gensym is leaked the first
+    /// time this is called on it, so prefer printing symbols when possible.
+    pub fn as_str(self) -> &'static str {
+        with_interner(|interner| interner.name(self))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gensym_number() {
+            Some(n) => write!(f, "g{}", n),
+            None => write!(f, "{}", self.as_str())
+        }
+    }
+}
+
+// The ID alone isn't very helpful when debugging
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Symbol({}: {:?})", self.0, self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning() {
+        let foo = Symbol::intern("foo");
+        assert_eq!(foo, Symbol::intern("foo"));
+        assert_ne!(foo, Symbol::intern("bar"));
+        assert_eq!(foo.as_str(), "foo");
+    }
+
+    #[test]
+    fn gensyms_are_unique() {
+        let a = Symbol::gensym();
+        let b = Symbol::gensym();
+        assert_ne!(a, b);
+        assert_ne!(a, Symbol::intern(&a.to_string()));
+        assert_eq!(a.as_str(), a.to_string());
+        assert_eq!(a.rename().as_str(), a.as_str());
+    }
+
+    #[test]
+    fn renaming() {
+        let foo = Symbol::intern("foo");
+        let renamed = foo.rename();
+        assert_ne!(foo, renamed);
+        assert_ne!(renamed, foo.rename());
+        assert_eq!(renamed.as_str(), "foo");
+        assert_eq!(renamed.original(), Some(foo));
+        assert_eq!(renamed.rename().original(), Some(foo));
+        assert_eq!(foo.original(), None);
+    }
+}